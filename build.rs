@@ -0,0 +1,41 @@
+use std::env;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+
+/// Make sure the binary is rebuilt if anything in the embedded directory changes
+fn rerun_if_changed(path: &Path) {
+    println!("cargo:rerun-if-changed={}", path.display());
+    if path.is_dir() {
+        for entry in fs::read_dir(path).expect("Failed to read embedded directory") {
+            rerun_if_changed(&entry.expect("Failed to read embedded directory").path());
+        }
+    }
+}
+
+/// With the `embed` feature, the directory in `NARNIA_EMBED_DIR` is packed into a tar file that
+/// is included into the binary and served if no web root is configured
+fn embed() {
+    println!("cargo:rerun-if-env-changed=NARNIA_EMBED_DIR");
+    let dir = env::var_os("NARNIA_EMBED_DIR")
+        .map(PathBuf::from)
+        .expect("The embed feature requires NARNIA_EMBED_DIR to point to a directory");
+    if !dir.is_dir() {
+        panic!("NARNIA_EMBED_DIR is not a directory: {:?}", dir);
+    }
+    rerun_if_changed(&dir);
+
+    let out = PathBuf::from(env::var_os("OUT_DIR").unwrap()).join("embedded.tar");
+    let mut builder = tar::Builder::new(File::create(&out).expect("Failed to create tar file"));
+    builder
+        .append_dir_all(".", &dir)
+        .expect("Failed to pack embedded directory");
+    builder.finish().expect("Failed to write tar file");
+}
+
+fn main() {
+    // without any rerun-if-changed, cargo would rerun this on every change in the package
+    println!("cargo:rerun-if-changed=build.rs");
+    if env::var_os("CARGO_FEATURE_EMBED").is_some() {
+        embed();
+    }
+}
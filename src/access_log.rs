@@ -0,0 +1,411 @@
+use crate::args::Args;
+use crate::body::CountingBody;
+use crate::errors::*;
+use actix_web::body::MessageBody;
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::header::{self, HeaderMap, HeaderName};
+use futures_util::future::{ready, LocalBoxFuture, Ready};
+use serde::{Deserialize, Serialize};
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Neither the address nor the user agent of clients is logged by default
+pub const DEFAULT_FORMAT: &str = "{time} {method} {path} {status} {size}";
+
+/// How precise timestamps in the access log are, coarse timestamps make it harder to correlate
+/// requests with other observations of the network
+#[derive(Debug, Clone, Copy, PartialEq, clap::ArgEnum, Serialize, Deserialize)]
+pub enum TimePrecision {
+    Second,
+    Minute,
+    Hour,
+    Day,
+}
+
+impl TimePrecision {
+    fn seconds(self) -> u64 {
+        match self {
+            TimePrecision::Second => 1,
+            TimePrecision::Minute => 60,
+            TimePrecision::Hour => 60 * 60,
+            TimePrecision::Day => 24 * 60 * 60,
+        }
+    }
+
+    /// Format a timestamp, rounded down to this precision
+    fn format(self, time: SystemTime) -> String {
+        let secs = time
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let secs = secs - secs % self.seconds();
+        humantime::format_rfc3339_seconds(UNIX_EPOCH + Duration::from_secs(secs)).to_string()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Field {
+    Time,
+    Method,
+    Path,
+    Status,
+    Size,
+    Duration,
+    Ip,
+    UserAgent,
+    Referer,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Literal(String),
+    Field(Field),
+}
+
+fn parse_format(format: &str) -> Result<Vec<Token>> {
+    let mut tokens = Vec::new();
+    let mut rest = format;
+    while let Some(start) = rest.find('{') {
+        if start > 0 {
+            tokens.push(Token::Literal(rest[..start].to_string()));
+        }
+        let end = rest[start..]
+            .find('}')
+            .with_context(|| anyhow!("Unclosed placeholder in access log format: {:?}", format))?;
+        let field = match &rest[start + 1..start + end] {
+            "time" => Field::Time,
+            "method" => Field::Method,
+            "path" => Field::Path,
+            "status" => Field::Status,
+            "size" => Field::Size,
+            "duration" => Field::Duration,
+            "ip" => Field::Ip,
+            "user_agent" => Field::UserAgent,
+            "referer" => Field::Referer,
+            other => bail!("Unknown placeholder in access log format: {:?}", other),
+        };
+        tokens.push(Token::Field(field));
+        rest = &rest[start + end + 1..];
+    }
+    if !rest.is_empty() {
+        tokens.push(Token::Literal(rest.to_string()));
+    }
+    Ok(tokens)
+}
+
+/// Values that are taken from the client are quoted and escaped, so they can't forge log lines
+fn quote(value: &str) -> String {
+    format!("\"{}\"", value.escape_debug())
+}
+
+fn header_value(headers: &HeaderMap, name: HeaderName) -> String {
+    match headers.get(name) {
+        Some(value) => quote(&String::from_utf8_lossy(value.as_bytes())),
+        None => String::from("-"),
+    }
+}
+
+/// Everything about a request that's known before the response is sent
+#[derive(Debug)]
+struct Request {
+    time: SystemTime,
+    start: Instant,
+    method: String,
+    path: String,
+    ip: String,
+    user_agent: String,
+    referer: String,
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    requests: u64,
+    status: [u64; 4],
+    bytes: u64,
+}
+
+/// Opt-in access log, either with a line per request or only with aggregated counters
+pub struct AccessLog {
+    format: Vec<Token>,
+    precision: TimePrecision,
+    output: Mutex<Box<dyn Write + Send>>,
+    /// Only set if counters are written instead of individual requests
+    counters: Option<Mutex<Counters>>,
+}
+
+impl Default for AccessLog {
+    fn default() -> AccessLog {
+        AccessLog {
+            format: Vec::new(),
+            precision: TimePrecision::Second,
+            output: Mutex::new(Box::new(io::sink())),
+            counters: None,
+        }
+    }
+}
+
+impl AccessLog {
+    pub fn load(args: &Args) -> Result<Option<Arc<AccessLog>>> {
+        let path = if let Some(path) = &args.access_log {
+            path
+        } else {
+            return Ok(None);
+        };
+
+        let format = parse_format(&args.access_log_format)?;
+        let output: Box<dyn Write + Send> = if path.as_os_str() == "-" {
+            Box::new(io::stderr())
+        } else {
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .with_context(|| anyhow!("Failed to open access log: {:?}", path))?;
+            Box::new(file)
+        };
+
+        let log = Arc::new(AccessLog {
+            format,
+            precision: args.access_log_time,
+            output: Mutex::new(output),
+            counters: args
+                .access_log_aggregate
+                .map(|_| Mutex::new(Counters::default())),
+        });
+
+        if let Some(interval) = args.access_log_aggregate {
+            if interval.is_zero() {
+                bail!("Access log interval can't be zero");
+            }
+            info!(
+                "Writing aggregated access log to {:?} every {:?}",
+                path, interval
+            );
+            let log = log.clone();
+            thread::spawn(move || loop {
+                thread::sleep(interval);
+                log.flush_counters();
+            });
+        } else {
+            info!("Writing access log to {:?}", path);
+        }
+        Ok(Some(log))
+    }
+
+    fn write(&self, line: &str) {
+        let mut output = self.output.lock().unwrap();
+        if let Err(err) = output.write_all(line.as_bytes()) {
+            warn!("Failed to write access log: {:#}", err);
+        }
+    }
+
+    fn format_line(&self, req: &Request, status: u16, size: u64, duration: Duration) -> String {
+        let mut line = String::new();
+        for token in &self.format {
+            match token {
+                Token::Literal(text) => line.push_str(text),
+                Token::Field(Field::Time) => line.push_str(&self.precision.format(req.time)),
+                Token::Field(Field::Method) => line.push_str(&req.method),
+                Token::Field(Field::Path) => line.push_str(&req.path),
+                Token::Field(Field::Status) => line.push_str(&status.to_string()),
+                Token::Field(Field::Size) => line.push_str(&size.to_string()),
+                Token::Field(Field::Duration) => line.push_str(&duration.as_millis().to_string()),
+                Token::Field(Field::Ip) => line.push_str(&req.ip),
+                Token::Field(Field::UserAgent) => line.push_str(&req.user_agent),
+                Token::Field(Field::Referer) => line.push_str(&req.referer),
+            }
+        }
+        line.push('\n');
+        line
+    }
+
+    /// Called once the response was sent, or the connection was closed
+    fn record(&self, req: &Request, status: u16, size: u64) {
+        if let Some(counters) = &self.counters {
+            let mut counters = counters.lock().unwrap();
+            counters.requests += 1;
+            if let Some(class) = (status / 100).checked_sub(2) {
+                if let Some(count) = counters.status.get_mut(usize::from(class)) {
+                    *count += 1;
+                }
+            }
+            counters.bytes += size;
+        } else {
+            let line = self.format_line(req, status, size, req.start.elapsed());
+            self.write(&line);
+        }
+    }
+
+    fn flush_counters(&self) {
+        let counters = if let Some(counters) = &self.counters {
+            std::mem::take(&mut *counters.lock().unwrap())
+        } else {
+            return;
+        };
+        let line = format!(
+            "{} requests={} 2xx={} 3xx={} 4xx={} 5xx={} bytes={}\n",
+            self.precision.format(SystemTime::now()),
+            counters.requests,
+            counters.status[0],
+            counters.status[1],
+            counters.status[2],
+            counters.status[3],
+            counters.bytes
+        );
+        self.write(&line);
+    }
+}
+
+/// Middleware that writes requests to the access log
+pub struct LogAccess {
+    log: Arc<AccessLog>,
+}
+
+impl LogAccess {
+    pub fn new(log: Arc<AccessLog>) -> LogAccess {
+        LogAccess { log }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for LogAccess
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<CountingBody>;
+    type Error = actix_web::Error;
+    type Transform = LogAccessMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(LogAccessMiddleware {
+            service: Rc::new(service),
+            log: self.log.clone(),
+        }))
+    }
+}
+
+pub struct LogAccessMiddleware<S> {
+    service: Rc<S>,
+    log: Arc<AccessLog>,
+}
+
+impl<S, B> Service<ServiceRequest> for LogAccessMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<CountingBody>;
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        let log = self.log.clone();
+
+        Box::pin(async move {
+            let request = Request {
+                time: SystemTime::now(),
+                start: Instant::now(),
+                method: req.method().to_string(),
+                path: quote(req.path()),
+                // onion clients all connect from the tor daemon, unix sockets have no address
+                ip: req
+                    .peer_addr()
+                    .map(|addr| addr.ip().to_string())
+                    .unwrap_or_else(|| String::from("-")),
+                user_agent: header_value(req.headers(), header::USER_AGENT),
+                referer: header_value(req.headers(), header::REFERER),
+            };
+
+            let res = service.call(req).await?;
+            let status = res.status().as_u16();
+            // the request is logged once the body was sent
+            Ok(res.map_body(move |_, body| {
+                CountingBody::new(body, move |size| log.record(&request, status, size))
+            }))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_case::test_case;
+
+    fn request() -> Request {
+        Request {
+            time: UNIX_EPOCH + Duration::from_secs(1_700_003_725),
+            start: Instant::now(),
+            method: String::from("GET"),
+            path: quote("/index.html"),
+            ip: String::from("127.0.0.1"),
+            user_agent: quote("curl/\"7\"\n"),
+            referer: String::from("-"),
+        }
+    }
+
+    #[test_case(TimePrecision::Second, "2023-11-14T23:15:25Z")]
+    #[test_case(TimePrecision::Minute, "2023-11-14T23:15:00Z")]
+    #[test_case(TimePrecision::Hour, "2023-11-14T23:00:00Z")]
+    #[test_case(TimePrecision::Day, "2023-11-14T00:00:00Z")]
+    fn test_time_precision(precision: TimePrecision, expected: &str) {
+        assert_eq!(precision.format(request().time), expected);
+    }
+
+    #[test_case("{method} {path}"; "placeholders")]
+    #[test_case("plain text"; "literal")]
+    #[test_case(""; "empty")]
+    fn test_parse_format(format: &str) {
+        assert!(parse_format(format).is_ok());
+    }
+
+    #[test_case("{cookie}"; "unknown placeholder")]
+    #[test_case("{path"; "unclosed placeholder")]
+    fn test_invalid_format(format: &str) {
+        assert!(parse_format(format).is_err());
+    }
+
+    #[test]
+    fn test_default_format() {
+        let log = AccessLog {
+            format: parse_format(DEFAULT_FORMAT).unwrap(),
+            precision: TimePrecision::Hour,
+            ..Default::default()
+        };
+        let line = log.format_line(&request(), 200, 1337, Duration::from_millis(3));
+        assert_eq!(line, "2023-11-14T23:00:00Z GET \"/index.html\" 200 1337\n");
+    }
+
+    #[test]
+    fn test_escape_client_values() {
+        let log = AccessLog {
+            format: parse_format("{ip} {user_agent} {referer} {duration}ms").unwrap(),
+            ..Default::default()
+        };
+        let line = log.format_line(&request(), 200, 0, Duration::from_millis(3));
+        assert_eq!(line, "127.0.0.1 \"curl/\\\"7\\\"\\n\" - 3ms\n");
+    }
+
+    #[test]
+    fn test_aggregate() {
+        let log = AccessLog {
+            counters: Some(Mutex::new(Counters::default())),
+            ..Default::default()
+        };
+        log.record(&request(), 200, 100);
+        log.record(&request(), 404, 20);
+        log.record(&request(), 101, 0);
+        let counters = log.counters.as_ref().unwrap().lock().unwrap();
+        assert_eq!(counters.requests, 3);
+        assert_eq!(counters.status, [1, 0, 1, 0]);
+        assert_eq!(counters.bytes, 120);
+    }
+}
@@ -0,0 +1,616 @@
+use crate::args::Args;
+use crate::errors::*;
+use crate::tls::{self, CertResolver};
+#[cfg(unix)]
+use crate::utils;
+use actix_web::rt::time::sleep;
+use actix_web::{web, HttpResponse};
+use instant_acme::{
+    Account, AccountCredentials, AuthorizationStatus, ChallengeType, Identifier, NewAccount,
+    NewOrder, OrderStatus,
+};
+use rcgen::{CertificateParams, CustomExtension, DistinguishedName};
+use rustls::sign::{self, CertifiedKey};
+use rustls::{Certificate, PrivateKey};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap};
+use std::convert::TryFrom;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use x509_parser::extensions::GeneralName;
+use x509_parser::time::ASN1Time;
+
+pub const LETS_ENCRYPT: &str = "https://acme-v02.api.letsencrypt.org/directory";
+/// The url path http-01 challenges are requested from
+pub const CHALLENGE_PATH: &str = "/.well-known/acme-challenge/";
+/// Offered by the ACME server when it validates a tls-alpn-01 challenge
+pub const ACME_TLS_ALPN: &[u8] = b"acme-tls/1";
+
+/// How often the expiry of the certificate is checked
+const CHECK_INTERVAL: Duration = Duration::from_secs(12 * 60 * 60);
+/// Wait before trying again after a certificate couldn't be issued
+const RETRY_INTERVAL: Duration = Duration::from_secs(60 * 60);
+/// How often the state of an order is polled while the ACME server validates it
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+const POLL_ATTEMPTS: usize = 30;
+
+#[derive(Debug, Clone, Copy, PartialEq, clap::ArgEnum, Serialize, Deserialize)]
+pub enum AcmeChallenge {
+    /// Answered by the http redirect listener, needs to be reachable on port 80
+    #[clap(name = "http-01")]
+    Http01,
+    /// Answered by the https listener, needs to be reachable on port 443
+    #[clap(name = "tls-alpn-01")]
+    TlsAlpn01,
+}
+
+/// The responses to challenges that are currently being validated
+#[derive(Default)]
+pub struct Challenges {
+    http: RwLock<HashMap<String, String>>,
+    tls_alpn: RwLock<HashMap<String, Arc<CertifiedKey>>>,
+}
+
+impl Challenges {
+    fn http(&self, token: &str) -> Option<String> {
+        self.http.read().unwrap().get(token).cloned()
+    }
+
+    pub fn tls_alpn(&self, domain: &str) -> Option<Arc<CertifiedKey>> {
+        self.tls_alpn.read().unwrap().get(domain).cloned()
+    }
+
+    fn clear(&self) {
+        self.http.write().unwrap().clear();
+        self.tls_alpn.write().unwrap().clear();
+    }
+}
+
+/// Paths that are routed to `http_challenge`, a single segment below `CHALLENGE_PATH`
+pub fn is_challenge_path(url_path: &str) -> bool {
+    url_path
+        .strip_prefix(CHALLENGE_PATH)
+        .is_some_and(|token| !token.is_empty() && !token.contains('/'))
+}
+
+pub async fn http_challenge(
+    challenges: web::Data<Challenges>,
+    token: web::Path<String>,
+) -> HttpResponse {
+    if let Some(key_authorization) = challenges.http(&token) {
+        HttpResponse::Ok()
+            .content_type("application/octet-stream")
+            .body(key_authorization)
+    } else {
+        HttpResponse::NotFound().finish()
+    }
+}
+
+/// The storage directory for a specific ACME server, accounts can't be shared between them
+fn storage_dir(base: &Path, directory: &str) -> PathBuf {
+    let host = directory
+        .split("://")
+        .nth(1)
+        .unwrap_or(directory)
+        .split('/')
+        .next()
+        .unwrap_or_default();
+    let mut name = host
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '.' | '-' => c,
+            _ => '_',
+        })
+        .collect::<String>();
+    // never a hidden file or a parent directory
+    if name.is_empty() || name.starts_with('.') {
+        name.insert(0, '_');
+    }
+    base.join(name)
+}
+
+/// The parts of a stored certificate that decide if it needs to be replaced
+#[derive(Debug, PartialEq)]
+struct CertInfo {
+    not_before: SystemTime,
+    not_after: SystemTime,
+    domains: Vec<String>,
+}
+
+impl CertInfo {
+    /// Read the validity and the dns names of a DER encoded certificate
+    fn parse(der: &[u8]) -> Result<CertInfo> {
+        let (_, cert) = x509_parser::parse_x509_certificate(der)
+            .map_err(|err| anyhow!("Failed to parse certificate: {}", err))?;
+        let time = |time: ASN1Time| -> Result<SystemTime> {
+            let secs = u64::try_from(time.timestamp())
+                .with_context(|| anyhow!("Unsupported time in certificate: {}", time))?;
+            Ok(UNIX_EPOCH + Duration::from_secs(secs))
+        };
+        let validity = cert.validity();
+        let mut domains = Vec::new();
+        if let Some(san) = cert
+            .subject_alternative_name()
+            .map_err(|err| anyhow!("Invalid subject alternative name: {}", err))?
+        {
+            for name in &san.value.general_names {
+                if let GeneralName::DNSName(name) = name {
+                    domains.push(name.to_string());
+                }
+            }
+        }
+        Ok(CertInfo {
+            not_before: time(validity.not_before)?,
+            not_after: time(validity.not_after)?,
+            domains,
+        })
+    }
+
+    /// If the certificate was issued for exactly these domains, the order doesn't matter
+    fn matches(&self, domains: &[String]) -> bool {
+        let normalize = |domains: &[String]| {
+            domains
+                .iter()
+                .map(|d| d.to_ascii_lowercase())
+                .collect::<BTreeSet<_>>()
+        };
+        normalize(&self.domains) == normalize(domains)
+    }
+}
+
+/// Renew once two thirds of the lifetime have passed, 30 days before expiry for 90 day certificates
+fn needs_renewal(not_before: SystemTime, not_after: SystemTime, now: SystemTime) -> bool {
+    let lifetime = not_after.duration_since(not_before).unwrap_or_default();
+    now + lifetime / 3 >= not_after
+}
+
+/// A certificate for the tls-alpn-01 challenge, see RFC 8737
+fn tls_alpn_cert(domain: &str, digest: &[u8]) -> Result<CertifiedKey> {
+    let mut params = CertificateParams::new(vec![domain.to_string()]);
+    params.distinguished_name = DistinguishedName::new();
+    params.custom_extensions = vec![CustomExtension::new_acme_identifier(digest)];
+    let cert = rcgen::Certificate::from_params(params)?;
+    certified_key(&cert)
+}
+
+fn certified_key(cert: &rcgen::Certificate) -> Result<CertifiedKey> {
+    let key = sign::any_supported_type(&PrivateKey(cert.serialize_private_key_der()))
+        .map_err(|_| anyhow!("Unsupported private key type"))?;
+    Ok(CertifiedKey::new(
+        vec![Certificate(cert.serialize_der()?)],
+        key,
+    ))
+}
+
+/// Requests and renews a certificate, it's swapped in without a restart
+pub struct Acme {
+    domains: Vec<String>,
+    email: Option<String>,
+    directory: String,
+    challenge: AcmeChallenge,
+    dir: PathBuf,
+    challenges: Arc<Challenges>,
+}
+
+impl Acme {
+    /// Returns `None` if ACME isn't enabled
+    pub fn load(args: &Args) -> Result<Option<Acme>> {
+        if args.acme_domains.is_empty() {
+            return Ok(None);
+        }
+        if args.tls_cert.is_some() || args.tls_key.is_some() {
+            bail!("ACME can't be used together with --tls-cert and --tls-key");
+        }
+        if !args.acme_accept_tos {
+            bail!("The terms of service of the ACME server need to be accepted with --acme-accept-tos");
+        }
+        if args.acme_challenge == AcmeChallenge::Http01 && args.http_redirect_bind.is_none() {
+            bail!("The http-01 challenge is answered on the http listener, it needs --http-redirect-bind");
+        }
+        let base = args
+            .acme_dir()
+            .context("ACME needs --acme-dir or a data directory to store certificates")?;
+
+        #[cfg(unix)]
+        {
+            if let Some(data_dir) = &args.data_dir {
+                utils::mkprivdir(data_dir)
+                    .with_context(|| anyhow!("Failed to create data directory: {:?}", &data_dir))?;
+            }
+            utils::mkprivdir(&base)
+                .with_context(|| anyhow!("Failed to create acme directory: {:?}", &base))?;
+        }
+        let dir = storage_dir(&base, &args.acme_directory);
+        #[cfg(unix)]
+        utils::mkprivdir(&dir)
+            .with_context(|| anyhow!("Failed to create acme directory: {:?}", &dir))?;
+        #[cfg(not(unix))]
+        fs::create_dir_all(&dir)
+            .with_context(|| anyhow!("Failed to create acme directory: {:?}", &dir))?;
+
+        Ok(Some(Acme {
+            domains: args.acme_domains.clone(),
+            email: args.acme_email.clone(),
+            directory: args.acme_directory.clone(),
+            challenge: args.acme_challenge,
+            dir,
+            challenges: Arc::default(),
+        }))
+    }
+
+    pub fn challenges(&self) -> Arc<Challenges> {
+        self.challenges.clone()
+    }
+
+    /// The certificate chain and private key are stored in one file, so they're replaced together
+    fn cert_path(&self) -> PathBuf {
+        self.dir.join("cert.pem")
+    }
+
+    fn account_path(&self) -> PathBuf {
+        self.dir.join("account.json")
+    }
+
+    /// Load the stored certificate, it's rejected if it was issued for other domains
+    fn load_stored(&self) -> Result<(CertifiedKey, CertInfo)> {
+        let path = self.cert_path();
+        let key = tls::load_certified_key(&path, &path)?;
+        let info = CertInfo::parse(&key.end_entity_cert()?.0)?;
+        if !info.matches(&self.domains) {
+            bail!(
+                "Stored certificate was issued for {:?} instead of {:?}",
+                info.domains,
+                self.domains
+            );
+        }
+        Ok((key, info))
+    }
+
+    /// Load the stored certificate, a self-signed one is used until the first one was issued
+    pub fn initial_cert(&self) -> Result<CertifiedKey> {
+        if self.cert_path().exists() {
+            match self.load_stored() {
+                Ok((key, _)) => return Ok(key),
+                Err(err) => warn!("Failed to load stored certificate: {:#}", err),
+            }
+        }
+        info!("Using a self-signed certificate until one was issued");
+        let cert = rcgen::generate_simple_self_signed(self.domains.clone())?;
+        certified_key(&cert)
+    }
+
+    /// The validity of the stored certificate, `None` if there's no usable certificate for the
+    /// configured domains
+    fn stored_validity(&self) -> Option<(SystemTime, SystemTime)> {
+        let (_, info) = self.load_stored().ok()?;
+        Some((info.not_before, info.not_after))
+    }
+
+    /// Renew the certificate whenever needed, runs forever
+    pub async fn run(self, resolver: Arc<CertResolver>) {
+        loop {
+            let renew = match self.stored_validity() {
+                Some((not_before, not_after)) => {
+                    needs_renewal(not_before, not_after, SystemTime::now())
+                }
+                None => true,
+            };
+            if !renew {
+                sleep(CHECK_INTERVAL).await;
+                continue;
+            }
+
+            info!("Requesting certificate for {:?}", self.domains);
+            match self.issue().await {
+                Ok(key) => {
+                    info!("Successfully issued certificate for {:?}", self.domains);
+                    resolver.replace(key);
+                    sleep(CHECK_INTERVAL).await;
+                }
+                Err(err) => {
+                    error!("Failed to issue certificate: {:#}", err);
+                    sleep(RETRY_INTERVAL).await;
+                }
+            }
+        }
+    }
+
+    async fn account(&self) -> Result<Account> {
+        let path = self.account_path();
+        if let Ok(json) = fs::read_to_string(&path) {
+            let credentials = serde_json::from_str::<AccountCredentials>(&json)
+                .with_context(|| anyhow!("Invalid ACME account: {:?}", path))?;
+            return Ok(Account::from_credentials(credentials)?);
+        }
+
+        info!("Creating ACME account at {:?}", self.directory);
+        let contact = self
+            .email
+            .iter()
+            .map(|email| format!("mailto:{}", email))
+            .collect::<Vec<_>>();
+        let contact = contact.iter().map(String::as_str).collect::<Vec<_>>();
+        let account = Account::create(
+            &NewAccount {
+                contact: &contact,
+                terms_of_service_agreed: true,
+                only_return_existing: false,
+            },
+            &self.directory,
+            None,
+        )
+        .await
+        .context("Failed to create ACME account")?;
+
+        let json = serde_json::to_string(&account.credentials())?;
+        write_atomic(&path, &json)?;
+        Ok(account)
+    }
+
+    async fn issue(&self) -> Result<CertifiedKey> {
+        let result = self.order().await;
+        self.challenges.clear();
+        let pem = result?;
+
+        let path = self.cert_path();
+        write_atomic(&path, &pem)?;
+        tls::load_certified_key(&path, &path)
+    }
+
+    /// Go through the ACME flow, returns the certificate chain and private key as PEM
+    async fn order(&self) -> Result<String> {
+        let account = self.account().await?;
+        let identifiers = self
+            .domains
+            .iter()
+            .cloned()
+            .map(Identifier::Dns)
+            .collect::<Vec<_>>();
+        let mut order = account
+            .new_order(&NewOrder {
+                identifiers: &identifiers,
+            })
+            .await?;
+
+        let challenge_type = match self.challenge {
+            AcmeChallenge::Http01 => ChallengeType::Http01,
+            AcmeChallenge::TlsAlpn01 => ChallengeType::TlsAlpn01,
+        };
+        let mut pending = Vec::new();
+        for authorization in order.authorizations().await? {
+            let Identifier::Dns(domain) = &authorization.identifier;
+            match authorization.status {
+                AuthorizationStatus::Pending => (),
+                AuthorizationStatus::Valid => continue,
+                status => bail!("Authorization for {:?} is {:?}", domain, status),
+            }
+            let challenge = authorization
+                .challenges
+                .iter()
+                .find(|challenge| challenge.r#type == challenge_type)
+                .with_context(|| {
+                    anyhow!("No {:?} challenge offered for {:?}", self.challenge, domain)
+                })?;
+
+            let key_authorization = order.key_authorization(challenge);
+            match self.challenge {
+                AcmeChallenge::Http01 => {
+                    self.challenges.http.write().unwrap().insert(
+                        challenge.token.clone(),
+                        key_authorization.as_str().to_string(),
+                    );
+                }
+                AcmeChallenge::TlsAlpn01 => {
+                    let cert = tls_alpn_cert(domain, key_authorization.digest().as_ref())?;
+                    self.challenges
+                        .tls_alpn
+                        .write()
+                        .unwrap()
+                        .insert(domain.clone(), Arc::new(cert));
+                }
+            }
+            pending.push(challenge.url.clone());
+        }
+
+        for url in &pending {
+            order.set_challenge_ready(url).await?;
+        }
+
+        let mut attempts = 0;
+        let state = loop {
+            let state = order.refresh().await?;
+            if !matches!(state.status, OrderStatus::Pending) {
+                break state;
+            }
+            attempts += 1;
+            if attempts >= POLL_ATTEMPTS {
+                bail!("Timed out waiting for the challenges to be validated");
+            }
+            sleep(POLL_INTERVAL).await;
+        };
+        if state.status == OrderStatus::Invalid {
+            bail!("Order is invalid: {:?}", state.error);
+        }
+
+        let mut params = CertificateParams::new(self.domains.clone());
+        params.distinguished_name = DistinguishedName::new();
+        let cert = rcgen::Certificate::from_params(params)?;
+        if order.state().status == OrderStatus::Ready {
+            order.finalize(&cert.serialize_request_der()?).await?;
+        }
+
+        let mut attempts = 0;
+        let chain = loop {
+            if let Some(chain) = order.certificate().await? {
+                break chain;
+            }
+            attempts += 1;
+            if attempts >= POLL_ATTEMPTS {
+                bail!("Timed out waiting for the certificate");
+            }
+            sleep(POLL_INTERVAL).await;
+        };
+
+        Ok(format!("{}{}", chain, cert.serialize_private_key_pem()))
+    }
+}
+
+/// Write to a temporary file first, so there's never a partially written file. The files contain
+/// private keys, so they're only readable by us
+fn write_atomic(path: &Path, content: &str) -> Result<()> {
+    let tmp = path.with_extension("tmp");
+    // a leftover file could have different permissions
+    match fs::remove_file(&tmp) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => {
+            return Err(err).with_context(|| anyhow!("Failed to remove {:?}", tmp));
+        }
+        _ => (),
+    }
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options
+        .open(&tmp)
+        .and_then(|mut file| file.write_all(content.as_bytes()))
+        .with_context(|| anyhow!("Failed to write {:?}", tmp))?;
+    fs::rename(&tmp, path).with_context(|| anyhow!("Failed to write {:?}", path))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_case::test_case;
+
+    #[test_case(
+        "https://acme-v02.api.letsencrypt.org/directory",
+        "/data/acme/acme-v02.api.letsencrypt.org"
+    )]
+    #[test_case("https://localhost:14000/dir", "/data/acme/localhost_14000")]
+    #[test_case("https://../directory", "/data/acme/_..")]
+    #[test_case("directory", "/data/acme/directory")]
+    fn test_storage_dir(directory: &str, expected: &str) {
+        assert_eq!(
+            storage_dir(Path::new("/data/acme"), directory),
+            Path::new(expected)
+        );
+    }
+
+    #[test]
+    fn test_cert_info() {
+        let domains = vec!["example.com".to_string(), "www.example.com".to_string()];
+        let cert = rcgen::generate_simple_self_signed(domains).unwrap();
+        let info = CertInfo::parse(&cert.serialize_der().unwrap()).unwrap();
+        // the defaults of rcgen
+        assert_eq!(
+            info,
+            CertInfo {
+                not_before: humantime::parse_rfc3339("1975-01-01T00:00:00Z").unwrap(),
+                not_after: humantime::parse_rfc3339("4096-01-01T00:00:00Z").unwrap(),
+                domains: vec!["example.com".to_string(), "www.example.com".to_string()],
+            }
+        );
+        assert!(CertInfo::parse(b"not a certificate").is_err());
+    }
+
+    #[test_case(&["www.example.com", "Example.com"], true)]
+    #[test_case(&["example.com"], false)]
+    #[test_case(&["example.com", "www.example.com", "example.org"], false)]
+    fn test_cert_info_matches(domains: &[&str], expected: bool) {
+        let info = CertInfo {
+            not_before: UNIX_EPOCH,
+            not_after: UNIX_EPOCH,
+            domains: vec!["example.com".to_string(), "www.example.com".to_string()],
+        };
+        let domains = domains.iter().map(|d| d.to_string()).collect::<Vec<_>>();
+        assert_eq!(info.matches(&domains), expected);
+    }
+
+    #[test]
+    fn test_stored_cert_for_other_domains() {
+        let dir = tempfile::tempdir().unwrap();
+        let acme = Acme {
+            domains: vec!["example.com".to_string()],
+            email: None,
+            directory: LETS_ENCRYPT.to_string(),
+            challenge: AcmeChallenge::TlsAlpn01,
+            dir: dir.path().to_path_buf(),
+            challenges: Arc::default(),
+        };
+        let cert = rcgen::generate_simple_self_signed(vec!["example.org".to_string()]).unwrap();
+        let pem = format!(
+            "{}{}",
+            cert.serialize_pem().unwrap(),
+            cert.serialize_private_key_pem()
+        );
+        write_atomic(&acme.cert_path(), &pem).unwrap();
+        assert!(acme.load_stored().is_err());
+        assert_eq!(acme.stored_validity(), None);
+        let initial = acme.initial_cert().unwrap();
+        assert_ne!(initial.cert[0].0, cert.serialize_der().unwrap());
+
+        let cert = rcgen::generate_simple_self_signed(vec!["example.com".to_string()]).unwrap();
+        let pem = format!(
+            "{}{}",
+            cert.serialize_pem().unwrap(),
+            cert.serialize_private_key_pem()
+        );
+        write_atomic(&acme.cert_path(), &pem).unwrap();
+        assert!(acme.stored_validity().is_some());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_write_atomic_is_private() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("cert.pem");
+        // a leftover temporary file doesn't keep its permissions
+        fs::write(path.with_extension("tmp"), "leftover").unwrap();
+        fs::set_permissions(
+            path.with_extension("tmp"),
+            fs::Permissions::from_mode(0o644),
+        )
+        .unwrap();
+        write_atomic(&path, "secret").unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "secret");
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        assert!(!path.with_extension("tmp").exists());
+    }
+
+    #[test_case("/.well-known/acme-challenge/abc", true)]
+    #[test_case("/.well-known/acme-challenge/", false)]
+    #[test_case("/.well-known/acme-challenge/a/b", false)]
+    #[test_case("//.well-known/acme-challenge/abc", false)]
+    #[test_case("/.well-known/%61cme-challenge/abc", false)]
+    #[test_case("/private/abc", false)]
+    fn test_is_challenge_path(path: &str, expected: bool) {
+        assert_eq!(is_challenge_path(path), expected);
+    }
+
+    #[test]
+    fn test_needs_renewal() {
+        let day = Duration::from_secs(24 * 60 * 60);
+        let not_before = UNIX_EPOCH;
+        let not_after = UNIX_EPOCH + 90 * day;
+        assert!(!needs_renewal(not_before, not_after, UNIX_EPOCH + day));
+        assert!(!needs_renewal(not_before, not_after, UNIX_EPOCH + 59 * day));
+        assert!(needs_renewal(not_before, not_after, UNIX_EPOCH + 60 * day));
+        assert!(needs_renewal(not_before, not_after, UNIX_EPOCH + 100 * day));
+    }
+
+    #[test]
+    fn test_tls_alpn_cert() {
+        let cert = tls_alpn_cert("example.com", &[0; 32]).unwrap();
+        assert_eq!(cert.cert.len(), 1);
+    }
+}
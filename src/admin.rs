@@ -0,0 +1,201 @@
+use crate::args::Args;
+use crate::errors::*;
+use crate::security::Sandbox;
+use crate::server::Bind;
+use crate::tor;
+#[cfg(unix)]
+use crate::utils;
+use actix_web::{web, App, HttpResponse, HttpServer};
+use serde::Serialize;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::thread;
+use std::time::Instant;
+
+/// The state of the httpd, in multi-process mode it's reported by the child
+pub struct Status {
+    httpd: Mutex<Option<Sandbox>>,
+}
+
+pub static STATUS: Status = Status {
+    httpd: Mutex::new(None),
+};
+
+impl Status {
+    /// Called once the http socket is bound and the httpd has been locked down
+    pub fn httpd_ready(&self, sandbox: Sandbox) {
+        *self.httpd.lock().unwrap() = Some(sandbox);
+    }
+
+    fn httpd(&self) -> Option<Sandbox> {
+        self.httpd.lock().unwrap().clone()
+    }
+}
+
+/// Serves `/health`, `/ready` and `/status` for process supervisors, on its own address so it's
+/// never reachable through the hidden service
+pub struct Admin {
+    bind: Bind,
+    data_dir: Option<PathBuf>,
+    tor_control: Option<PathBuf>,
+    multi_process: bool,
+    started: Instant,
+}
+
+impl Admin {
+    /// Needs to be called before privileges are dropped, so the address can be bound
+    pub fn setup(args: &Args) -> Result<Option<Admin>> {
+        let addr = if let Some(addr) = args.admin_addr()? {
+            addr
+        } else {
+            return Ok(None);
+        };
+        if let Some(admin_bind) = &args.admin_bind {
+            if args.bind.as_ref() == Some(admin_bind)
+                || args.metrics_bind.as_ref() == Some(admin_bind)
+            {
+                bail!("The admin endpoints need to be served on their own address");
+            }
+        }
+
+        #[cfg(unix)]
+        if let Some(data_dir) = &args.data_dir {
+            utils::mkprivdir(data_dir)
+                .with_context(|| anyhow!("Failed to create data directory: {:?}", &data_dir))?;
+        }
+        let bind = Bind::listen(addr).context("Failed to bind admin socket")?;
+        let tor_control = if cfg!(unix) {
+            args.data_dir.as_deref().map(tor::control_socket)
+        } else {
+            None
+        };
+        Ok(Some(Admin {
+            bind,
+            data_dir: args.data_dir.clone(),
+            tor_control,
+            multi_process: args.needs_child(),
+            started: Instant::now(),
+        }))
+    }
+
+    pub fn background(self) {
+        thread::spawn(move || {
+            if let Err(err) = serve(self) {
+                error!("Admin thread has terminated: {:#}", err);
+            }
+        });
+    }
+}
+
+struct State {
+    data_dir: Option<PathBuf>,
+    tor_control: Option<PathBuf>,
+    multi_process: bool,
+    started: Instant,
+}
+
+impl State {
+    async fn tor_bootstrap(&self) -> Option<u8> {
+        let path = self.tor_control.clone()?;
+        Some(tor::query_bootstrap(path).await)
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct StatusResponse {
+    version: &'static str,
+    uptime: u64,
+    ready: bool,
+    onion: Option<String>,
+    tor_bootstrap: Option<u8>,
+    multi_process: bool,
+    /// The restrictions of the process that serves http, `null` until it's ready
+    sandbox: Option<Sandbox>,
+}
+
+/// The hidden service is only reachable once Tor has bootstrapped completely
+fn is_ready(httpd: Option<&Sandbox>, tor_bootstrap: Option<u8>) -> bool {
+    httpd.is_some() && !matches!(tor_bootstrap, Some(percent) if percent < 100)
+}
+
+async fn health() -> HttpResponse {
+    HttpResponse::Ok().body("ok\n")
+}
+
+async fn ready(state: web::Data<State>) -> HttpResponse {
+    if STATUS.httpd().is_none() {
+        return HttpResponse::ServiceUnavailable().body("httpd isn't ready yet\n");
+    }
+    match state.tor_bootstrap().await {
+        Some(percent) if percent < 100 => HttpResponse::ServiceUnavailable()
+            .body(format!("Tor is bootstrapping ({}%)\n", percent)),
+        _ => HttpResponse::Ok().body("ready\n"),
+    }
+}
+
+async fn status(state: web::Data<State>) -> HttpResponse {
+    let sandbox = STATUS.httpd();
+    let tor_bootstrap = state.tor_bootstrap().await;
+    let onion = state.data_dir.as_deref().and_then(|data_dir| {
+        tor::hostname(data_dir)
+            .map_err(|err| debug!("Hidden service isn't set up yet: {:#}", err))
+            .ok()
+    });
+
+    HttpResponse::Ok().json(StatusResponse {
+        version: env!("CARGO_PKG_VERSION"),
+        uptime: state.started.elapsed().as_secs(),
+        ready: is_ready(sandbox.as_ref(), tor_bootstrap),
+        onion,
+        tor_bootstrap,
+        multi_process: state.multi_process,
+        sandbox,
+    })
+}
+
+#[actix_web::main]
+async fn serve(admin: Admin) -> Result<()> {
+    let state = web::Data::new(State {
+        data_dir: admin.data_dir,
+        tor_control: admin.tor_control,
+        multi_process: admin.multi_process,
+        started: admin.started,
+    });
+    let server = HttpServer::new(move || {
+        App::new()
+            .app_data(state.clone())
+            .route("/health", web::get().to(health))
+            .route("/ready", web::get().to(ready))
+            .route("/status", web::get().to(status))
+    })
+    .workers(1)
+    // shutting down is handled by the httpd server, or by the default signal handlers
+    .disable_signals();
+
+    let server = match admin.bind {
+        Bind::Tcp(tcp) => server.listen(tcp),
+        #[cfg(unix)]
+        Bind::Unix(uds) => server.listen_uds(uds),
+    };
+    server
+        .context("Failed to setup admin server")?
+        .run()
+        .await
+        .context("Failed to run admin server")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ready() {
+        let sandbox = Sandbox::default();
+        assert!(is_ready(Some(&sandbox), None));
+        assert!(is_ready(Some(&sandbox), Some(100)));
+        assert!(!is_ready(Some(&sandbox), Some(85)));
+        assert!(!is_ready(None, None));
+        assert!(!is_ready(None, Some(100)));
+    }
+}
@@ -0,0 +1,619 @@
+use crate::errors::*;
+use crate::resolve::{Node, Resolver};
+use crate::rules;
+use crate::utils;
+use actix_web::web::Bytes;
+use futures_util::stream::{self, Stream};
+use serde::Deserialize;
+use std::convert::TryFrom;
+use std::io::{self, Read, Write};
+use std::mem;
+use std::path::{Path, PathBuf};
+use tokio::sync::mpsc;
+
+/// Size of the chunks that are sent to the client
+const CHUNK_SIZE: usize = 64 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    Tar,
+    Zip,
+}
+
+impl Format {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Format::Tar => "tar",
+            Format::Zip => "zip",
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Format::Tar => "application/x-tar",
+            Format::Zip => "application/zip",
+        }
+    }
+}
+
+/// Query parameters to request an archive of a directory, like `?download=tar`
+#[derive(Debug, Default, Deserialize)]
+pub struct ArchiveQuery {
+    pub download: Option<Format>,
+}
+
+impl ArchiveQuery {
+    pub fn parse(query: &str) -> ArchiveQuery {
+        actix_web::web::Query::<ArchiveQuery>::from_query(query)
+            .map(|query| query.into_inner())
+            .unwrap_or_default()
+    }
+}
+
+#[derive(Debug)]
+struct Item {
+    /// The path on disk
+    path: PathBuf,
+    /// The path inside of the archive
+    name: PathBuf,
+    /// The size of the file, not set for directories
+    size: Option<u64>,
+}
+
+/// The files that are going to be added to an archive, collected before the download starts so
+/// the size limit can be enforced up front
+#[derive(Debug)]
+pub struct Plan {
+    items: Vec<Item>,
+    total: u64,
+    max_size: u64,
+}
+
+impl Plan {
+    pub fn collect(
+        resolver: &Resolver,
+        dir: Node,
+        full_path: &Path,
+        name: &Path,
+        is_web_root: bool,
+        max_size: u64,
+    ) -> Result<Plan> {
+        let mut plan = Plan {
+            items: vec![Item {
+                path: full_path.to_path_buf(),
+                name: name.to_path_buf(),
+                size: None,
+            }],
+            total: 0,
+            max_size,
+        };
+        plan.walk(resolver, dir, full_path, name, is_web_root)?;
+        Ok(plan)
+    }
+
+    /// Zip64 isn't supported, archives that would need it are rejected before the download starts
+    /// instead of failing halfway through
+    pub fn check(&self, format: Format) -> Result<()> {
+        if format == Format::Zip && self.zip_size()? > u64::from(u32::MAX) {
+            bail!("Archive is too large for zip, use tar instead");
+        }
+        Ok(())
+    }
+
+    /// The exact size of the zip file `write_zip` is going to produce
+    fn zip_size(&self) -> Result<u64> {
+        if self.items.len() > usize::from(u16::MAX) {
+            bail!("Too many files for zip, use tar instead");
+        }
+        let mut size = ZIP_END_LEN;
+        for item in &self.items {
+            let name = zip_name(&item.name, item.size.is_none());
+            if name.len() > usize::from(u16::MAX) {
+                bail!("File name is too long for zip: {:?}", item.name);
+            }
+            let name = name.len() as u64;
+            size += ZIP_LOCAL_LEN + name + item.size.unwrap_or(0) + ZIP_DESCRIPTOR_LEN;
+            size += ZIP_CENTRAL_LEN + name;
+        }
+        Ok(size)
+    }
+
+    fn walk(
+        &mut self,
+        resolver: &Resolver,
+        dir: Node,
+        full_path: &Path,
+        name: &Path,
+        is_web_root: bool,
+    ) -> Result<()> {
+        let mut entries = resolver
+            .read_dir(dir, full_path)
+            .with_context(|| anyhow!("Failed to list directory: {:?}", full_path))?;
+        entries.sort_by(|a, b| a.name.cmp(&b.name));
+
+        for entry in entries {
+            if is_web_root && rules::is_rules_file(Path::new(&entry.name)) {
+                continue;
+            }
+
+            let path = full_path.join(&entry.name);
+            let name = name.join(&entry.name);
+
+            // this enforces the symlink policy, files we may not open are skipped
+            let file = match resolver.open(&path) {
+                Ok(file) => file,
+                Err(err) => {
+                    debug!("Skipping file in archive({:?}): {:#}", path, err);
+                    continue;
+                }
+            };
+            let md = file.metadata()?;
+
+            if md.is_dir() {
+                // don't descend into symlinked directories, they could form a loop
+                if entry.is_symlink {
+                    continue;
+                }
+                self.items.push(Item {
+                    path: path.clone(),
+                    name: name.clone(),
+                    size: None,
+                });
+                self.walk(resolver, file, &path, &name, false)?;
+            } else {
+                self.total += md.len();
+                if self.total > self.max_size {
+                    bail!(
+                        "Directory exceeds archive size limit of {} bytes",
+                        self.max_size
+                    );
+                }
+                self.items.push(Item {
+                    path,
+                    name,
+                    size: Some(md.len()),
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Build the archive in a background thread and stream it to the client
+    pub fn stream(
+        self,
+        resolver: Resolver,
+        format: Format,
+    ) -> impl Stream<Item = io::Result<Bytes>> {
+        let (tx, rx) = mpsc::channel(16);
+
+        actix_web::rt::task::spawn_blocking(move || {
+            let mut writer = ChannelWriter {
+                tx: tx.clone(),
+                buf: Vec::with_capacity(CHUNK_SIZE),
+            };
+            let result = match format {
+                Format::Tar => self.write_tar(&resolver, &mut writer),
+                Format::Zip => self.write_zip(&resolver, &mut writer),
+            };
+            if let Err(err) = result.and_then(|_| writer.flush()) {
+                warn!("Failed to build archive: {:#}", err);
+                tx.blocking_send(Err(err)).ok();
+            }
+        });
+
+        stream::unfold(rx, |mut rx| async move {
+            rx.recv().await.map(|item| (item, rx))
+        })
+    }
+
+    fn write_tar<W: Write>(&self, resolver: &Resolver, w: W) -> io::Result<()> {
+        let mut builder = tar::Builder::new(w);
+        for item in &self.items {
+            // don't leak timestamps or owners
+            let mut header = tar::Header::new_gnu();
+            header.set_mtime(0);
+            header.set_uid(0);
+            header.set_gid(0);
+
+            if let Some(size) = item.size {
+                let file = resolver.open(&item.path)?;
+                header.set_entry_type(tar::EntryType::Regular);
+                header.set_mode(0o644);
+                header.set_size(size);
+                builder.append_data(
+                    &mut header,
+                    &item.name,
+                    ExactReader::new(file.into_reader()?, size),
+                )?;
+            } else {
+                header.set_entry_type(tar::EntryType::Directory);
+                header.set_mode(0o755);
+                header.set_size(0);
+                builder.append_data(&mut header, &item.name, io::empty())?;
+            }
+        }
+        builder.into_inner()?;
+        Ok(())
+    }
+
+    fn write_zip<W: Write>(&self, resolver: &Resolver, w: W) -> io::Result<()> {
+        let mut zip = ZipWriter::new(w);
+        for item in &self.items {
+            let name = zip_name(&item.name, item.size.is_none());
+            if let Some(size) = item.size {
+                let file = resolver.open(&item.path)?;
+                zip.add_file(name, ExactReader::new(file.into_reader()?, size))?;
+            } else {
+                zip.add_dir(name)?;
+            }
+        }
+        zip.finish()
+    }
+}
+
+/// Read exactly the number of bytes that were planned, even if the file changed since
+struct ExactReader<R> {
+    inner: io::Take<R>,
+}
+
+impl<R: Read> ExactReader<R> {
+    fn new(inner: R, size: u64) -> ExactReader<R> {
+        ExactReader {
+            inner: inner.take(size),
+        }
+    }
+}
+
+impl<R: Read> Read for ExactReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        if n == 0 && !buf.is_empty() && self.inner.limit() > 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "File was truncated while building archive",
+            ));
+        }
+        Ok(n)
+    }
+}
+
+/// Forward everything written to the http response, in chunks
+struct ChannelWriter {
+    tx: mpsc::Sender<io::Result<Bytes>>,
+    buf: Vec<u8>,
+}
+
+impl Write for ChannelWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(buf);
+        if self.buf.len() >= CHUNK_SIZE {
+            self.flush()?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if !self.buf.is_empty() {
+            let chunk = mem::replace(&mut self.buf, Vec::with_capacity(CHUNK_SIZE));
+            self.tx
+                .blocking_send(Ok(Bytes::from(chunk)))
+                .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "Client disconnected"))?;
+        }
+        Ok(())
+    }
+}
+
+/// Paths in zip files are separated with `/`, directories end with one
+fn zip_name(path: &Path, is_dir: bool) -> Vec<u8> {
+    let mut name = Vec::new();
+    for comp in path.iter() {
+        name.extend_from_slice(&utils::os_str_bytes(comp));
+        name.push(b'/');
+    }
+    if !is_dir {
+        name.pop();
+    }
+    name
+}
+
+/// The dos date of 1980-01-01, the earliest date that can be represented
+const ZIP_DATE: u16 = (1 << 5) | 1;
+/// Bit 3: sizes and crc follow the file data, bit 11: the name is utf8
+const ZIP_FLAGS: u16 = 1 << 3;
+const ZIP_FLAG_UTF8: u16 = 1 << 11;
+const ZIP_VERSION: u16 = 20;
+/// Made by unix, so the external attributes are interpreted as file mode
+const ZIP_VERSION_MADE_BY: u16 = (3 << 8) | ZIP_VERSION;
+/// The sizes of the fixed parts of the zip records, without names
+const ZIP_LOCAL_LEN: u64 = 30;
+const ZIP_DESCRIPTOR_LEN: u64 = 16;
+const ZIP_CENTRAL_LEN: u64 = 46;
+const ZIP_END_LEN: u64 = 22;
+
+struct ZipEntry {
+    name: Vec<u8>,
+    crc: u32,
+    size: u32,
+    offset: u32,
+    mode: u32,
+}
+
+/// A minimal zip writer for non-seekable streams, files are stored without compression
+struct ZipWriter<W> {
+    inner: W,
+    offset: u64,
+    entries: Vec<ZipEntry>,
+}
+
+fn zip_u32(n: u64) -> io::Result<u32> {
+    u32::try_from(n).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Archive is too large for zip, use tar instead",
+        )
+    })
+}
+
+impl<W: Write> ZipWriter<W> {
+    fn new(inner: W) -> ZipWriter<W> {
+        ZipWriter {
+            inner,
+            offset: 0,
+            entries: Vec::new(),
+        }
+    }
+
+    fn write(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.inner.write_all(buf)?;
+        self.offset += buf.len() as u64;
+        Ok(())
+    }
+
+    fn flags(name: &[u8]) -> u16 {
+        if std::str::from_utf8(name).is_ok() {
+            ZIP_FLAGS | ZIP_FLAG_UTF8
+        } else {
+            ZIP_FLAGS
+        }
+    }
+
+    fn local_header(&mut self, name: &[u8]) -> io::Result<u32> {
+        let offset = zip_u32(self.offset)?;
+        let mut buf = Vec::with_capacity(30 + name.len());
+        buf.extend_from_slice(&0x0403_4b50u32.to_le_bytes());
+        buf.extend_from_slice(&ZIP_VERSION.to_le_bytes());
+        buf.extend_from_slice(&Self::flags(name).to_le_bytes());
+        buf.extend_from_slice(&0u16.to_le_bytes()); // stored
+        buf.extend_from_slice(&0u16.to_le_bytes()); // time
+        buf.extend_from_slice(&ZIP_DATE.to_le_bytes());
+        buf.extend_from_slice(&[0; 12]); // crc and sizes, see data descriptor
+        buf.extend_from_slice(&(name.len() as u16).to_le_bytes());
+        buf.extend_from_slice(&0u16.to_le_bytes()); // extra field length
+        buf.extend_from_slice(name);
+        self.write(&buf)?;
+        Ok(offset)
+    }
+
+    fn add_dir(&mut self, name: Vec<u8>) -> io::Result<()> {
+        self.add_file(name, io::empty())?;
+        if let Some(entry) = self.entries.last_mut() {
+            entry.mode = 0o040755;
+        }
+        Ok(())
+    }
+
+    fn add_file<R: Read>(&mut self, name: Vec<u8>, mut reader: R) -> io::Result<()> {
+        let offset = self.local_header(&name)?;
+
+        let mut hasher = crc32fast::Hasher::new();
+        let mut size = 0u64;
+        let mut buf = vec![0; CHUNK_SIZE];
+        loop {
+            let n = reader.read(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+            hasher.update(&buf[..n]);
+            self.write(&buf[..n])?;
+            size += n as u64;
+        }
+        let crc = hasher.finalize();
+        let size = zip_u32(size)?;
+
+        let mut buf = Vec::with_capacity(16);
+        buf.extend_from_slice(&0x0807_4b50u32.to_le_bytes());
+        buf.extend_from_slice(&crc.to_le_bytes());
+        buf.extend_from_slice(&size.to_le_bytes()); // compressed
+        buf.extend_from_slice(&size.to_le_bytes()); // uncompressed
+        self.write(&buf)?;
+
+        self.entries.push(ZipEntry {
+            name,
+            crc,
+            size,
+            offset,
+            mode: 0o100644,
+        });
+        Ok(())
+    }
+
+    fn finish(mut self) -> io::Result<()> {
+        let start = zip_u32(self.offset)?;
+        let entries = mem::take(&mut self.entries);
+        for entry in &entries {
+            let mut buf = Vec::with_capacity(46 + entry.name.len());
+            buf.extend_from_slice(&0x0201_4b50u32.to_le_bytes());
+            buf.extend_from_slice(&ZIP_VERSION_MADE_BY.to_le_bytes());
+            buf.extend_from_slice(&ZIP_VERSION.to_le_bytes());
+            buf.extend_from_slice(&Self::flags(&entry.name).to_le_bytes());
+            buf.extend_from_slice(&0u16.to_le_bytes()); // stored
+            buf.extend_from_slice(&0u16.to_le_bytes()); // time
+            buf.extend_from_slice(&ZIP_DATE.to_le_bytes());
+            buf.extend_from_slice(&entry.crc.to_le_bytes());
+            buf.extend_from_slice(&entry.size.to_le_bytes());
+            buf.extend_from_slice(&entry.size.to_le_bytes());
+            buf.extend_from_slice(&(entry.name.len() as u16).to_le_bytes());
+            buf.extend_from_slice(&[0; 8]); // extra, comment, disk, internal attributes
+            buf.extend_from_slice(&(entry.mode << 16).to_le_bytes());
+            buf.extend_from_slice(&entry.offset.to_le_bytes());
+            buf.extend_from_slice(&entry.name);
+            self.write(&buf)?;
+        }
+        let size = zip_u32(self.offset)? - start;
+
+        let count = u16::try_from(entries.len())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Too many files for zip"))?;
+        let mut buf = Vec::with_capacity(22);
+        buf.extend_from_slice(&0x0605_4b50u32.to_le_bytes());
+        buf.extend_from_slice(&[0; 4]); // disk numbers
+        buf.extend_from_slice(&count.to_le_bytes());
+        buf.extend_from_slice(&count.to_le_bytes());
+        buf.extend_from_slice(&size.to_le_bytes());
+        buf.extend_from_slice(&start.to_le_bytes());
+        buf.extend_from_slice(&0u16.to_le_bytes()); // comment length
+        self.write(&buf)?;
+
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::args::Args;
+    use clap::Parser;
+    use std::fs;
+    use std::io::Cursor;
+
+    fn setup() -> (tempfile::TempDir, Resolver) {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().to_path_buf();
+        fs::create_dir(root.join("sub")).unwrap();
+        fs::write(root.join("a.txt"), "hello").unwrap();
+        fs::write(root.join("sub/b.txt"), "world").unwrap();
+        fs::write(root.join("_headers"), "/*\n  X-Test: 1\n").unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::ffi::OsStrExt;
+            let name = std::ffi::OsStr::from_bytes(b"caf\xe9.txt");
+            fs::write(root.join(name), "latin1").unwrap();
+        }
+        let args = Args::parse_from(["narnia"]);
+        (dir, Resolver::new(&args, root))
+    }
+
+    fn collect(resolver: &Resolver, max_size: u64) -> Result<Plan> {
+        let root = resolver.root().to_path_buf();
+        let dir = resolver.open(&root).unwrap();
+        Plan::collect(resolver, dir, &root, Path::new("site"), true, max_size)
+    }
+
+    fn expected() -> Vec<(Vec<u8>, Vec<u8>)> {
+        let mut files = vec![
+            (b"site/".to_vec(), vec![]),
+            (b"site/a.txt".to_vec(), b"hello".to_vec()),
+            (b"site/sub/".to_vec(), vec![]),
+            (b"site/sub/b.txt".to_vec(), b"world".to_vec()),
+        ];
+        if cfg!(unix) {
+            files.push((b"site/caf\xe9.txt".to_vec(), b"latin1".to_vec()));
+        }
+        files.sort();
+        files
+    }
+
+    #[test]
+    fn test_tar_round_trip() {
+        let (_dir, resolver) = setup();
+        let plan = collect(&resolver, 1024).unwrap();
+        let mut buf = Vec::new();
+        plan.write_tar(&resolver, &mut buf).unwrap();
+
+        let mut archive = tar::Archive::new(Cursor::new(buf));
+        let mut files = Vec::new();
+        for entry in archive.entries().unwrap() {
+            let mut entry = entry.unwrap();
+            assert_eq!(entry.header().mtime().unwrap(), 0);
+            let mut name = entry.path_bytes().into_owned();
+            if entry.header().entry_type().is_dir() && !name.ends_with(b"/") {
+                name.push(b'/');
+            }
+            let mut data = Vec::new();
+            entry.read_to_end(&mut data).unwrap();
+            files.push((name, data));
+        }
+        files.sort();
+        assert_eq!(files, expected());
+    }
+
+    #[test]
+    fn test_zip_round_trip() {
+        let (_dir, resolver) = setup();
+        let plan = collect(&resolver, 1024).unwrap();
+        let mut buf = Vec::new();
+        plan.write_zip(&resolver, &mut buf).unwrap();
+        assert_eq!(plan.zip_size().unwrap(), buf.len() as u64);
+
+        let mut archive = zip::ZipArchive::new(Cursor::new(buf)).unwrap();
+        let mut files = Vec::new();
+        for idx in 0..archive.len() {
+            let mut file = archive.by_index(idx).unwrap();
+            let name = file.name_raw().to_vec();
+            let mut data = Vec::new();
+            // this verifies the crc
+            file.read_to_end(&mut data).unwrap();
+            files.push((name, data));
+        }
+        files.sort();
+        assert_eq!(files, expected());
+    }
+
+    #[test]
+    fn test_size_limit() {
+        let (_dir, resolver) = setup();
+        let total = if cfg!(unix) { 16 } else { 10 };
+        assert_eq!(collect(&resolver, total).unwrap().total, total);
+        assert!(collect(&resolver, total - 1).is_err());
+    }
+
+    #[test]
+    fn test_zip64_is_rejected() {
+        let plan = |items: Vec<Item>| Plan {
+            items,
+            total: 0,
+            max_size: u64::MAX,
+        };
+        let item = |name: &str, size| Item {
+            path: PathBuf::from(name),
+            name: PathBuf::from(name),
+            size,
+        };
+
+        let small = plan(vec![item("site", None), item("site/a.txt", Some(5))]);
+        assert!(small.check(Format::Zip).is_ok());
+
+        let large = plan(vec![item("site/a.bin", Some(u64::from(u32::MAX)))]);
+        assert!(large.check(Format::Zip).is_err());
+        assert!(large.check(Format::Tar).is_ok());
+
+        let many = plan((0..=u16::MAX).map(|n| item(&n.to_string(), None)).collect());
+        assert!(many.check(Format::Zip).is_err());
+        assert!(many.check(Format::Tar).is_ok());
+    }
+
+    #[test]
+    fn test_exact_reader() {
+        let mut buf = Vec::new();
+        ExactReader::new(&b"abcdef"[..], 3)
+            .read_to_end(&mut buf)
+            .unwrap();
+        assert_eq!(buf, b"abc");
+
+        let err = ExactReader::new(&b"abc"[..], 5)
+            .read_to_end(&mut Vec::new())
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
@@ -1,24 +1,177 @@
+use crate::access_log::{self, TimePrecision};
+use crate::acme::{self, AcmeChallenge};
 use crate::errors::*;
+use crate::logger::{LogFormat, LogOutput};
+use crate::resolve::SymlinkPolicy;
+use crate::utils;
+use clap::{CommandFactory, FromArgMatches, Parser};
 use libtor::TorAddress;
 use serde::{Deserialize, Serialize};
+use std::ffi::OsString;
+use std::fs;
 use std::path::PathBuf;
+use std::time::Duration;
 
 #[derive(Debug, Clone, clap::Parser, Serialize, Deserialize)]
+#[clap(args_override_self = true)]
 pub struct Args {
     #[clap(short, long, parse(from_occurrences))]
     pub verbose: u8,
+    /// Write log lines as text or as json objects
+    #[clap(long, arg_enum, default_value = "text", env = "NARNIA_LOG_FORMAT")]
+    pub log_format: LogFormat,
+    /// Where logs are written to
+    #[clap(long, arg_enum, default_value = "stderr", env = "NARNIA_LOG_OUTPUT")]
+    pub log_output: LogOutput,
+    /// Read options from this file, one `name = value` per line, it's read again on reload
+    #[clap(short = 'c', long, env = "NARNIA_CONFIG")]
+    pub config: Option<PathBuf>,
+    /// Write the process id into this file, so the config can be reloaded with `narnia reload`
+    #[clap(long)]
+    pub pid_file: Option<PathBuf>,
     /// Enables a Tor thread for a hidden service, configures the folder to store Tor data in
     #[clap(short = 'D', long, env = "NARNIA_DATA_DIR")]
     pub data_dir: Option<PathBuf>,
     /// Files that should be served
     #[clap(short = 'w', long, env = "NARNIA_WEB_ROOT")]
     pub web_root: Option<String>,
-    /// Enable directory listing if no index.html was found
+    /// Only serve a tar or zip bundle signed with this minisign public key, the signature is read from <bundle>.minisig
+    #[clap(long, env = "NARNIA_BUNDLE_KEY")]
+    pub bundle_key: Option<PathBuf>,
+    /// Enable directory listing if no index file was found
     #[clap(short = 'L', long)]
     pub list_directories: bool,
+    /// Html template for directory listings, with {{path}} and {{entries}} placeholders
+    #[clap(long)]
+    pub listing_template: Option<PathBuf>,
+    /// Allow downloading directories with listings as tar or zip up to this size, 0 to disable
+    #[clap(long, default_value = "512M", parse(try_from_str = utils::parse_size))]
+    pub archive_max_size: u64,
+    /// Files to look for when a directory is requested, in order
+    #[clap(
+        long = "index",
+        default_value = "index.html",
+        multiple_occurrences = true,
+        use_value_delimiter = true
+    )]
+    pub index_files: Vec<String>,
+    /// Serve /about from about.html and redirect /about.html to /about
+    #[clap(long)]
+    pub clean_urls: bool,
+    /// Serve hidden files and directories, their names start with a dot
+    #[clap(long)]
+    pub serve_hidden: bool,
+    /// Hidden files and directories that are served regardless, in addition to .well-known
+    #[clap(
+        long = "allow-hidden",
+        multiple_occurrences = true,
+        use_value_delimiter = true
+    )]
+    pub hidden_allowlist: Vec<String>,
+    /// Configure if symlinks inside the web root are followed
+    #[clap(long, arg_enum, default_value = "within-root")]
+    pub symlinks: SymlinkPolicy,
+    /// Serve this file instead of a 404 for unknown paths without a file extension (single page applications)
+    #[clap(long, env = "NARNIA_SPA_FALLBACK")]
+    pub spa_fallback: Option<String>,
+    /// Keep small files in memory, up to this size in total, 0 to disable
+    #[clap(long, default_value = "0", parse(try_from_str = utils::parse_size))]
+    pub cache_size: u64,
+    /// Only cache files up to this size
+    #[clap(long, default_value = "1M", parse(try_from_str = utils::parse_size))]
+    pub cache_max_file_size: u64,
+    /// Accept file uploads into this directory, it needs to be outside of the web root
+    #[clap(long, env = "NARNIA_UPLOAD_DIR")]
+    pub upload_dir: Option<PathBuf>,
+    /// The path of the upload form
+    #[clap(long, default_value = "/upload")]
+    pub upload_path: String,
+    /// The maximum size of a single upload request
+    #[clap(long, default_value = "100M", parse(try_from_str = utils::parse_size))]
+    pub upload_max_size: u64,
+    /// Refuse uploads that would leave less than this much free space on the disk, 0 to disable
+    #[clap(long, default_value = "1G", parse(try_from_str = utils::parse_size))]
+    pub upload_min_free: u64,
+    /// Allow reading and writing this directory with WebDAV
+    #[clap(long, env = "NARNIA_WEBDAV_DIR")]
+    pub webdav_dir: Option<String>,
+    /// The path the WebDAV directory is available at
+    #[clap(long, default_value = "/dav")]
+    pub webdav_path: String,
+    /// The maximum size of a file uploaded with WebDAV
+    #[clap(long, default_value = "1G", parse(try_from_str = utils::parse_size))]
+    pub webdav_max_size: u64,
+    /// Allow WebDAV without authentication, anybody who can reach the server can modify files
+    #[clap(long)]
+    pub webdav_allow_anonymous: bool,
+    /// Require http basic authentication, with argon2 or bcrypt hashes in htpasswd format
+    #[clap(long, env = "NARNIA_HTPASSWD")]
+    pub htpasswd: Option<PathBuf>,
+    /// Accept bearer tokens for authentication, one per line
+    #[clap(long, env = "NARNIA_AUTH_TOKENS")]
+    pub auth_tokens: Option<PathBuf>,
+    /// Only require authentication for these path prefixes instead of everything
+    #[clap(
+        long = "auth-path",
+        multiple_occurrences = true,
+        use_value_delimiter = true
+    )]
+    pub auth_paths: Vec<String>,
+    /// Write an access log to this file, or to stderr with -
+    #[clap(long, env = "NARNIA_ACCESS_LOG")]
+    pub access_log: Option<PathBuf>,
+    /// Format of access log lines with {time}, {method}, {path}, {status}, {size}, {duration}, {ip}, {user_agent} and {referer}
+    #[clap(long, default_value = access_log::DEFAULT_FORMAT)]
+    pub access_log_format: String,
+    /// Round timestamps in the access log down to this precision
+    #[clap(long, arg_enum, default_value = "hour")]
+    pub access_log_time: TimePrecision,
+    /// Don't log individual requests, only write counters once per interval (like 1h)
+    #[clap(long, parse(try_from_str = humantime::parse_duration))]
+    pub access_log_aggregate: Option<Duration>,
     /// The address to find to, supports unix domain sockets
     #[clap(short = 'B', long, env = "NARNIA_BIND_ADDR")]
     pub bind: Option<String>,
+    /// Serve https with this certificate chain, it's read again on reload
+    #[clap(long, env = "NARNIA_TLS_CERT")]
+    pub tls_cert: Option<PathBuf>,
+    /// The private key of the certificate
+    #[clap(long, env = "NARNIA_TLS_KEY")]
+    pub tls_key: Option<PathBuf>,
+    /// Redirect plain http requests on this address to https
+    #[clap(long, env = "NARNIA_HTTP_REDIRECT_BIND")]
+    pub http_redirect_bind: Option<String>,
+    /// Request a certificate for these domains with ACME, instead of --tls-cert and --tls-key
+    #[clap(
+        long = "acme-domain",
+        multiple_occurrences = true,
+        use_value_delimiter = true
+    )]
+    pub acme_domains: Vec<String>,
+    /// Contact email for the ACME account
+    #[clap(long, env = "NARNIA_ACME_EMAIL")]
+    pub acme_email: Option<String>,
+    /// Accept the terms of service of the ACME server
+    #[clap(long)]
+    pub acme_accept_tos: bool,
+    /// The directory url of the ACME server
+    #[clap(long, default_value = acme::LETS_ENCRYPT, env = "NARNIA_ACME_DIRECTORY")]
+    pub acme_directory: String,
+    /// How control over the domains is proven
+    #[clap(long, arg_enum, default_value = "http-01")]
+    pub acme_challenge: AcmeChallenge,
+    /// Store the ACME account and certificates in this folder, defaults to acme/ in the data directory
+    #[clap(long)]
+    pub acme_dir: Option<PathBuf>,
+    /// Serve prometheus metrics at /metrics on this address, it's never exposed on the hidden service
+    #[clap(long, env = "NARNIA_METRICS_BIND")]
+    pub metrics_bind: Option<String>,
+    /// Serve /health, /ready and /status on a unix domain socket in the data directory
+    #[clap(long)]
+    pub admin: bool,
+    /// Serve the admin endpoints on this address instead, implies --admin
+    #[clap(long, env = "NARNIA_ADMIN_BIND")]
+    pub admin_bind: Option<String>,
     #[cfg(unix)]
     /// Change the process to this user after setup
     #[clap(short, long)]
@@ -33,9 +186,139 @@ pub struct Args {
     /// Always use multi-process mode
     #[clap(short = 'm', long)]
     pub always_multi_process: bool,
+    #[clap(subcommand)]
+    #[serde(skip)]
+    pub subcommand: Option<SubCommand>,
+}
+
+#[derive(Debug, Clone, clap::Subcommand)]
+pub enum SubCommand {
+    /// Share a single file on a temporary hidden service
+    Share(ShareArgs),
+    /// Tell a running narnia process to reload its config, this needs --pid-file
+    Reload,
+    /// Copy a directory into a new release and point the web root to it
+    Deploy(DeployArgs),
+}
+
+#[derive(Debug, Clone, clap::Parser)]
+pub struct ShareArgs {
+    /// Stop sharing after the file was downloaded completely
+    #[clap(long)]
+    pub once: bool,
+    /// The file to share
+    pub file: PathBuf,
+}
+
+#[derive(Debug, Clone, clap::Parser)]
+pub struct DeployArgs {
+    /// How many previous releases to keep, the one that was active before is always kept
+    #[clap(long, default_value = "3")]
+    pub keep: usize,
+    /// The directory to deploy
+    pub dir: PathBuf,
+}
+
+/// Turn the lines of a config file into commandline options
+fn parse_config(buf: &str) -> Result<Vec<String>> {
+    let mut options = Vec::new();
+    for (idx, line) in buf.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let (name, value) = match line.split_once('=') {
+            Some((name, value)) => (name.trim(), Some(value.trim())),
+            None => (line, None),
+        };
+        if name.is_empty() || name.starts_with('-') {
+            bail!("Line {}: Invalid option name: {:?}", idx + 1, name);
+        }
+        options.push(format!("--{}", name));
+        options.extend(value.map(String::from));
+    }
+    Ok(options)
+}
+
+/// Paths starting with . or / are unix domain sockets, everything else is a tcp address
+pub fn parse_bind_addr(addr: &str) -> TorAddress {
+    let addr = addr.to_string();
+    cfg_if::cfg_if! {
+        if #[cfg(unix)] {
+            if addr.starts_with('.') || addr.starts_with('/') {
+                TorAddress::Unix(addr)
+            } else {
+                TorAddress::Address(addr)
+            }
+        } else {
+            TorAddress::Address(addr)
+        }
+    }
 }
 
 impl Args {
+    /// Parse the commandline together with the config file, the commandline takes precedence
+    pub fn parse_with_config<I: IntoIterator<Item = OsString>>(argv: I) -> Result<Args> {
+        let argv = argv.into_iter().collect::<Vec<_>>();
+        let matches = Args::command().try_get_matches_from(&argv)?;
+        let mut args = Args::from_arg_matches(&matches)?;
+        let path = if let Some(path) = &args.config {
+            path
+        } else {
+            args.trim_lists();
+            return Ok(args);
+        };
+
+        let buf = fs::read_to_string(path)
+            .with_context(|| anyhow!("Failed to read config file: {:?}", path))?;
+        let options = parse_config(&buf)
+            .with_context(|| anyhow!("Failed to parse config file: {:?}", path))?;
+
+        let merged = argv
+            .iter()
+            .take(1)
+            .cloned()
+            .chain(options.into_iter().map(OsString::from))
+            .chain(argv.iter().skip(1).cloned());
+        let mut merged = Args::try_parse_from(merged)
+            .with_context(|| anyhow!("Invalid option in config file: {:?}", path))?;
+
+        // options that can be given multiple times would be appended to the ones in the file
+        for (id, merged, cmdline) in [
+            ("index-files", &mut merged.index_files, args.index_files),
+            (
+                "hidden-allowlist",
+                &mut merged.hidden_allowlist,
+                args.hidden_allowlist,
+            ),
+            ("auth-paths", &mut merged.auth_paths, args.auth_paths),
+            ("acme-domains", &mut merged.acme_domains, args.acme_domains),
+        ] {
+            if matches.occurrences_of(id) > 0 {
+                *merged = cmdline;
+            }
+        }
+        merged.trim_lists();
+        Ok(merged)
+    }
+
+    /// Lists are split on commas but not trimmed, `index = index.html, index.htm` is common in
+    /// config files
+    fn trim_lists(&mut self) {
+        for list in [
+            &mut self.index_files,
+            &mut self.hidden_allowlist,
+            &mut self.auth_paths,
+            &mut self.acme_domains,
+        ] {
+            for value in list.iter_mut() {
+                *value = value.trim().to_string();
+            }
+            list.retain(|value| !value.is_empty());
+        }
+    }
+
     pub fn needs_child(&self) -> bool {
         cfg_if::cfg_if! {
             if #[cfg(unix)] {
@@ -48,32 +331,156 @@ impl Args {
 
     pub fn bind_addr(&self) -> Result<TorAddress> {
         if let Some(bind_addr) = &self.bind {
-            let bind_addr = bind_addr.to_string();
+            Ok(parse_bind_addr(bind_addr))
+        } else if let Some(data_dir) = &self.data_dir {
             cfg_if::cfg_if! {
                 if #[cfg(unix)] {
-                    if bind_addr.starts_with('.') || bind_addr.starts_with('/') {
-                        Ok(TorAddress::Unix(bind_addr))
-                    } else {
-                        Ok(TorAddress::Address(bind_addr))
-                    }
+                    let path = data_dir.join("narnia.sock");
+                    let path = utils::path_to_string(path)?;
+                    Ok(TorAddress::Unix(path))
                 } else {
-                    Ok(TorAddress::Address(bind_addr))
+                    let _ = data_dir;
+                    bail!("You always have to set -B on windows");
                 }
             }
+        } else {
+            bail!("Either bind address or data directory needs to be configured")
+        }
+    }
+
+    pub fn tls_enabled(&self) -> bool {
+        self.tls_cert.is_some() || self.tls_key.is_some() || self.acme_enabled()
+    }
+
+    pub fn acme_enabled(&self) -> bool {
+        !self.acme_domains.is_empty()
+    }
+
+    pub fn acme_dir(&self) -> Option<PathBuf> {
+        self.acme_dir
+            .clone()
+            .or_else(|| self.data_dir.as_ref().map(|data_dir| data_dir.join("acme")))
+    }
+
+    pub fn admin_enabled(&self) -> bool {
+        self.admin || self.admin_bind.is_some()
+    }
+
+    pub fn admin_addr(&self) -> Result<Option<TorAddress>> {
+        if let Some(addr) = &self.admin_bind {
+            Ok(Some(parse_bind_addr(addr)))
+        } else if !self.admin {
+            Ok(None)
         } else if let Some(data_dir) = &self.data_dir {
             cfg_if::cfg_if! {
                 if #[cfg(unix)] {
-                    use crate::utils;
-                    let path = data_dir.join("narnia.sock");
+                    let path = data_dir.join("admin.sock");
                     let path = utils::path_to_string(path)?;
-                    Ok(TorAddress::Unix(path))
+                    Ok(Some(TorAddress::Unix(path)))
                 } else {
                     let _ = data_dir;
-                    bail!("You always have to set -B on windows");
+                    bail!("You always have to set --admin-bind on windows");
                 }
             }
         } else {
-            bail!("Either bind address or data directory needs to be configured")
+            bail!("The admin endpoints need either --admin-bind or a data directory")
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn test_parse_config() {
+        let buf =
+            "# comment\nweb-root = /srv/www\n\nlist-directories\nindex = index.html, index.htm\n";
+        let options = parse_config(buf).unwrap();
+        assert_eq!(
+            options,
+            &[
+                "--web-root",
+                "/srv/www",
+                "--list-directories",
+                "--index",
+                "index.html, index.htm"
+            ]
+        );
+
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(buf.as_bytes()).unwrap();
+        let argv = ["narnia", "-c", file.path().to_str().unwrap()];
+        let args = Args::parse_with_config(argv.iter().map(OsString::from)).unwrap();
+        assert_eq!(args.web_root.as_deref(), Some("/srv/www"));
+        assert!(args.list_directories);
+        assert_eq!(args.index_files, &["index.html", "index.htm"]);
+    }
+
+    #[test]
+    fn test_config_lists_are_trimmed() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        writeln!(
+            file,
+            "index = index.html, index.htm
+allow-hidden = .well-known, .env
+auth-path = /private, /dav
+acme-domain = a.com, www.a.com,"
+        )
+        .unwrap();
+        let config = file.path().to_str().unwrap();
+
+        let argv = ["narnia", "-c", config];
+        let args = Args::parse_with_config(argv.iter().map(OsString::from)).unwrap();
+        assert_eq!(args.index_files, &["index.html", "index.htm"]);
+        assert_eq!(args.hidden_allowlist, &[".well-known", ".env"]);
+        assert_eq!(args.auth_paths, &["/private", "/dav"]);
+        assert_eq!(args.acme_domains, &["a.com", "www.a.com"]);
+    }
+
+    #[test]
+    fn test_invalid_config() {
+        assert!(parse_config("--web-root = /srv/www").is_err());
+        assert!(parse_config("= /srv/www").is_err());
+    }
+
+    #[test]
+    fn test_commandline_overrides_config() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        writeln!(
+            file,
+            "web-root = /srv/www\nlist-directories\nbind = [::1]:1337"
+        )
+        .unwrap();
+        let config = file.path().to_str().unwrap();
+
+        let argv = ["narnia", "-vv", "-c", config, "-w", "/var/www"];
+        let args = Args::parse_with_config(argv.iter().map(OsString::from)).unwrap();
+        assert_eq!(args.web_root.as_deref(), Some("/var/www"));
+        assert_eq!(args.bind.as_deref(), Some("[::1]:1337"));
+        assert!(args.list_directories);
+        assert_eq!(args.verbose, 2);
+    }
+
+    #[test]
+    fn test_commandline_replaces_lists() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        writeln!(
+            file,
+            "web-root = /srv/www\nindex = index.htm\nauth-path = /private\nauth-path = /dav"
+        )
+        .unwrap();
+        let config = file.path().to_str().unwrap();
+
+        let argv = ["narnia", "-c", config, "--index", "a.html,b.html"];
+        let args = Args::parse_with_config(argv.iter().map(OsString::from)).unwrap();
+        assert_eq!(args.index_files, &["a.html", "b.html"]);
+        assert_eq!(args.auth_paths, &["/private", "/dav"]);
+
+        let argv = ["narnia", "-c", config, "--auth-path", "/admin"];
+        let args = Args::parse_with_config(argv.iter().map(OsString::from)).unwrap();
+        assert_eq!(args.index_files, &["index.htm"]);
+        assert_eq!(args.auth_paths, &["/admin"]);
+    }
+}
@@ -0,0 +1,434 @@
+use crate::acme::{self, AcmeChallenge};
+use crate::args::Args;
+use crate::errors::*;
+use crate::utils;
+use actix_web::body::EitherBody;
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::header::{self, HeaderValue};
+use actix_web::{web, HttpResponse};
+use argon2::{Argon2, PasswordVerifier};
+use futures_util::future::{ready, LocalBoxFuture, Ready};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use subtle::{Choice, ConstantTimeEq};
+
+/// How many verified credentials are remembered, so hashes aren't verified on every request
+const CACHE_SIZE: usize = 1024;
+
+#[derive(Debug, Clone, PartialEq)]
+enum PasswordHash {
+    Argon2(String),
+    Bcrypt(String),
+}
+
+impl PasswordHash {
+    fn parse(hash: &str) -> Result<PasswordHash> {
+        if hash.starts_with("$argon2") {
+            argon2::PasswordHash::new(hash)
+                .map_err(|err| anyhow!("Invalid argon2 hash: {}", err))?;
+            Ok(PasswordHash::Argon2(hash.to_string()))
+        } else if hash.starts_with("$2a$") || hash.starts_with("$2b$") || hash.starts_with("$2y$") {
+            hash.parse::<bcrypt::HashParts>()
+                .map_err(|err| anyhow!("Invalid bcrypt hash: {}", err))?;
+            Ok(PasswordHash::Bcrypt(hash.to_string()))
+        } else {
+            bail!("Unsupported password hash, only argon2 and bcrypt are supported")
+        }
+    }
+
+    /// Hashes with the same parameters take the same time to verify
+    fn params(&self) -> String {
+        match self {
+            PasswordHash::Argon2(hash) => match argon2::PasswordHash::new(hash) {
+                Ok(hash) => format!("{}:{:?}:{}", hash.algorithm, hash.version, hash.params),
+                Err(_) => String::new(),
+            },
+            PasswordHash::Bcrypt(hash) => match hash.parse::<bcrypt::HashParts>() {
+                Ok(parts) => format!("bcrypt:{}", parts.get_cost()),
+                Err(_) => String::new(),
+            },
+        }
+    }
+
+    fn verify(&self, password: &str) -> bool {
+        match self {
+            PasswordHash::Argon2(hash) => argon2::PasswordHash::new(hash)
+                .and_then(|hash| Argon2::default().verify_password(password.as_bytes(), &hash))
+                .is_ok(),
+            PasswordHash::Bcrypt(hash) => bcrypt::verify(password, hash).unwrap_or(false),
+        }
+    }
+}
+
+/// Credentials that are accepted with http basic or bearer token authentication
+#[derive(Debug, Default)]
+pub struct Auth {
+    users: HashMap<String, PasswordHash>,
+    /// Checked when a user doesn't exist, so unknown users take as long as known ones
+    dummy_hash: Option<PasswordHash>,
+    /// Tokens are stored as sha256 so they can be compared in constant time
+    tokens: Vec<[u8; 32]>,
+    /// Only require authentication below these paths, or everywhere if empty
+    paths: Vec<PathBuf>,
+    /// The ACME server can't authenticate, so http-01 challenges are always answered
+    acme_http01: bool,
+    cache: Mutex<HashSet<[u8; 32]>>,
+}
+
+fn sha256(data: &[u8]) -> [u8; 32] {
+    Sha256::digest(data).into()
+}
+
+/// Unknown users are verified against the slowest hash, otherwise a file that mixes algorithms or
+/// costs would reveal which users exist. Verification time can't be compared between algorithms,
+/// so every combination of parameters is measured once
+fn slowest_hash<'a, I: Iterator<Item = &'a PasswordHash>>(hashes: I) -> Option<PasswordHash> {
+    let mut measured = HashMap::new();
+    for hash in hashes {
+        measured.entry(hash.params()).or_insert_with(|| {
+            let start = Instant::now();
+            hash.verify("");
+            (start.elapsed(), hash)
+        });
+    }
+    measured
+        .into_values()
+        .max_by_key(|(elapsed, _)| *elapsed)
+        .map(|(_, hash)| hash.clone())
+}
+
+/// Read a file, skipping empty lines and comments
+fn read_lines(path: &Path) -> Result<Vec<String>> {
+    let buf = fs::read_to_string(path).with_context(|| anyhow!("Failed to read {:?}", path))?;
+    Ok(buf
+        .lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(String::from)
+        .collect())
+}
+
+impl Auth {
+    pub fn load(args: &Args) -> Result<Option<Auth>> {
+        if args.htpasswd.is_none() && args.auth_tokens.is_none() {
+            return Ok(None);
+        }
+
+        let mut auth = Auth::default();
+        if let Some(path) = &args.htpasswd {
+            auth.parse_htpasswd(&read_lines(path)?)
+                .with_context(|| anyhow!("Failed to parse {:?}", path))?;
+        }
+        if let Some(path) = &args.auth_tokens {
+            auth.parse_tokens(&read_lines(path)?);
+        }
+
+        for path in &args.auth_paths {
+            if !path.starts_with('/') {
+                bail!("Authentication path needs to start with /: {:?}", path);
+            }
+            auth.paths.push(PathBuf::from(path));
+        }
+        auth.acme_http01 = args.acme_enabled() && args.acme_challenge == AcmeChallenge::Http01;
+
+        info!(
+            "Requiring authentication for {}, {} users and {} tokens configured",
+            if auth.paths.is_empty() {
+                "all paths".to_string()
+            } else {
+                format!("{:?}", args.auth_paths)
+            },
+            auth.users.len(),
+            auth.tokens.len()
+        );
+        Ok(Some(auth))
+    }
+
+    fn parse_htpasswd(&mut self, lines: &[String]) -> Result<()> {
+        for (idx, line) in lines.iter().enumerate() {
+            let (user, hash) = line
+                .split_once(':')
+                .with_context(|| anyhow!("Entry {}: Expected user:hash", idx + 1))?;
+            let hash = PasswordHash::parse(hash)
+                .with_context(|| anyhow!("Entry {}: {:?}", idx + 1, user))?;
+            self.users.insert(user.to_string(), hash);
+        }
+        self.dummy_hash = slowest_hash(self.users.values());
+        Ok(())
+    }
+
+    fn parse_tokens(&mut self, lines: &[String]) {
+        for token in lines {
+            self.tokens.push(sha256(token.as_bytes()));
+        }
+    }
+
+    /// Check if authentication is required for a percent-encoded url path
+    pub fn applies(&self, url_path: &str) -> bool {
+        if self.acme_http01 && acme::is_challenge_path(url_path) {
+            return false;
+        }
+        if self.paths.is_empty() {
+            return true;
+        }
+        // compare decoded path components, so `/%70rivate` or `//private` are still covered
+        let path = utils::decode_url_path(url_path);
+        self.paths.iter().any(|prefix| path.starts_with(prefix))
+    }
+
+    fn verify_token(&self, token: &str) -> bool {
+        let token = sha256(token.as_bytes());
+        let mut valid = Choice::from(0);
+        for known in &self.tokens {
+            valid |= known.ct_eq(&token);
+        }
+        valid.into()
+    }
+
+    fn verify_password(&self, user: &str, password: &str) -> bool {
+        let (hash, exists) = match (self.users.get(user), &self.dummy_hash) {
+            (Some(hash), _) => (hash, true),
+            (None, Some(dummy)) => (dummy, false),
+            (None, None) => return false,
+        };
+        hash.verify(password) && exists
+    }
+
+    /// Verify the value of an authorization header, this might be slow
+    pub fn verify(&self, authorization: Option<&HeaderValue>) -> bool {
+        let value = match authorization {
+            Some(value) => value.as_bytes(),
+            None => return false,
+        };
+
+        let key = sha256(value);
+        if self.cache.lock().unwrap().contains(&key) {
+            return true;
+        }
+
+        let value = match std::str::from_utf8(value) {
+            Ok(value) => value,
+            Err(_) => return false,
+        };
+        let (scheme, credentials) = value.split_once(' ').unwrap_or((value, ""));
+        let credentials = credentials.trim();
+
+        let valid = if scheme.eq_ignore_ascii_case("bearer") {
+            self.verify_token(credentials)
+        } else if scheme.eq_ignore_ascii_case("basic") {
+            base64::decode(credentials)
+                .ok()
+                .and_then(|credentials| String::from_utf8(credentials).ok())
+                .and_then(|credentials| {
+                    let (user, password) = credentials.split_once(':')?;
+                    Some(self.verify_password(user, password))
+                })
+                .unwrap_or(false)
+        } else {
+            false
+        };
+
+        if valid {
+            let mut cache = self.cache.lock().unwrap();
+            if cache.len() >= CACHE_SIZE {
+                cache.clear();
+            }
+            cache.insert(key);
+        }
+        valid
+    }
+
+    fn unauthorized(&self) -> HttpResponse {
+        let challenge = if self.users.is_empty() {
+            "Bearer realm=\"narnia\""
+        } else {
+            "Basic realm=\"narnia\", charset=\"UTF-8\""
+        };
+        HttpResponse::Unauthorized()
+            .append_header((header::WWW_AUTHENTICATE, challenge))
+            .content_type("text/plain; charset=utf-8")
+            .body("401 - unauthorized\n")
+    }
+}
+
+/// Middleware that rejects requests without valid credentials
+pub struct RequireAuth {
+    auth: Arc<Auth>,
+}
+
+impl RequireAuth {
+    pub fn new(auth: Arc<Auth>) -> RequireAuth {
+        RequireAuth { auth }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for RequireAuth
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = actix_web::Error;
+    type Transform = RequireAuthMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RequireAuthMiddleware {
+            service: Rc::new(service),
+            auth: self.auth.clone(),
+        }))
+    }
+}
+
+pub struct RequireAuthMiddleware<S> {
+    service: Rc<S>,
+    auth: Arc<Auth>,
+}
+
+impl<S, B> Service<ServiceRequest> for RequireAuthMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        let auth = self.auth.clone();
+
+        Box::pin(async move {
+            if auth.applies(req.path()) {
+                let authorization = req.headers().get(header::AUTHORIZATION).cloned();
+                let verifier = auth.clone();
+                // password hashes are slow on purpose, don't block the worker
+                let valid = web::block(move || verifier.verify(authorization.as_ref()))
+                    .await
+                    .unwrap_or(false);
+                if !valid {
+                    debug!("Rejecting unauthenticated request for {:?}", req.path());
+                    let response = auth.unauthorized();
+                    return Ok(req.into_response(response).map_into_right_body());
+                }
+            }
+
+            service
+                .call(req)
+                .await
+                .map(ServiceResponse::map_into_left_body)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use argon2::password_hash::{PasswordHasher, SaltString};
+    use test_case::test_case;
+
+    fn auth() -> Auth {
+        let salt = SaltString::new("c29tZXNhbHRzb21lc2FsdA").unwrap();
+        // cheap parameters to keep the tests fast, they are read from the hash during verification
+        let params = argon2::Params::new(256, 1, 1, None).unwrap();
+        let argon2 = Argon2::new(argon2::Algorithm::Argon2id, argon2::Version::V0x13, params)
+            .hash_password(b"hunter2", &salt)
+            .unwrap()
+            .to_string();
+        let bcrypt = bcrypt::hash("correct horse", 4).unwrap();
+
+        let mut auth = Auth::default();
+        auth.parse_htpasswd(&[format!("alice:{}", argon2), format!("bob:{}", bcrypt)])
+            .unwrap();
+        auth.parse_tokens(&["s3cr3t-t0ken".to_string()]);
+        auth
+    }
+
+    fn basic(credentials: &str) -> HeaderValue {
+        HeaderValue::from_str(&format!("Basic {}", base64::encode(credentials))).unwrap()
+    }
+
+    #[test_case(Some(basic("alice:hunter2")), true; "argon2")]
+    #[test_case(Some(basic("bob:correct horse")), true; "bcrypt")]
+    #[test_case(Some(HeaderValue::from_static("Bearer s3cr3t-t0ken")), true; "token")]
+    #[test_case(Some(HeaderValue::from_static("bearer s3cr3t-t0ken")), true; "scheme is case insensitive")]
+    #[test_case(None, false; "missing")]
+    #[test_case(Some(basic("alice:hunter3")), false; "wrong password")]
+    #[test_case(Some(basic("bob:hunter2")), false; "password of other user")]
+    #[test_case(Some(basic("mallory:hunter2")), false; "unknown user with password of first user")]
+    #[test_case(Some(basic("alice")), false; "missing password")]
+    #[test_case(Some(HeaderValue::from_static("Bearer s3cr3t")), false; "token prefix")]
+    #[test_case(Some(HeaderValue::from_static("Digest s3cr3t-t0ken")), false; "unsupported scheme")]
+    fn test_verify(header: Option<HeaderValue>, valid: bool) {
+        assert_eq!(auth().verify(header.as_ref()), valid);
+    }
+
+    #[test]
+    fn test_dummy_hash_is_slowest() {
+        let salt = SaltString::new("c29tZXNhbHRzb21lc2FsdA").unwrap();
+        let params = argon2::Params::new(256, 1, 1, None).unwrap();
+        let argon2 = Argon2::new(argon2::Algorithm::Argon2id, argon2::Version::V0x13, params)
+            .hash_password(b"hunter2", &salt)
+            .unwrap()
+            .to_string();
+        let cheap = bcrypt::hash("correct horse", 4).unwrap();
+        // about a hundred times slower than the others
+        let slow = bcrypt::hash("battery staple", 10).unwrap();
+
+        let mut auth = Auth::default();
+        auth.parse_htpasswd(&[
+            format!("alice:{}", argon2),
+            format!("bob:{}", cheap),
+            format!("carol:{}", slow),
+        ])
+        .unwrap();
+        assert_eq!(auth.dummy_hash, Some(PasswordHash::Bcrypt(slow)));
+        assert!(!auth.verify_password("mallory", "battery staple"));
+        assert!(auth.verify_password("carol", "battery staple"));
+    }
+
+    #[test_case("/private", true; "prefix")]
+    #[test_case("/private/a.txt", true; "below prefix")]
+    #[test_case("//private/a.txt", true; "double slash")]
+    #[test_case("/%70rivate/a.txt", true; "encoded")]
+    #[test_case("/privateer", false; "partial segment")]
+    #[test_case("/", false; "root")]
+    fn test_applies(path: &str, expected: bool) {
+        let mut auth = auth();
+        auth.paths.push(PathBuf::from("/private"));
+        assert_eq!(auth.applies(path), expected);
+    }
+
+    #[test_case("/.well-known/acme-challenge/abc", false; "challenge")]
+    #[test_case("/.well-known/acme-challenge/a/b", true; "below challenge")]
+    #[test_case("/index.html", true; "other path")]
+    fn test_applies_acme_http01(path: &str, expected: bool) {
+        let mut auth = auth();
+        auth.acme_http01 = true;
+        assert_eq!(auth.applies(path), expected);
+        auth.paths.push(PathBuf::from("/.well-known"));
+        assert_eq!(auth.applies(path), expected && path != "/index.html");
+    }
+
+    #[test]
+    fn test_challenge_requires_auth_without_acme() {
+        assert!(auth().applies("/.well-known/acme-challenge/abc"));
+    }
+
+    #[test_case("alice:plaintext"; "plaintext")]
+    #[test_case("alice:$1$md5crypt$"; "md5crypt")]
+    #[test_case("alice:$argon2id$v=19$m=4096,t=3,p=1$c2FsdA$!!"; "invalid argon2")]
+    #[test_case("alice:$2y$10$short"; "invalid bcrypt")]
+    #[test_case("alice"; "missing hash")]
+    fn test_invalid_htpasswd(line: &str) {
+        assert!(Auth::default().parse_htpasswd(&[line.to_string()]).is_err());
+    }
+}
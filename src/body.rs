@@ -0,0 +1,53 @@
+use actix_web::body::{BodySize, BoxBody, MessageBody};
+use actix_web::web::Bytes;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+/// Counts the bytes of a response body, `done` is called with the total once the body is dropped,
+/// either because it was sent completely or because the connection was closed
+pub struct CountingBody {
+    body: BoxBody,
+    size: u64,
+    done: Option<Box<dyn FnOnce(u64)>>,
+}
+
+impl CountingBody {
+    pub fn new<B, F>(body: B, done: F) -> CountingBody
+    where
+        B: MessageBody + 'static,
+        F: FnOnce(u64) + 'static,
+    {
+        CountingBody {
+            body: body.boxed(),
+            size: 0,
+            done: Some(Box::new(done)),
+        }
+    }
+}
+
+impl MessageBody for CountingBody {
+    type Error = Box<dyn std::error::Error>;
+
+    fn size(&self) -> BodySize {
+        self.body.size()
+    }
+
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Bytes, Self::Error>>> {
+        let poll = Pin::new(&mut self.body).poll_next(cx);
+        if let Poll::Ready(Some(Ok(chunk))) = &poll {
+            self.size += chunk.len() as u64;
+        }
+        poll
+    }
+}
+
+impl Drop for CountingBody {
+    fn drop(&mut self) {
+        if let Some(done) = self.done.take() {
+            done(self.size);
+        }
+    }
+}
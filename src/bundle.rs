@@ -0,0 +1,894 @@
+use crate::errors::*;
+use crate::resolve::{DirEntry, Metadata};
+use crate::signing::SigningKey;
+use actix_web::web::{self, Bytes};
+use flate2::read::DeflateDecoder;
+use futures_util::stream::{self, Stream};
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Component, Path};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const CHUNK_SIZE: usize = 64 * 1024;
+/// The fixed size of the zip end of central directory record, without the comment
+const ZIP_EOCD_SIZE: usize = 22;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Compression {
+    Stored,
+    Deflate,
+}
+
+#[derive(Debug)]
+enum Kind {
+    /// The index of every child, by name
+    Dir(BTreeMap<OsString, usize>),
+    File {
+        offset: u64,
+        compressed_len: u64,
+        compression: Compression,
+    },
+}
+
+#[derive(Debug)]
+struct Entry {
+    kind: Kind,
+    len: u64,
+    modified: Option<SystemTime>,
+}
+
+/// Where the bytes of a bundle are read from
+#[derive(Debug)]
+enum Source {
+    File(File),
+    /// A tar file that was embedded into the binary at build time
+    #[cfg_attr(not(feature = "embed"), allow(dead_code))]
+    Static(&'static [u8]),
+    /// A signed bundle, it's kept in memory so only the bytes that were verified are served
+    Memory(Vec<u8>),
+}
+
+impl Source {
+    fn len(&self) -> io::Result<u64> {
+        match self {
+            Source::File(file) => Ok(file.metadata()?.len()),
+            Source::Static(bytes) => Ok(bytes.len() as u64),
+            Source::Memory(bytes) => Ok(bytes.len() as u64),
+        }
+    }
+
+    fn reader(&self) -> Box<dyn Read + '_> {
+        match self {
+            Source::File(file) => Box::new(file),
+            Source::Static(bytes) => Box::new(*bytes),
+            Source::Memory(bytes) => Box::new(&bytes[..]),
+        }
+    }
+
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        match self {
+            Source::File(file) => read_at(file, buf, offset),
+            Source::Static(bytes) => Ok(read_slice_at(bytes, buf, offset)),
+            Source::Memory(bytes) => Ok(read_slice_at(bytes, buf, offset)),
+        }
+    }
+
+    fn read_exact_at(&self, mut buf: &mut [u8], mut offset: u64) -> io::Result<()> {
+        while !buf.is_empty() {
+            match self.read_at(buf, offset)? {
+                0 => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "Bundle is truncated",
+                    ))
+                }
+                n => {
+                    buf = &mut buf[n..];
+                    offset += n as u64;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A tar or zip file that is served instead of a directory. The index is built once when the
+/// bundle is loaded, files are read from the bundle on every request.
+#[derive(Debug)]
+pub struct Bundle {
+    source: Source,
+    /// The first entry is always the root directory
+    entries: Vec<Entry>,
+}
+
+#[cfg(unix)]
+fn read_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    use std::os::unix::fs::FileExt;
+    file.read_at(buf, offset)
+}
+
+#[cfg(windows)]
+fn read_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    use std::os::windows::fs::FileExt;
+    file.seek_read(buf, offset)
+}
+
+fn read_slice_at(bytes: &[u8], buf: &mut [u8], offset: u64) -> usize {
+    let start = usize::try_from(offset).unwrap_or(usize::MAX);
+    let src = bytes.get(start..).unwrap_or_default();
+    let n = buf.len().min(src.len());
+    buf[..n].copy_from_slice(&src[..n]);
+    n
+}
+
+fn u16_at(buf: &[u8], pos: usize) -> u16 {
+    u16::from_le_bytes([buf[pos], buf[pos + 1]])
+}
+
+fn u32_at(buf: &[u8], pos: usize) -> u32 {
+    u32::from_le_bytes([buf[pos], buf[pos + 1], buf[pos + 2], buf[pos + 3]])
+}
+
+#[cfg(unix)]
+fn path_from_bytes(bytes: &[u8]) -> OsString {
+    use std::os::unix::ffi::OsStrExt;
+    std::ffi::OsStr::from_bytes(bytes).to_os_string()
+}
+
+#[cfg(not(unix))]
+fn path_from_bytes(bytes: &[u8]) -> OsString {
+    OsString::from(String::from_utf8_lossy(bytes).into_owned())
+}
+
+/// Days between 1970-01-01 and a date, see http://howardhinnant.github.io/date_algorithms.html
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+/// Zip files store the modification time in the MS-DOS format, without a timezone. Like most
+/// tools we assume UTC.
+fn dos_time(date: u16, time: u16) -> Option<SystemTime> {
+    let year = 1980 + i64::from(date >> 9);
+    let month = i64::from((date >> 5) & 0xf);
+    let day = i64::from(date & 0x1f);
+    let (hour, minute, second) = (time >> 11, (time >> 5) & 0x3f, (time & 0x1f) * 2);
+    if !(1..=12).contains(&month) || day == 0 || hour > 23 || minute > 59 || second > 59 {
+        return None;
+    }
+    let days = days_from_civil(year, month, day);
+    let secs = days * 86400 + i64::from(hour) * 3600 + i64::from(minute) * 60 + i64::from(second);
+    Some(UNIX_EPOCH + Duration::from_secs(u64::try_from(secs).ok()?))
+}
+
+/// The unix modification time from the extended timestamp extra field, if there is one
+fn zip_extended_mtime(mut extra: &[u8]) -> Option<SystemTime> {
+    while extra.len() >= 4 {
+        let id = u16_at(extra, 0);
+        let len = usize::from(u16_at(extra, 2));
+        let data = extra.get(4..4 + len)?;
+        // the flags say which times are present, the modification time is always first
+        if id == 0x5455 && data.len() >= 5 && data[0] & 1 != 0 {
+            let mtime = i32::from_le_bytes([data[1], data[2], data[3], data[4]]);
+            let mtime = u64::try_from(mtime).ok()?;
+            return Some(UNIX_EPOCH + Duration::from_secs(mtime));
+        }
+        extra = &extra[4 + len..];
+    }
+    None
+}
+
+/// Split a path from the archive into its components, paths that could point outside of the
+/// bundle are rejected
+fn normalize(path: &Path) -> Option<Vec<OsString>> {
+    let mut components = Vec::new();
+    for comp in path.components() {
+        match comp {
+            Component::Normal(name) => components.push(name.to_os_string()),
+            Component::CurDir => (),
+            _ => return None,
+        }
+    }
+    Some(components)
+}
+
+impl Bundle {
+    pub fn open(path: &Path) -> Result<Bundle> {
+        let file =
+            File::open(path).with_context(|| anyhow!("Failed to open bundle: {:?}", path))?;
+        Bundle::index_file(path, file)
+    }
+
+    /// Only load the bundle if it was signed with `key`. The bundle is read into memory and the
+    /// signature is checked on those bytes, so modifying the file afterwards has no effect.
+    pub fn open_signed(path: &Path, key: &SigningKey) -> Result<Bundle> {
+        let data = fs::read(path).with_context(|| anyhow!("Failed to read bundle: {:?}", path))?;
+        let comment = key.verify(path, &data[..])?;
+        info!(
+            "Verified signature of bundle {:?} by key {}: {}",
+            path,
+            key.id(),
+            comment
+        );
+        Bundle::index(path, Source::Memory(data))
+    }
+
+    fn index_file(path: &Path, file: File) -> Result<Bundle> {
+        Bundle::index(path, Source::File(file))
+    }
+
+    fn index(path: &Path, source: Source) -> Result<Bundle> {
+        let mut bundle = Bundle::new(source);
+
+        let mut magic = [0; 4];
+        bundle
+            .source
+            .read_exact_at(&mut magic, 0)
+            .with_context(|| anyhow!("Failed to read bundle: {:?}", path))?;
+        match magic {
+            [b'P', b'K', 3, 4] | [b'P', b'K', 5, 6] => bundle.index_zip(),
+            [0x1f, 0x8b, _, _] | [b'B', b'Z', b'h', _] | [0xfd, b'7', b'z', b'X'] => {
+                bail!("Compressed tar files can't be served, use an uncompressed tar or zip file")
+            }
+            _ => bundle.index_tar(),
+        }
+        .with_context(|| anyhow!("Failed to index bundle: {:?}", path))?;
+
+        info!(
+            "Loaded bundle {:?} with {} entries",
+            path,
+            bundle.entries.len() - 1
+        );
+        Ok(bundle)
+    }
+
+    /// The files that were embedded with the `embed` feature, they're packed into a tar file by
+    /// the build script
+    #[cfg(feature = "embed")]
+    pub fn embedded() -> Result<Bundle> {
+        static EMBEDDED: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/embedded.tar"));
+
+        let mut bundle = Bundle::new(Source::Static(EMBEDDED));
+        bundle
+            .index_tar()
+            .context("Failed to index embedded files")?;
+        info!("Loaded {} embedded entries", bundle.entries.len() - 1);
+        Ok(bundle)
+    }
+
+    fn new(source: Source) -> Bundle {
+        Bundle {
+            source,
+            entries: vec![Entry {
+                kind: Kind::Dir(BTreeMap::new()),
+                len: 0,
+                modified: None,
+            }],
+        }
+    }
+
+    /// Add an entry, missing parent directories are created
+    fn insert(&mut self, components: &[OsString], entry: Entry) {
+        let (name, parents) = match components.split_last() {
+            Some(split) => split,
+            // the root directory is created implicitly
+            None => return,
+        };
+
+        let mut idx = 0;
+        for parent in parents {
+            idx = match self.child(idx, parent) {
+                Some(child) if matches!(self.entries[child].kind, Kind::Dir(_)) => child,
+                Some(_) => {
+                    warn!("Skipping bundle entry below a file: {:?}", components);
+                    return;
+                }
+                None => self.add_child(
+                    idx,
+                    parent.clone(),
+                    Entry {
+                        kind: Kind::Dir(BTreeMap::new()),
+                        len: 0,
+                        modified: None,
+                    },
+                ),
+            };
+        }
+
+        match self.child(idx, name) {
+            // a directory that was already created implicitly
+            Some(existing) if matches!(self.entries[existing].kind, Kind::Dir(_)) => {
+                if let Kind::Dir(_) = entry.kind {
+                    self.entries[existing].modified = entry.modified;
+                } else {
+                    warn!("Skipping file that is also a directory: {:?}", components);
+                }
+            }
+            // like tar, later entries replace earlier ones
+            Some(existing) => self.entries[existing] = entry,
+            None => {
+                self.add_child(idx, name.clone(), entry);
+            }
+        }
+    }
+
+    fn child(&self, idx: usize, name: &OsString) -> Option<usize> {
+        match &self.entries[idx].kind {
+            Kind::Dir(children) => children.get(name).copied(),
+            Kind::File { .. } => None,
+        }
+    }
+
+    fn add_child(&mut self, parent: usize, name: OsString, entry: Entry) -> usize {
+        let idx = self.entries.len();
+        self.entries.push(entry);
+        if let Kind::Dir(children) = &mut self.entries[parent].kind {
+            children.insert(name, idx);
+        }
+        idx
+    }
+
+    fn index_tar(&mut self) -> Result<()> {
+        let mut archive = tar::Archive::new(self.source.reader());
+        let mut entries = Vec::new();
+        for entry in archive.entries()? {
+            let entry = entry?;
+            let path = entry.path()?.into_owned();
+            let components = match normalize(&path) {
+                Some(components) => components,
+                None => {
+                    warn!("Skipping bundle entry with invalid path: {:?}", path);
+                    continue;
+                }
+            };
+
+            let header = entry.header();
+            let modified = header
+                .mtime()
+                .ok()
+                .map(|secs| SystemTime::UNIX_EPOCH + Duration::from_secs(secs));
+            let kind = match header.entry_type() {
+                tar::EntryType::Directory => Kind::Dir(BTreeMap::new()),
+                tar::EntryType::Regular | tar::EntryType::Continuous => Kind::File {
+                    offset: entry.raw_file_position(),
+                    compressed_len: entry.size(),
+                    compression: Compression::Stored,
+                },
+                other => {
+                    debug!("Skipping bundle entry of type {:?}: {:?}", other, path);
+                    continue;
+                }
+            };
+            entries.push((
+                components,
+                Entry {
+                    kind,
+                    len: entry.size(),
+                    modified,
+                },
+            ));
+        }
+        drop(archive);
+
+        for (components, entry) in entries {
+            self.insert(&components, entry);
+        }
+        Ok(())
+    }
+
+    fn index_zip(&mut self) -> Result<()> {
+        let len = self.source.len()?;
+        let tail_len = len.min((ZIP_EOCD_SIZE + usize::from(u16::MAX)) as u64);
+        let mut tail = vec![0; tail_len as usize];
+        self.source.read_exact_at(&mut tail, len - tail_len)?;
+
+        let eocd = tail
+            .len()
+            .checked_sub(ZIP_EOCD_SIZE)
+            .and_then(|last| {
+                (0..=last)
+                    .rev()
+                    .find(|&i| tail[i..i + 4] == [b'P', b'K', 5, 6])
+            })
+            .context("Missing zip end of central directory")?;
+        let eocd = &tail[eocd..];
+        let count = u16_at(eocd, 10);
+        let cd_size = u32_at(eocd, 12);
+        let cd_offset = u32_at(eocd, 16);
+        if count == u16::MAX || cd_size == u32::MAX || cd_offset == u32::MAX {
+            bail!("Zip64 bundles aren't supported");
+        }
+
+        let mut cd = vec![0; cd_size as usize];
+        self.source.read_exact_at(&mut cd, u64::from(cd_offset))?;
+
+        let mut pos = 0;
+        for _ in 0..count {
+            let header = cd
+                .get(pos..pos + 46)
+                .context("Zip central directory is truncated")?;
+            if u32_at(header, 0) != 0x0201_4b50 {
+                bail!("Invalid zip central directory header");
+            }
+            let made_by = u16_at(header, 4);
+            let flags = u16_at(header, 8);
+            let method = u16_at(header, 10);
+            let mtime = u16_at(header, 12);
+            let mdate = u16_at(header, 14);
+            let compressed_len = u32_at(header, 20);
+            let uncompressed_len = u32_at(header, 24);
+            let name_len = usize::from(u16_at(header, 28));
+            let extra_len = usize::from(u16_at(header, 30));
+            let comment_len = usize::from(u16_at(header, 32));
+            let external_attrs = u32_at(header, 38);
+            let local_offset = u32_at(header, 42);
+            let name = cd
+                .get(pos + 46..pos + 46 + name_len)
+                .context("Zip central directory is truncated")?;
+            let path = path_from_bytes(name);
+            let extra = cd
+                .get(pos + 46 + name_len..pos + 46 + name_len + extra_len)
+                .context("Zip central directory is truncated")?;
+            let modified = zip_extended_mtime(extra).or_else(|| dos_time(mdate, mtime));
+            pos += 46 + name_len + extra_len + comment_len;
+
+            if [compressed_len, uncompressed_len, local_offset].contains(&u32::MAX) {
+                bail!("Zip64 bundles aren't supported");
+            }
+            let components = match normalize(Path::new(&path)) {
+                Some(components) => components,
+                None => {
+                    warn!("Skipping bundle entry with invalid path: {:?}", path);
+                    continue;
+                }
+            };
+            if flags & 1 != 0 {
+                warn!("Skipping encrypted bundle entry: {:?}", path);
+                continue;
+            }
+            // created on unix, with the file type in the upper half of the attributes
+            if made_by >> 8 == 3 && (external_attrs >> 16) & 0o170000 == 0o120000 {
+                debug!("Skipping symlink in bundle: {:?}", path);
+                continue;
+            }
+
+            let kind = if name.ends_with(b"/") {
+                Kind::Dir(BTreeMap::new())
+            } else {
+                let compression = match method {
+                    0 => Compression::Stored,
+                    8 => Compression::Deflate,
+                    _ => {
+                        warn!(
+                            "Skipping bundle entry with unsupported compression method {}: {:?}",
+                            method, path
+                        );
+                        continue;
+                    }
+                };
+
+                let mut local = [0; 30];
+                self.source
+                    .read_exact_at(&mut local, u64::from(local_offset))?;
+                if u32_at(&local, 0) != 0x0403_4b50 {
+                    bail!("Invalid zip local file header: {:?}", path);
+                }
+                let offset = u64::from(local_offset)
+                    + 30
+                    + u64::from(u16_at(&local, 26))
+                    + u64::from(u16_at(&local, 28));
+                Kind::File {
+                    offset,
+                    compressed_len: u64::from(compressed_len),
+                    compression,
+                }
+            };
+
+            self.insert(
+                &components,
+                Entry {
+                    kind,
+                    len: u64::from(uncompressed_len),
+                    modified,
+                },
+            );
+        }
+        Ok(())
+    }
+
+    /// Find an entry by the components of its path
+    pub fn lookup(self: &Arc<Self>, components: &[OsString]) -> Option<Handle> {
+        let mut idx = 0;
+        for name in components {
+            idx = self.child(idx, name)?;
+        }
+        Some(Handle {
+            bundle: self.clone(),
+            idx,
+        })
+    }
+}
+
+/// A file or directory inside of a bundle
+#[derive(Debug, Clone)]
+pub struct Handle {
+    bundle: Arc<Bundle>,
+    idx: usize,
+}
+
+impl Handle {
+    fn entry(&self) -> &Entry {
+        &self.bundle.entries[self.idx]
+    }
+
+    pub fn metadata(&self) -> Metadata {
+        let entry = self.entry();
+        Metadata::new(
+            matches!(entry.kind, Kind::Dir(_)),
+            entry.len,
+            entry.modified,
+        )
+    }
+
+    pub fn read_dir(&self) -> io::Result<Vec<DirEntry>> {
+        let children = match &self.entry().kind {
+            Kind::Dir(children) => children,
+            Kind::File { .. } => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "Not a directory",
+                ))
+            }
+        };
+        Ok(children
+            .iter()
+            .map(|(name, idx)| {
+                let entry = &self.bundle.entries[*idx];
+                DirEntry {
+                    name: name.clone(),
+                    len: entry.len,
+                    is_dir: matches!(entry.kind, Kind::Dir(_)),
+                    is_symlink: false,
+                    modified: entry.modified,
+                }
+            })
+            .collect())
+    }
+
+    /// Read the content of a file, starting at `offset`
+    pub fn reader(&self, offset: u64) -> io::Result<Box<dyn Read + Send>> {
+        let entry = self.entry();
+        let (data_offset, compressed_len, compression) = match entry.kind {
+            Kind::File {
+                offset,
+                compressed_len,
+                compression,
+            } => (offset, compressed_len, compression),
+            Kind::Dir(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "Is a directory",
+                ))
+            }
+        };
+
+        match compression {
+            Compression::Stored => {
+                let offset = offset.min(entry.len);
+                Ok(Box::new(EntryReader {
+                    bundle: self.bundle.clone(),
+                    offset: data_offset + offset,
+                    remaining: entry.len - offset,
+                }))
+            }
+            Compression::Deflate => {
+                let reader = EntryReader {
+                    bundle: self.bundle.clone(),
+                    offset: data_offset,
+                    remaining: compressed_len,
+                };
+                // never return more than the size in the index
+                let mut reader = DeflateDecoder::new(reader).take(entry.len);
+                io::copy(&mut reader.by_ref().take(offset), &mut io::sink())?;
+                Ok(Box::new(reader))
+            }
+        }
+    }
+}
+
+/// Read a slice of the bundle, positional reads allow concurrent readers on the same file
+struct EntryReader {
+    bundle: Arc<Bundle>,
+    offset: u64,
+    remaining: u64,
+}
+
+impl Read for EntryReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.remaining == 0 {
+            return Ok(0);
+        }
+        let len = buf
+            .len()
+            .min(usize::try_from(self.remaining).unwrap_or(usize::MAX));
+        let n = self.bundle.source.read_at(&mut buf[..len], self.offset)?;
+        if n == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "Bundle is truncated",
+            ));
+        }
+        self.offset += n as u64;
+        self.remaining -= n as u64;
+        Ok(n)
+    }
+}
+
+/// Stream `len` bytes from a reader, the reads are done in a blocking thread
+pub fn stream(reader: Box<dyn Read + Send>, len: u64) -> impl Stream<Item = io::Result<Bytes>> {
+    stream::unfold(Some(reader.take(len)), |reader| async move {
+        let mut reader = reader?;
+        let result = web::block(move || {
+            let mut buf = vec![0; CHUNK_SIZE];
+            let n = reader.read(&mut buf)?;
+            buf.truncate(n);
+            Ok::<_, io::Error>((reader, buf))
+        })
+        .await;
+
+        match result {
+            Ok(Ok((_, buf))) if buf.is_empty() => None,
+            Ok(Ok((reader, buf))) => Some((Ok(Bytes::from(buf)), Some(reader))),
+            Ok(Err(err)) => Some((Err(err), None)),
+            Err(_) => Some((Err(io::Error::other("Blocking task failed")), None)),
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    fn read(bundle: &Arc<Bundle>, path: &str, offset: u64) -> String {
+        let components = normalize(Path::new(path)).unwrap();
+        let mut buf = String::new();
+        bundle
+            .lookup(&components)
+            .unwrap()
+            .reader(offset)
+            .unwrap()
+            .read_to_string(&mut buf)
+            .unwrap();
+        buf
+    }
+
+    fn list(bundle: &Arc<Bundle>, path: &str) -> Vec<(String, bool)> {
+        let components = normalize(Path::new(path)).unwrap();
+        bundle
+            .lookup(&components)
+            .unwrap()
+            .read_dir()
+            .unwrap()
+            .into_iter()
+            .map(|entry| (entry.name.to_string_lossy().into_owned(), entry.is_dir))
+            .collect()
+    }
+
+    fn tar_bundle() -> tempfile::NamedTempFile {
+        let mut builder = tar::Builder::new(tempfile::NamedTempFile::new().unwrap());
+        for (path, data) in [
+            ("./index.html", "hello world"),
+            ("docs/a.txt", "aaa"),
+            ("docs/a.txt", "replaced"),
+            ("../escape.txt", "nope"),
+        ] {
+            let mut header = tar::Header::new_gnu();
+            header.set_entry_type(tar::EntryType::Regular);
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            // set the path without validation, so the invalid one ends up in the archive
+            header.as_gnu_mut().unwrap().name[..path.len()].copy_from_slice(path.as_bytes());
+            header.set_cksum();
+            builder.append(&header, data.as_bytes()).unwrap();
+        }
+        builder.into_inner().unwrap()
+    }
+
+    #[test]
+    fn test_tar_bundle() {
+        let file = tar_bundle();
+        let bundle = Arc::new(Bundle::open(file.path()).unwrap());
+        assert_eq!(
+            list(&bundle, ""),
+            &[
+                ("docs".to_string(), true),
+                ("index.html".to_string(), false)
+            ]
+        );
+        assert_eq!(read(&bundle, "index.html", 0), "hello world");
+        assert_eq!(read(&bundle, "index.html", 6), "world");
+        assert_eq!(read(&bundle, "docs/a.txt", 0), "replaced");
+        assert!(bundle
+            .lookup(&normalize(Path::new("escape.txt")).unwrap())
+            .is_none());
+        assert!(bundle
+            .lookup(&normalize(Path::new("index.html/a")).unwrap())
+            .is_none());
+    }
+
+    #[test]
+    fn test_zip_bundle() {
+        // a minimal zip with a deflated index.html and a stored docs/a.txt
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        let mut zip = Vec::new();
+        let mut cd = Vec::new();
+        let deflated = {
+            let mut encoder =
+                flate2::write::DeflateEncoder::new(Vec::new(), flate2::Compression::default());
+            encoder.write_all(b"hello world").unwrap();
+            encoder.finish().unwrap()
+        };
+        for (name, method, data, len) in [
+            ("index.html", 8u16, &deflated[..], 11u32),
+            ("docs/", 0, &b""[..], 0),
+            ("docs/a.txt", 0, &b"aaa"[..], 3),
+        ] {
+            let offset = zip.len() as u32;
+            zip.extend_from_slice(&[b'P', b'K', 3, 4, 20, 0, 0, 0]);
+            zip.extend_from_slice(&method.to_le_bytes());
+            zip.extend_from_slice(&[0; 8]);
+            zip.extend_from_slice(&(data.len() as u32).to_le_bytes());
+            zip.extend_from_slice(&len.to_le_bytes());
+            zip.extend_from_slice(&(name.len() as u16).to_le_bytes());
+            zip.extend_from_slice(&[0; 2]);
+            zip.extend_from_slice(name.as_bytes());
+            zip.extend_from_slice(data);
+
+            cd.extend_from_slice(&[b'P', b'K', 1, 2, 20, 3, 20, 0, 0, 0]);
+            cd.extend_from_slice(&method.to_le_bytes());
+            cd.extend_from_slice(&[0; 8]);
+            cd.extend_from_slice(&(data.len() as u32).to_le_bytes());
+            cd.extend_from_slice(&len.to_le_bytes());
+            cd.extend_from_slice(&(name.len() as u16).to_le_bytes());
+            cd.extend_from_slice(&[0; 8]);
+            cd.extend_from_slice(&(0o100644u32 << 16).to_le_bytes());
+            cd.extend_from_slice(&offset.to_le_bytes());
+            cd.extend_from_slice(name.as_bytes());
+        }
+        let cd_offset = zip.len() as u32;
+        zip.extend_from_slice(&cd);
+        zip.extend_from_slice(&[b'P', b'K', 5, 6, 0, 0, 0, 0, 3, 0, 3, 0]);
+        zip.extend_from_slice(&(cd.len() as u32).to_le_bytes());
+        zip.extend_from_slice(&cd_offset.to_le_bytes());
+        zip.extend_from_slice(&[0; 2]);
+        file.write_all(&zip).unwrap();
+
+        let bundle = Arc::new(Bundle::open(file.path()).unwrap());
+        assert_eq!(
+            list(&bundle, ""),
+            &[
+                ("docs".to_string(), true),
+                ("index.html".to_string(), false)
+            ]
+        );
+        assert_eq!(list(&bundle, "docs"), &[("a.txt".to_string(), false)]);
+        assert_eq!(read(&bundle, "index.html", 0), "hello world");
+        assert_eq!(read(&bundle, "index.html", 6), "world");
+        assert_eq!(read(&bundle, "docs/a.txt", 1), "aa");
+    }
+
+    #[test]
+    fn test_zip_modified() {
+        use zip::write::FileOptions;
+        use zip::DateTime;
+
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        let mut zip = zip::ZipWriter::new(io::Cursor::new(Vec::new()));
+        let options = FileOptions::default()
+            .compression_method(zip::CompressionMethod::Stored)
+            .last_modified_time(DateTime::from_date_and_time(2021, 3, 4, 5, 6, 8).unwrap());
+        zip.add_directory("docs/", options).unwrap();
+        zip.start_file("docs/a.txt", options).unwrap();
+        zip.write_all(b"aaa").unwrap();
+        file.write_all(&zip.finish().unwrap().into_inner()).unwrap();
+
+        let bundle = Arc::new(Bundle::open(file.path()).unwrap());
+        // 2021-03-04T05:06:08Z
+        let expected = Some(UNIX_EPOCH + Duration::from_secs(1614834368));
+        for path in ["docs", "docs/a.txt"] {
+            let handle = bundle.lookup(&normalize(Path::new(path)).unwrap()).unwrap();
+            assert_eq!(handle.metadata().modified(), expected, "{}", path);
+        }
+    }
+
+    #[test]
+    fn test_dos_time() {
+        assert_eq!(dos_time(0, 0), None);
+        // 1980-01-01T00:00:00Z
+        assert_eq!(
+            dos_time(0x21, 0),
+            Some(UNIX_EPOCH + Duration::from_secs(315532800))
+        );
+        // 2107-12-31T23:59:58Z, the latest time that can be stored
+        assert_eq!(
+            dos_time(0xff9f, 0xbf7d),
+            Some(UNIX_EPOCH + Duration::from_secs(4354819198))
+        );
+        assert_eq!(dos_time(0x21, 24 << 11), None);
+    }
+
+    #[test]
+    fn test_zip_extended_mtime() {
+        let mut extra = vec![0x0a, 0x00, 0x04, 0x00, 1, 2, 3, 4];
+        extra.extend_from_slice(&[0x55, 0x54, 5, 0, 1]);
+        extra.extend_from_slice(&1614834368i32.to_le_bytes());
+        assert_eq!(
+            zip_extended_mtime(&extra),
+            Some(UNIX_EPOCH + Duration::from_secs(1614834368))
+        );
+        assert_eq!(zip_extended_mtime(&extra[..8]), None);
+        assert_eq!(zip_extended_mtime(&extra[..10]), None);
+    }
+
+    #[test]
+    fn test_signed_bundle_is_read_once() {
+        const PUBLIC_KEY: &str = "RWQBAgMEBQYHCF3jto6MqCPxz4YYAZMGLeXjurVW3pOBjvSVQ4faZG9r";
+        const SIGNATURE: &str = "untrusted comment: signature from minisign secret key
+RUQBAgMEBQYHCG76gX/a1RWLUDsio8aud5S71poyGrbw1wCv8P1jYzSHZO1iTTO2HtPkHUXwmi4SnswXJPOQe+48GThELd7wBQM=
+trusted comment: timestamp:1700000000\tfile:site.tar
+QmpEMc7fKxnlms1AROlGNUC+R5dQ8MABVZGIetdVJpQw8zPhufIt9dodfEXnPQb2TAiwwble8FOptYR0DfV1Cw==
+";
+        let mut builder = tar::Builder::new(Vec::new());
+        let mut header = tar::Header::new_gnu();
+        header.set_path("index.html").unwrap();
+        header.set_size(14);
+        header.set_mode(0o644);
+        header.set_mtime(0);
+        header.set_cksum();
+        builder.append(&header, &b"signed content"[..]).unwrap();
+        let data = builder.into_inner().unwrap();
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("site.tar");
+        fs::write(&path, &data).unwrap();
+        fs::write(crate::signing::signature_path(&path), SIGNATURE).unwrap();
+        let key_file = dir.path().join("minisign.pub");
+        fs::write(&key_file, PUBLIC_KEY).unwrap();
+        let key = SigningKey::load(&key_file).unwrap();
+
+        let bundle = Arc::new(Bundle::open_signed(&path, &key).unwrap());
+        // modify the bundle in place after it was verified
+        let tampered = String::from_utf8(data)
+            .unwrap()
+            .replace("signed content", "evil content!!");
+        fs::write(&path, tampered).unwrap();
+        assert_eq!(read(&bundle, "index.html", 0), "signed content");
+        assert!(Bundle::open_signed(&path, &key).is_err());
+    }
+
+    #[test]
+    fn test_static_bundle() {
+        let bytes = std::fs::read(tar_bundle().path()).unwrap();
+        let mut bundle = Bundle::new(Source::Static(Box::leak(bytes.into_boxed_slice())));
+        bundle.index_tar().unwrap();
+        let bundle = Arc::new(bundle);
+        assert_eq!(read(&bundle, "index.html", 6), "world");
+        assert_eq!(read(&bundle, "docs/a.txt", 0), "replaced");
+    }
+
+    #[test]
+    fn test_reject_compressed_tar() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(&[0x1f, 0x8b, 8, 0, 0, 0, 0, 0]).unwrap();
+        assert!(Bundle::open(file.path()).is_err());
+    }
+}
@@ -0,0 +1,361 @@
+use crate::errors::*;
+use actix_web::http::header::HeaderValue;
+use actix_web::web::Bytes;
+use lru::LruCache;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+use nix::sys::inotify::{AddWatchFlags, Inotify, WatchDescriptor};
+#[cfg(any(target_os = "linux", target_os = "android"))]
+use std::collections::HashMap;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// A small file that was read into memory, together with the content type it was served with
+#[derive(Debug)]
+pub struct Entry {
+    pub content_type: HeaderValue,
+    pub body: Bytes,
+}
+
+#[derive(Debug)]
+struct Inner {
+    entries: LruCache<PathBuf, Arc<Entry>>,
+    size: u64,
+    /// Incremented for every change that was detected, entries that were read before the change
+    /// are not inserted
+    generation: u64,
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    watches: HashMap<PathBuf, WatchDescriptor>,
+    /// Set if the watcher failed, nothing is cached anymore
+    broken: bool,
+}
+
+impl Inner {
+    fn invalidate(&mut self) {
+        self.entries.clear();
+        self.size = 0;
+        self.generation += 1;
+    }
+}
+
+/// Size-bounded LRU cache for the contents of small files, keyed by the path that was requested.
+/// The cache is cleared on reload, on linux it's also cleared by inotify whenever something
+/// changes in a directory that a cached file was read from.
+#[derive(Debug)]
+pub struct Cache {
+    max_size: u64,
+    max_file_size: u64,
+    inner: Mutex<Inner>,
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    inotify: Arc<InotifyFd>,
+}
+
+/// The inotify instance is shared by the cache and the thread reading its events, it's closed once
+/// both of them are gone
+#[cfg(any(target_os = "linux", target_os = "android"))]
+#[derive(Debug)]
+struct InotifyFd(Inotify);
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+impl Drop for InotifyFd {
+    fn drop(&mut self) {
+        if let Err(err) = nix::unistd::close(self.0.as_raw_fd()) {
+            warn!("Failed to close inotify: {:#}", err);
+        }
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn watch_flags() -> AddWatchFlags {
+    AddWatchFlags::IN_MODIFY
+        | AddWatchFlags::IN_ATTRIB
+        | AddWatchFlags::IN_CLOSE_WRITE
+        | AddWatchFlags::IN_CREATE
+        | AddWatchFlags::IN_DELETE
+        | AddWatchFlags::IN_MOVED_FROM
+        | AddWatchFlags::IN_MOVED_TO
+        | AddWatchFlags::IN_DELETE_SELF
+        | AddWatchFlags::IN_MOVE_SELF
+}
+
+impl Cache {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub fn new(max_size: u64, max_file_size: u64) -> Result<Arc<Cache>> {
+        use nix::sys::inotify::InitFlags;
+
+        let inotify = Inotify::init(InitFlags::IN_NONBLOCK | InitFlags::IN_CLOEXEC)
+            .context("Failed to setup inotify")?;
+        let inotify = Arc::new(InotifyFd(inotify));
+        let cache = Arc::new(Cache::with_inotify(
+            max_size,
+            max_file_size,
+            inotify.clone(),
+        ));
+
+        let weak = Arc::downgrade(&cache);
+        std::thread::spawn(move || watch(inotify, weak));
+        Ok(cache)
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    pub fn new(max_size: u64, max_file_size: u64) -> Result<Arc<Cache>> {
+        Ok(Arc::new(Cache::with_inotify(max_size, max_file_size)))
+    }
+
+    fn with_inotify(
+        max_size: u64,
+        max_file_size: u64,
+        #[cfg(any(target_os = "linux", target_os = "android"))] inotify: Arc<InotifyFd>,
+    ) -> Cache {
+        Cache {
+            max_size,
+            max_file_size,
+            inner: Mutex::new(Inner {
+                entries: LruCache::unbounded(),
+                size: 0,
+                generation: 0,
+                #[cfg(any(target_os = "linux", target_os = "android"))]
+                watches: HashMap::new(),
+                broken: false,
+            }),
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            inotify,
+        }
+    }
+
+    pub fn max_file_size(&self) -> u64 {
+        self.max_file_size
+    }
+
+    /// Returns true if the cache was created with these limits
+    pub fn has_limits(&self, max_size: u64, max_file_size: u64) -> bool {
+        self.max_size == max_size && self.max_file_size == max_file_size
+    }
+
+    /// Drop all entries and stop watching directories, this is used on reload so nothing from the
+    /// previous config is served
+    pub fn clear(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        for (dir, wd) in inner.watches.drain() {
+            if let Err(err) = self.inotify.0.rm_watch(wd) {
+                debug!("Failed to stop watching {:?}: {:#}", dir, err);
+            }
+        }
+        inner.invalidate();
+    }
+
+    pub fn get(&self, path: &Path) -> Option<Arc<Entry>> {
+        self.inner.lock().unwrap().entries.get(path).cloned()
+    }
+
+    /// Needs to be read before the file is opened, and passed to `insert` afterwards
+    pub fn generation(&self) -> u64 {
+        self.inner.lock().unwrap().generation
+    }
+
+    /// Make sure changes to the directories of this file are noticed, returns false if the file
+    /// shouldn't be cached. Adding a watch counts as a change, since the file could have been
+    /// modified after it was opened but before the watch was in place.
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub fn watch(&self, root: &Path, file: &Path) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.broken {
+            return false;
+        }
+
+        let dir = match file.parent() {
+            Some(dir) if dir.starts_with(root) => dir,
+            _ => return false,
+        };
+
+        let mut watched = true;
+        for dir in dir.ancestors() {
+            if !inner.watches.contains_key(dir) {
+                match self.inotify.0.add_watch(dir, watch_flags()) {
+                    Ok(wd) => {
+                        debug!("Watching {:?} for changes", dir);
+                        inner.watches.insert(dir.to_path_buf(), wd);
+                        inner.generation += 1;
+                    }
+                    Err(err) => {
+                        debug!("Failed to watch {:?}, not caching: {:#}", dir, err);
+                        return false;
+                    }
+                }
+                watched = false;
+            }
+            if dir == root {
+                break;
+            }
+        }
+        watched
+    }
+
+    /// There's nothing to watch, the cache is only dropped on reload
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    pub fn watch(&self, _root: &Path, _file: &Path) -> bool {
+        true
+    }
+
+    /// Insert a file unless a change was detected since `generation` was read
+    pub fn insert(&self, path: PathBuf, generation: u64, entry: Entry) {
+        let len = entry.body.len() as u64;
+        if len > self.max_file_size || len > self.max_size {
+            return;
+        }
+
+        let mut inner = self.inner.lock().unwrap();
+        if inner.generation != generation || inner.broken {
+            return;
+        }
+        if let Some(old) = inner.entries.put(path, Arc::new(entry)) {
+            inner.size -= old.body.len() as u64;
+        }
+        inner.size += len;
+
+        while inner.size > self.max_size {
+            match inner.entries.pop_lru() {
+                Some((_, old)) => inner.size -= old.body.len() as u64,
+                None => break,
+            }
+        }
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    fn handle_events(&self, events: &[nix::sys::inotify::InotifyEvent]) {
+        let mut inner = self.inner.lock().unwrap();
+        for event in events {
+            // the directory is gone, it needs to be watched again if it's recreated
+            if event.mask.contains(AddWatchFlags::IN_IGNORED) {
+                inner.watches.retain(|_, wd| *wd != event.wd);
+            }
+        }
+        if !inner.entries.is_empty() {
+            debug!("Detected changes in the web root, clearing cache");
+        }
+        inner.invalidate();
+    }
+}
+
+/// Clear the cache whenever inotify reports something, stops once the cache was dropped
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn watch(inotify: Arc<InotifyFd>, cache: std::sync::Weak<Cache>) {
+    use nix::errno::Errno;
+    use nix::poll::{poll, PollFd, PollFlags};
+
+    loop {
+        let mut fds = [PollFd::new(inotify.0.as_raw_fd(), PollFlags::POLLIN)];
+        if let Err(err) = poll(&mut fds, 1000) {
+            if err != Errno::EINTR {
+                warn!("Failed to poll inotify: {:#}", err);
+            }
+        }
+
+        let cache = match cache.upgrade() {
+            Some(cache) => cache,
+            None => break,
+        };
+        match inotify.0.read_events() {
+            Ok(events) => cache.handle_events(&events),
+            Err(Errno::EAGAIN) | Err(Errno::EINTR) => (),
+            Err(err) => {
+                error!("Failed to read inotify events, disabling cache: {:#}", err);
+                let mut inner = cache.inner.lock().unwrap();
+                inner.broken = true;
+                inner.invalidate();
+                break;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(body: &'static str) -> Entry {
+        Entry {
+            content_type: HeaderValue::from_static("text/plain; charset=utf-8"),
+            body: Bytes::from_static(body.as_bytes()),
+        }
+    }
+
+    #[test]
+    fn test_evict_least_recently_used() {
+        let cache = Cache::new(10, 10).unwrap();
+        cache.insert(PathBuf::from("/a"), 0, entry("aaaa"));
+        cache.insert(PathBuf::from("/b"), 0, entry("bbbb"));
+        assert!(cache.get(Path::new("/a")).is_some());
+        cache.insert(PathBuf::from("/c"), 0, entry("cccc"));
+
+        assert!(cache.get(Path::new("/a")).is_some());
+        assert!(cache.get(Path::new("/b")).is_none());
+        assert!(cache.get(Path::new("/c")).is_some());
+    }
+
+    #[test]
+    fn test_skip_large_files() {
+        let cache = Cache::new(100, 4).unwrap();
+        cache.insert(PathBuf::from("/a"), 0, entry("aaaaa"));
+        assert!(cache.get(Path::new("/a")).is_none());
+    }
+
+    #[test]
+    fn test_skip_outdated_generation() {
+        let cache = Cache::new(100, 100).unwrap();
+        cache.inner.lock().unwrap().invalidate();
+        cache.insert(PathBuf::from("/a"), 0, entry("aaaa"));
+        assert!(cache.get(Path::new("/a")).is_none());
+        cache.insert(PathBuf::from("/a"), cache.generation(), entry("aaaa"));
+        assert!(cache.get(Path::new("/a")).is_some());
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    #[test]
+    fn test_invalidate_on_change() {
+        use std::fs;
+        use std::time::{Duration, Instant};
+
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        fs::create_dir(root.join("docs")).unwrap();
+        let file = root.join("docs/a.txt");
+        fs::write(&file, "a").unwrap();
+
+        let cache = Cache::new(100, 100).unwrap();
+        assert!(!cache.watch(root, &file));
+        assert!(cache.watch(root, &file));
+        cache.insert(file.clone(), cache.generation(), entry("a"));
+        assert!(cache.get(&file).is_some());
+
+        fs::write(&file, "b").unwrap();
+        let start = Instant::now();
+        while cache.get(&file).is_some() {
+            assert!(start.elapsed() < Duration::from_secs(5));
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    #[test]
+    fn test_clear_removes_watches() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("a.txt");
+        std::fs::write(&file, "a").unwrap();
+
+        let cache = Cache::new(100, 100).unwrap();
+        assert!(!cache.watch(dir.path(), &file));
+        cache.insert(file.clone(), cache.generation(), entry("a"));
+        assert!(cache.get(&file).is_some());
+
+        let generation = cache.generation();
+        cache.clear();
+        assert!(cache.get(&file).is_none());
+        assert!(cache.inner.lock().unwrap().watches.is_empty());
+        assert_ne!(cache.generation(), generation);
+        // the directory needs to be watched again
+        assert!(!cache.watch(dir.path(), &file));
+    }
+}
@@ -0,0 +1,365 @@
+use crate::args::{Args, DeployArgs};
+use crate::errors::*;
+use crate::server;
+use crate::utils;
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// How often we try to find a free release name before giving up
+const MAX_RENAMES: usize = 100;
+
+/// The directory the releases of a web root are kept in, `/srv/www` uses `/srv/www.releases`
+pub fn releases_dir(web_root: &Path) -> Option<PathBuf> {
+    let name = web_root.file_name()?;
+    let mut releases = name.to_os_string();
+    releases.push(".releases");
+    Some(web_root.with_file_name(releases))
+}
+
+/// If the web root is a symlink to a release, resolve it once so every request is served from a
+/// single release, even if the symlink is swapped while the request is processed
+pub fn pin_release(web_root: String) -> Result<String> {
+    let is_symlink = fs::symlink_metadata(&web_root)
+        .map(|md| md.file_type().is_symlink())
+        .unwrap_or(false);
+    if !is_symlink {
+        return Ok(web_root);
+    }
+
+    let release = fs::canonicalize(&web_root)
+        .with_context(|| anyhow!("Failed to resolve web root: {:?}", web_root))?;
+    info!("Serving release {:?}", release);
+    utils::path_to_string(release)
+}
+
+/// All finished releases, oldest first
+fn list_releases(releases: &Path) -> Result<Vec<OsString>> {
+    let mut names = Vec::new();
+    for entry in fs::read_dir(releases)
+        .with_context(|| anyhow!("Failed to list releases: {:?}", releases))?
+    {
+        let name = entry?.file_name();
+        // releases that are still being copied are hidden
+        if !name.to_string_lossy().starts_with('.') {
+            names.push(name);
+        }
+    }
+    names.sort();
+    Ok(names)
+}
+
+/// Create an empty staging directory for a new release, returns the final name of the release
+fn create_staging(releases: &Path) -> Result<(String, PathBuf)> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    for i in 0..MAX_RENAMES {
+        let name = match i {
+            0 => format!("{:010}", now),
+            _ => format!("{:010}.{}", now, i),
+        };
+        if releases.join(&name).exists() {
+            continue;
+        }
+        let staging = releases.join(format!(".{}.partial", name));
+        match fs::create_dir(&staging) {
+            Ok(()) => return Ok((name, staging)),
+            Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => continue,
+            Err(err) => {
+                return Err(err)
+                    .with_context(|| anyhow!("Failed to create directory: {:?}", staging))
+            }
+        }
+    }
+    bail!("Failed to find a free release name")
+}
+
+/// Copy a directory recursively, symlinks are copied as symlinks
+fn copy_tree(src: &Path, dst: &Path) -> Result<()> {
+    for entry in
+        fs::read_dir(src).with_context(|| anyhow!("Failed to read directory: {:?}", src))?
+    {
+        let entry = entry?;
+        let src = entry.path();
+        let dst = dst.join(entry.file_name());
+        let file_type = entry.file_type()?;
+
+        if file_type.is_dir() {
+            fs::create_dir(&dst)
+                .with_context(|| anyhow!("Failed to create directory: {:?}", dst))?;
+            copy_tree(&src, &dst)?;
+        } else if file_type.is_file() {
+            fs::copy(&src, &dst).with_context(|| anyhow!("Failed to copy file: {:?}", src))?;
+        } else if file_type.is_symlink() {
+            copy_symlink(&src, &dst)?;
+        } else {
+            warn!(
+                "Skipping file that is neither a file, directory or symlink: {:?}",
+                src
+            );
+        }
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn copy_symlink(src: &Path, dst: &Path) -> Result<()> {
+    let target =
+        fs::read_link(src).with_context(|| anyhow!("Failed to read symlink: {:?}", src))?;
+    std::os::unix::fs::symlink(target, dst)
+        .with_context(|| anyhow!("Failed to create symlink: {:?}", dst))?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn copy_symlink(src: &Path, _dst: &Path) -> Result<()> {
+    bail!("Copying symlinks is only supported on unix: {:?}", src)
+}
+
+/// Point the web root to a release, the symlink is created next to it and renamed over the web
+/// root, so there's never a moment where the web root doesn't exist
+#[cfg(unix)]
+fn activate(web_root: &Path, target: &Path) -> Result<()> {
+    let tmp = web_root.with_file_name(format!(".narnia-deploy-{}", std::process::id()));
+    if fs::remove_file(&tmp).is_ok() {
+        debug!("Removed stale symlink: {:?}", tmp);
+    }
+    std::os::unix::fs::symlink(target, &tmp)
+        .with_context(|| anyhow!("Failed to create symlink: {:?}", tmp))?;
+    fs::rename(&tmp, web_root)
+        .with_context(|| anyhow!("Failed to replace web root: {:?}", web_root))?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn activate(_web_root: &Path, _target: &Path) -> Result<()> {
+    bail!("Deploying is only supported on unix")
+}
+
+/// The name of the release the web root points to
+fn active_release(web_root: &Path) -> Option<OsString> {
+    let target = fs::read_link(web_root).ok()?;
+    target.file_name().map(OsStr::to_os_string)
+}
+
+/// Delete the oldest releases, the active release and `keep` releases before it are kept. The
+/// previous release is never deleted, the server only switches to the new release once it
+/// processed the reload and keeps serving the previous one if the reload fails
+fn prune(release: &Path, previous: Option<&OsStr>, keep: usize) -> Result<()> {
+    let (releases, active) = match (release.parent(), release.file_name()) {
+        (Some(releases), Some(active)) => (releases, active),
+        _ => bail!("Invalid release path: {:?}", release),
+    };
+    let names = list_releases(releases)?;
+    let remove = names.len().saturating_sub(keep + 1);
+    for name in &names[..remove] {
+        if name == active || Some(name.as_os_str()) == previous {
+            continue;
+        }
+        let path = releases.join(name);
+        info!("Removing old release {:?}", path);
+        fs::remove_dir_all(&path).with_context(|| anyhow!("Failed to remove {:?}", path))?;
+    }
+    Ok(())
+}
+
+/// Copy a directory into a new release and activate it
+pub fn deploy_dir(web_root: &Path, src: &Path) -> Result<PathBuf> {
+    let md = fs::metadata(src).with_context(|| anyhow!("Failed to open directory: {:?}", src))?;
+    if !md.is_dir() {
+        bail!("Only directories can be deployed: {:?}", src);
+    }
+    match fs::symlink_metadata(web_root) {
+        Ok(md) if !md.file_type().is_symlink() => bail!(
+            "Web root needs to be a symlink to a release, move {:?} out of the way to start using releases",
+            web_root
+        ),
+        _ => (),
+    }
+
+    let releases = releases_dir(web_root).context("Web root has no file name")?;
+    fs::create_dir_all(&releases)
+        .with_context(|| anyhow!("Failed to create releases directory: {:?}", releases))?;
+
+    let (name, staging) = create_staging(&releases)?;
+    info!("Copying {:?} into new release {:?}", src, name);
+    if let Err(err) = copy_tree(src, &staging) {
+        // never leave incomplete releases behind
+        if let Err(err) = fs::remove_dir_all(&staging) {
+            warn!(
+                "Failed to remove incomplete release({:?}): {:#}",
+                staging, err
+            );
+        }
+        return Err(err);
+    }
+    let release = releases.join(&name);
+    fs::rename(&staging, &release)
+        .with_context(|| anyhow!("Failed to rename release: {:?}", release))?;
+
+    // the symlink is relative, so it keeps working in a chroot
+    let target = Path::new(releases.file_name().unwrap()).join(&name);
+    activate(web_root, &target)?;
+    info!("Activated release {:?}", release);
+    Ok(release)
+}
+
+pub fn run(args: &Args, deploy: &DeployArgs) -> Result<()> {
+    let web_root = args
+        .web_root
+        .as_ref()
+        .context("Missing --web-root argument")?;
+    // with a chroot the web root is relative to it
+    #[cfg(unix)]
+    let web_root = if let Some(chroot) = &args.chroot {
+        chroot.join(web_root.trim_start_matches('/'))
+    } else {
+        PathBuf::from(web_root)
+    };
+    #[cfg(not(unix))]
+    let web_root = PathBuf::from(web_root);
+
+    let previous = active_release(&web_root);
+    let release = deploy_dir(&web_root, &deploy.dir)?;
+    println!("{}", release.display());
+
+    match &args.pid_file {
+        Some(path) if path.exists() => server::send_reload(args)?,
+        Some(path) => info!("Pid file doesn't exist, no server to reload: {:?}", path),
+        None => warn!(
+            "No --pid-file configured, the running server needs to be reloaded to serve the new release"
+        ),
+    }
+
+    // old releases are only removed after the server was told to switch to the new one
+    prune(&release, previous.as_deref(), deploy.keep)?;
+    Ok(())
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    fn release_of(web_root: &Path) -> String {
+        fs::read_link(web_root)
+            .unwrap()
+            .file_name()
+            .unwrap()
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    #[test]
+    fn test_releases_dir() {
+        assert_eq!(
+            releases_dir(Path::new("/srv/www")),
+            Some(PathBuf::from("/srv/www.releases"))
+        );
+        assert_eq!(
+            releases_dir(Path::new("www/")),
+            Some(PathBuf::from("www.releases"))
+        );
+        assert_eq!(releases_dir(Path::new("/")), None);
+    }
+
+    #[test]
+    fn test_deploy() {
+        let dir = tempfile::tempdir().unwrap();
+        let web_root = dir.path().join("www");
+        let src = dir.path().join("src");
+        fs::create_dir_all(src.join("css")).unwrap();
+        fs::write(src.join("index.html"), "v1").unwrap();
+        fs::write(src.join("css/style.css"), "body {}").unwrap();
+        std::os::unix::fs::symlink("index.html", src.join("home.html")).unwrap();
+
+        let previous = active_release(&web_root);
+        prune(
+            &deploy_dir(&web_root, &src).unwrap(),
+            previous.as_deref(),
+            1,
+        )
+        .unwrap();
+        let first = release_of(&web_root);
+        assert_eq!(
+            fs::read_to_string(web_root.join("index.html")).unwrap(),
+            "v1"
+        );
+        assert_eq!(
+            fs::read_to_string(web_root.join("css/style.css")).unwrap(),
+            "body {}"
+        );
+        assert_eq!(
+            fs::read_link(web_root.join("home.html")).unwrap(),
+            Path::new("index.html")
+        );
+
+        fs::write(src.join("index.html"), "v2").unwrap();
+        let previous = active_release(&web_root);
+        prune(
+            &deploy_dir(&web_root, &src).unwrap(),
+            previous.as_deref(),
+            1,
+        )
+        .unwrap();
+        let second = release_of(&web_root);
+        assert_ne!(first, second);
+        assert_eq!(
+            fs::read_to_string(web_root.join("index.html")).unwrap(),
+            "v2"
+        );
+
+        let previous = active_release(&web_root);
+        prune(
+            &deploy_dir(&web_root, &src).unwrap(),
+            previous.as_deref(),
+            1,
+        )
+        .unwrap();
+        let third = release_of(&web_root);
+        let releases = list_releases(&dir.path().join("www.releases")).unwrap();
+        assert_eq!(releases, &[OsString::from(second), OsString::from(third)]);
+    }
+
+    #[test]
+    fn test_prune_keeps_previous_release() {
+        let dir = tempfile::tempdir().unwrap();
+        let web_root = dir.path().join("www");
+        let src = dir.path().join("src");
+        fs::create_dir(&src).unwrap();
+
+        let mut deployed = Vec::new();
+        for _ in 0..3 {
+            let previous = active_release(&web_root);
+            prune(
+                &deploy_dir(&web_root, &src).unwrap(),
+                previous.as_deref(),
+                0,
+            )
+            .unwrap();
+            deployed.push(active_release(&web_root).unwrap());
+        }
+        let releases = list_releases(&dir.path().join("www.releases")).unwrap();
+        assert_eq!(releases, &deployed[1..]);
+    }
+
+    #[test]
+    fn test_deploy_requires_symlink() {
+        let dir = tempfile::tempdir().unwrap();
+        let web_root = dir.path().join("www");
+        fs::create_dir(&web_root).unwrap();
+        assert!(deploy_dir(&web_root, dir.path()).is_err());
+    }
+
+    #[test]
+    fn test_pin_release() {
+        let dir = tempfile::tempdir().unwrap();
+        let web_root = dir.path().join("www");
+        let src = dir.path().join("src");
+        fs::create_dir(&src).unwrap();
+        let release = deploy_dir(&web_root, &src).unwrap();
+
+        let pinned = pin_release(web_root.to_str().unwrap().to_string()).unwrap();
+        assert_eq!(Path::new(&pinned), fs::canonicalize(release).unwrap());
+        assert_eq!(pin_release(pinned.clone()).unwrap(), pinned);
+    }
+}
@@ -1,25 +1,199 @@
+use crate::access_log::{AccessLog, LogAccess};
+use crate::acme::{self, Acme};
+use crate::archive::{self, ArchiveQuery};
 use crate::args::Args;
+use crate::auth::{Auth, RequireAuth};
+use crate::bundle::{self, Bundle};
+use crate::cache::{self, Cache};
+use crate::deploy;
 use crate::errors::*;
+use crate::listing::{self, Listing, ListingQuery};
+use crate::metrics::RecordMetrics;
+use crate::resolve::{Node, Resolver};
+use crate::rules::{
+    self,
+    headers::HeaderRules,
+    redirects::{self, RedirectRules},
+};
 use crate::server::Bind;
+use crate::signing::SigningKey;
+use crate::tls::{CertResolver, RedirectToHttps};
+use crate::upload::{self, Upload, UploadError};
 use crate::utils;
-use actix_files::NamedFile;
+use crate::webdav::WebDav;
+use actix_files::{HttpRange, NamedFile};
+use actix_multipart::Multipart;
 use actix_web::{
-    get, http::header, middleware, web, App, HttpRequest, HttpResponse, HttpServer, Responder,
+    get,
+    http::{
+        header::{self, HeaderValue},
+        StatusCode,
+    },
+    middleware, web, App, HttpRequest, HttpResponse, HttpServer, Responder,
 };
-use std::borrow::Cow;
-use std::fs;
+use std::fs::{self, File};
+use std::io::{self, Read};
 use std::path::Component;
 use std::path::Path;
 use std::path::PathBuf;
-
-const DIR_LIST_PADDING: usize = 50;
+use std::sync::mpsc;
+use std::sync::{Arc, RwLock};
+use std::thread;
 
 pub struct Config {
     web_root: String,
     list_directories: bool,
+    index_files: Vec<String>,
+    clean_urls: bool,
+    headers: HeaderRules,
+    redirects: RedirectRules,
+    spa_fallback: Option<PathBuf>,
+    resolver: Resolver,
+    listing_template: String,
+    archive_max_size: u64,
+    cache: Option<Arc<Cache>>,
+}
+
+impl Config {
+    /// Without a web root the files that were embedded at build time are served
+    pub fn load(args: &Args, web_root: Option<String>) -> Result<Config> {
+        Config::load_with_cache(args, web_root, None)
+    }
+
+    /// The cache of the previous config is cleared and used again if the limits didn't change, so
+    /// every reload doesn't start another inotify instance
+    fn load_with_cache(
+        args: &Args,
+        web_root: Option<String>,
+        previous: Option<&Arc<Cache>>,
+    ) -> Result<Config> {
+        // the key is loaded again on reload, so it can be rotated
+        let key = if let Some(path) = &args.bundle_key {
+            Some(SigningKey::load(path)?)
+        } else {
+            None
+        };
+        let (web_root, bundle) = if let Some(web_root) = web_root {
+            let web_root = deploy::pin_release(web_root)?;
+            let is_file = fs::metadata(&web_root)
+                .map(|md| md.is_file())
+                .unwrap_or(false);
+            // a tar or zip file is served as if it was extracted into the web root
+            let bundle = match (is_file, &key) {
+                (true, Some(key)) => Some(Bundle::open_signed(Path::new(&web_root), key)?),
+                (true, None) => Some(Bundle::open(Path::new(&web_root))?),
+                (false, Some(_)) => bail!(
+                    "With --bundle-key the web root needs to be a signed bundle: {:?}",
+                    web_root
+                ),
+                (false, None) => None,
+            };
+            (web_root, bundle)
+        } else if key.is_some() {
+            bail!("With --bundle-key the web root needs to be a signed bundle, embedded files can't be verified")
+        } else {
+            (String::from("/"), Some(embedded_bundle()?))
+        };
+        let mut resolver = Resolver::new(args, PathBuf::from(&web_root));
+        if let Some(bundle) = bundle {
+            resolver = resolver.with_bundle(bundle);
+        }
+        let headers = HeaderRules::load(&resolver).context("Failed to load header rules")?;
+        let redirects = RedirectRules::load(&resolver).context("Failed to load redirect rules")?;
+        for name in &args.index_files {
+            let mut components = Path::new(name).components();
+            if !matches!(
+                (components.next(), components.next()),
+                (Some(Component::Normal(_)), None)
+            ) {
+                bail!("Index file needs to be a plain file name: {:?}", name);
+            }
+        }
+        let spa_fallback = if let Some(fallback) = &args.spa_fallback {
+            let fallback = resolve_path_req(&web_root, Path::new(fallback.trim_start_matches('/')))
+                .context("Invalid spa fallback path")?;
+            Some(fallback)
+        } else {
+            None
+        };
+        let listing_template = if let Some(path) = &args.listing_template {
+            listing::load_template(path)?
+        } else {
+            listing::DEFAULT_TEMPLATE.to_string()
+        };
+        let cache = match previous {
+            Some(cache) if cache.has_limits(args.cache_size, args.cache_max_file_size) => {
+                // nothing from the previous config may be served
+                cache.clear();
+                Some(cache.clone())
+            }
+            _ if args.cache_size > 0 => {
+                Some(Cache::new(args.cache_size, args.cache_max_file_size)?)
+            }
+            _ => None,
+        };
+        Ok(Config {
+            web_root,
+            list_directories: args.list_directories,
+            index_files: args.index_files.clone(),
+            clean_urls: args.clean_urls,
+            headers,
+            redirects,
+            spa_fallback,
+            resolver,
+            listing_template,
+            archive_max_size: args.archive_max_size,
+            cache,
+        })
+    }
+}
+
+/// The current config, it's replaced atomically on reload while requests that are already in
+/// progress keep using the config they started with
+pub struct LiveConfig {
+    config: RwLock<Arc<Config>>,
+}
+
+impl LiveConfig {
+    pub fn new(config: Config) -> LiveConfig {
+        LiveConfig {
+            config: RwLock::new(Arc::new(config)),
+        }
+    }
+
+    pub fn get(&self) -> Arc<Config> {
+        self.config.read().unwrap().clone()
+    }
+
+    /// Load the config again, the previous config is kept if this fails
+    pub fn reload(&self, args: &Args) -> Result<()> {
+        let previous = self.get();
+        let config = Config::load_with_cache(args, web_root(args)?, previous.cache.as_ref())?;
+        *self.config.write().unwrap() = Arc::new(config);
+        Ok(())
+    }
+}
+
+/// The configured web root, `None` if the embedded files should be served instead
+pub fn web_root(args: &Args) -> Result<Option<String>> {
+    match &args.web_root {
+        Some(web_root) => Ok(Some(web_root.clone())),
+        None if cfg!(feature = "embed") => Ok(None),
+        None => bail!("Missing --web-root argument"),
+    }
 }
 
-fn resolve_path_req(base: &str, req: &Path) -> Result<PathBuf> {
+#[cfg(feature = "embed")]
+fn embedded_bundle() -> Result<Bundle> {
+    Bundle::embedded()
+}
+
+#[cfg(not(feature = "embed"))]
+fn embedded_bundle() -> Result<Bundle> {
+    bail!("Missing --web-root argument, narnia was built without embedded files")
+}
+
+pub fn resolve_path_req(base: &str, req: &Path) -> Result<PathBuf> {
     let mut path = PathBuf::from(base);
     for comp in req.components() {
         match comp {
@@ -47,90 +221,357 @@ fn forbidden() -> HttpResponse {
         .body("403 - forbidden\n")
 }
 
-fn not_found() -> HttpResponse {
+pub(crate) fn not_found() -> HttpResponse {
     HttpResponse::NotFound()
         .content_type("text/plain; charset=utf-8")
         .body("404 - not found\n")
 }
 
-enum ResolvedPath<'a> {
-    File(Cow<'a, Path>),
-    ListDir(&'a Path),
+/// Headers that are added to every response
+pub(crate) fn default_headers() -> middleware::DefaultHeaders {
+    middleware::DefaultHeaders::new()
+        .add((header::DATE, "Thu, 01 Jan 1970 00:00:00 GMT"))
+        .add((header::X_CONTENT_TYPE_OPTIONS, "nosniff"))
+        .add((header::REFERRER_POLICY, "no-referrer"))
+}
+
+enum ResolvedPath {
+    File(Node, PathBuf),
+    ListDir(Node),
     Forbidden,
     NotFound,
 }
 
-fn resolve_path_fs(path: &Path, list_directories: bool) -> ResolvedPath {
-    if path.exists() {
-        if path.is_dir() {
-            let index_path = path.join("index.html");
-            if index_path.exists() {
-                ResolvedPath::File(Cow::Owned(index_path))
-            } else if list_directories {
-                ResolvedPath::ListDir(path)
-            } else {
-                ResolvedPath::Forbidden
+fn is_file(file: &Node) -> bool {
+    file.metadata().map(|md| md.is_file()).unwrap_or(false)
+}
+
+fn resolve_path_fs(cfg: &Config, path: &Path) -> ResolvedPath {
+    let file = match cfg.resolver.open(path) {
+        Ok(file) => file,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+            if cfg.clean_urls {
+                let mut html_path = path.as_os_str().to_owned();
+                html_path.push(".html");
+                let html_path = PathBuf::from(html_path);
+                if let Ok(file) = cfg.resolver.open(&html_path) {
+                    if is_file(&file) {
+                        return ResolvedPath::File(file, html_path);
+                    }
+                }
+            }
+            return ResolvedPath::NotFound;
+        }
+        Err(err) => {
+            debug!("Failed to open path({:?}): {:#}", path, err);
+            return ResolvedPath::Forbidden;
+        }
+    };
+
+    let is_dir = match file.metadata() {
+        Ok(md) => md.is_dir(),
+        Err(_) => return ResolvedPath::Forbidden,
+    };
+
+    if is_dir {
+        for name in &cfg.index_files {
+            let index_path = path.join(name);
+            if let Ok(file) = cfg.resolver.open(&index_path) {
+                if is_file(&file) {
+                    return ResolvedPath::File(file, index_path);
+                }
             }
+        }
+        if cfg.list_directories {
+            ResolvedPath::ListDir(file)
         } else {
-            ResolvedPath::File(Cow::Borrowed(path))
+            ResolvedPath::Forbidden
         }
     } else {
-        ResolvedPath::NotFound
+        ResolvedPath::File(file, path.to_path_buf())
+    }
+}
+
+fn redirect(req: &HttpRequest, status: StatusCode, mut location: String) -> HttpResponse {
+    let query = req.query_string();
+    if !query.is_empty() && !location.contains('?') {
+        location.push('?');
+        location.push_str(query);
     }
+    HttpResponse::build(status)
+        .append_header((header::LOCATION, location))
+        .finish()
 }
 
-fn list_directory(full_path: &Path, req_path: &str) -> Result<String> {
-    let escaped_req_path = htmlescape::encode_minimal(req_path);
-    let mut buf = format!(
-        r#"<html>
-<head><title>Index of /{}</title></head>
-<body>
-<h1>Index of /{}</h1><hr><pre>
-"#,
-        escaped_req_path, escaped_req_path
-    );
+/// Redirect requests for html files to their clean url, if enabled
+fn clean_url_redirect(cfg: &Config, req: &HttpRequest, req_path: &Path) -> Option<HttpResponse> {
+    if !cfg.clean_urls || utils::os_str_bytes(req_path.as_os_str()).ends_with(b"/") {
+        return None;
+    }
+
+    let file_name = req_path.file_name()?;
+    let dir = req_path.parent().unwrap_or_else(|| Path::new(""));
+    let (target, location) = if cfg
+        .index_files
+        .iter()
+        .any(|name| file_name == name.as_str())
+    {
+        let location = if dir.as_os_str().is_empty() {
+            "/".to_string()
+        } else {
+            format!("/{}/", listing::encode_path(dir))
+        };
+        (dir.to_path_buf(), location)
+    } else if req_path.extension()? == "html" {
+        let target = req_path.with_extension("");
+        let location = format!("/{}", listing::encode_path(&target));
+        (target, location)
+    } else {
+        return None;
+    };
 
-    if !req_path.is_empty() {
-        buf.push_str("<a href=\"../\">../</a>\n");
+    // only redirect if the clean url serves this exact file, otherwise we'd redirect to a 404 or
+    // to a different file
+    let path = resolve_path_req(&cfg.web_root, req_path).ok()?;
+    let target = resolve_path_req(&cfg.web_root, &target).ok()?;
+    match resolve_path_fs(cfg, &target) {
+        ResolvedPath::File(_, resolved) if resolved == path => (),
+        _ => return None,
     }
 
-    let iter = fs::read_dir(&full_path).context("Failed to list directory")?;
+    Some(redirect(req, StatusCode::MOVED_PERMANENTLY, location))
+}
 
-    let mut listing = Vec::new();
-    for entry in iter {
-        let entry = entry.context("Failed to get directory entry")?;
+pub async fn serve_file(req: &HttpRequest, node: Node, path: &Path) -> HttpResponse {
+    match node {
+        Node::Fs(file) => serve_fs_file(req, file, path),
+        Node::Bundle(handle) => serve_bundle_file(req, handle, path).await,
+    }
+}
 
-        let file_name = entry.file_name();
-        // skip filenames with invalid utf8
-        if let Ok(file_name) = file_name.into_string() {
-            let md = entry
-                .metadata()
-                .with_context(|| anyhow!("Failed to stat file: {:?}", entry.path()))?;
-            listing.push((file_name, md.len()));
+fn serve_fs_file(req: &HttpRequest, file: File, path: &Path) -> HttpResponse {
+    let file = match NamedFile::from_file(file, path) {
+        Ok(file) => file,
+        Err(err) => {
+            warn!("Failed to open file({:?}): {:#}", path, err);
+            return forbidden();
         }
+    };
+
+    file.prefer_utf8(true)
+        .disable_content_disposition()
+        .use_etag(false)
+        .use_last_modified(false)
+        .into_response(req)
+}
+
+/// The content type NamedFile would use with `prefer_utf8`
+fn content_type(path: &Path) -> mime::Mime {
+    let mime = path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .map(actix_files::file_extension_to_mime)
+        .unwrap_or(mime::APPLICATION_OCTET_STREAM);
+
+    if mime == mime::APPLICATION_JAVASCRIPT {
+        mime::APPLICATION_JAVASCRIPT_UTF_8
+    } else if mime == mime::TEXT_HTML {
+        mime::TEXT_HTML_UTF_8
+    } else if mime == mime::TEXT_CSS {
+        mime::TEXT_CSS_UTF_8
+    } else if mime == mime::TEXT_PLAIN {
+        mime::TEXT_PLAIN_UTF_8
+    } else if mime == mime::TEXT_CSV {
+        mime::TEXT_CSV_UTF_8
+    } else if mime == mime::TEXT_TAB_SEPARATED_VALUES {
+        mime::TEXT_TAB_SEPARATED_VALUES_UTF_8
+    } else {
+        mime
     }
+}
 
-    listing.sort();
+/// Serve a file from a bundle, with the same headers and range support as NamedFile
+async fn serve_bundle_file(req: &HttpRequest, handle: bundle::Handle, path: &Path) -> HttpResponse {
+    let len = handle.metadata().len();
+    let mut res = HttpResponse::Ok();
+    res.insert_header((header::CONTENT_TYPE, content_type(path).to_string()))
+        .insert_header((header::ACCEPT_RANGES, "bytes"));
 
-    for (file_name, size) in listing {
-        let padding = DIR_LIST_PADDING.saturating_sub(file_name.len());
-        let escaped_name = htmlescape::encode_attribute(&file_name);
-        buf.push_str(&format!(
-            "<a href=\"{}\">{}</a>{} {}\n",
-            escaped_name,
-            escaped_name,
-            " ".repeat(padding),
-            size
-        ));
+    let (offset, length) = if let Some(range) = req.headers().get(header::RANGE) {
+        let range = match range.to_str() {
+            Ok(range) => HttpRange::parse(range, len),
+            Err(_) => return res.status(StatusCode::BAD_REQUEST).finish(),
+        };
+        match range.ok().as_ref().and_then(|ranges| ranges.first()) {
+            Some(range) => {
+                // don't allow compression middleware to modify partial content
+                res.insert_header((header::CONTENT_ENCODING, "identity"))
+                    .insert_header((
+                        header::CONTENT_RANGE,
+                        format!(
+                            "bytes {}-{}/{}",
+                            range.start,
+                            range.start + range.length - 1,
+                            len
+                        ),
+                    ));
+                if range.start != 0 || range.length != len {
+                    res.status(StatusCode::PARTIAL_CONTENT);
+                }
+                (range.start, range.length)
+            }
+            None => {
+                return res
+                    .insert_header((header::CONTENT_RANGE, format!("bytes */{}", len)))
+                    .status(StatusCode::RANGE_NOT_SATISFIABLE)
+                    .finish()
+            }
+        }
+    } else {
+        (0, len)
+    };
+
+    // compressed files are decompressed up to the offset, so this is kept off the worker
+    match web::block(move || handle.reader(offset)).await {
+        Ok(Ok(reader)) => res
+            .no_chunking(length)
+            .streaming(bundle::stream(reader, length)),
+        Ok(Err(err)) => {
+            warn!("Failed to read file from bundle({:?}): {:#}", path, err);
+            forbidden()
+        }
+        Err(err) => {
+            warn!("Failed to read file from bundle({:?}): {:#}", path, err);
+            forbidden()
+        }
     }
+}
 
-    buf.push_str("</pre><hr></body>\n</html>\n");
-    Ok(buf)
+/// Range and conditional requests are always handled by NamedFile
+fn is_cacheable(req: &HttpRequest) -> bool {
+    let headers = req.headers();
+    !headers.contains_key(header::RANGE)
+        && !headers.contains_key(header::IF_MATCH)
+        && !headers.contains_key(header::IF_NONE_MATCH)
 }
 
-#[get("/{tail:.*}")]
-async fn index(cfg: web::Data<Config>, req: HttpRequest) -> impl Responder {
-    let req_path: PathBuf = req.match_info().query("tail").parse().unwrap();
+fn serve_cached(entry: &cache::Entry) -> HttpResponse {
+    HttpResponse::Ok()
+        .insert_header((header::CONTENT_TYPE, entry.content_type.clone()))
+        .insert_header((header::ACCEPT_RANGES, "bytes"))
+        .body(entry.body.clone())
+}
+
+/// Read a small file into memory and add it to the cache, it's served with the same headers as
+/// `serve_fs_file`
+fn serve_and_cache(
+    cfg: &Config,
+    cache: &Cache,
+    generation: u64,
+    req: &HttpRequest,
+    file: File,
+    key: PathBuf,
+    path: &Path,
+) -> HttpResponse {
+    let len = file.metadata().map(|md| md.len()).unwrap_or(u64::MAX);
+    if len > cache.max_file_size() || !cache.watch(Path::new(&cfg.web_root), path) {
+        return serve_fs_file(req, file, path);
+    }
+
+    let mut body = Vec::new();
+    if let Err(err) = (&file)
+        .take(cache.max_file_size() + 1)
+        .read_to_end(&mut body)
+    {
+        warn!("Failed to read file({:?}): {:#}", path, err);
+        return forbidden();
+    }
+    if body.len() as u64 > cache.max_file_size() {
+        return serve_fs_file(req, file, path);
+    }
+
+    let content_type = match HeaderValue::from_str(content_type(path).as_ref()) {
+        Ok(content_type) => content_type,
+        Err(_) => return serve_fs_file(req, file, path),
+    };
+    let entry = cache::Entry {
+        content_type,
+        body: body.into(),
+    };
+    let response = serve_cached(&entry);
+    cache.insert(key, generation, entry);
+    response
+}
+
+async fn serve_archive(
+    cfg: &Config,
+    dir: Node,
+    path: &Path,
+    req_path: &Path,
+    format: archive::Format,
+) -> HttpResponse {
+    let dir_name = req_path
+        .file_name()
+        .map(|name| name.to_os_string())
+        .unwrap_or_else(|| "download".into());
+    let is_web_root = req_path.as_os_str().is_empty();
+
+    // this walks the whole directory tree, so it's kept off the worker
+    let plan = {
+        let resolver = cfg.resolver.clone();
+        let path = path.to_path_buf();
+        let name = PathBuf::from(&dir_name);
+        let max_size = cfg.archive_max_size;
+        web::block(move || {
+            let plan = archive::Plan::collect(&resolver, dir, &path, &name, is_web_root, max_size)?;
+            plan.check(format)?;
+            Ok::<_, Error>(plan)
+        })
+        .await
+    };
+    let plan = match plan {
+        Ok(Ok(plan)) => plan,
+        Ok(Err(err)) => {
+            debug!("Refusing to build archive({:?}): {:#}", path, err);
+            return forbidden();
+        }
+        Err(err) => {
+            warn!("Failed to collect files for archive({:?}): {:#}", path, err);
+            return forbidden();
+        }
+    };
+
+    let file_name = dir_name
+        .to_string_lossy()
+        .chars()
+        .map(|c| {
+            if c.is_ascii_graphic() && c != '"' && c != '\\' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect::<String>();
+    let disposition = header::ContentDisposition {
+        disposition: header::DispositionType::Attachment,
+        parameters: vec![header::DispositionParam::Filename(format!(
+            "{}.{}",
+            file_name,
+            format.extension()
+        ))],
+    };
+
+    HttpResponse::Ok()
+        .content_type(format.content_type())
+        .append_header((header::CONTENT_DISPOSITION, disposition))
+        .streaming(plan.stream(cfg.resolver.clone(), format))
+}
+
+async fn serve(cfg: &Config, req: &HttpRequest, req_path: PathBuf) -> HttpResponse {
+    if rules::is_rules_file(&req_path) {
+        return not_found();
+    }
+
     let path = match resolve_path_req(&cfg.web_root, &req_path) {
         Ok(path) => path,
         Err(err) => {
@@ -139,81 +580,297 @@ async fn index(cfg: web::Data<Config>, req: HttpRequest) -> impl Responder {
         }
     };
 
-    match resolve_path_fs(&path, cfg.list_directories) {
-        ResolvedPath::File(path) => {
-            let file = match NamedFile::open(&path) {
-                Ok(file) => file,
-                Err(err) => {
-                    warn!("Failed to open file({:?}): {:#}", path, err);
-                    return forbidden();
-                }
-            };
-
-            file.prefer_utf8(true)
-                .disable_content_disposition()
-                .use_etag(false)
-                .use_last_modified(false)
-                .into_response(&req)
-        }
-        ResolvedPath::ListDir(path) => {
-            let req_path = match utils::path_to_string(req_path) {
-                Ok(path) => path,
-                Err(_) => return bad_request(),
-            };
+    let cache = cfg.cache.as_deref().filter(|_| is_cacheable(req));
+    // read before the file is opened, so changes in the meantime are noticed
+    let generation = cache.map(|cache| cache.generation());
+    if let Some(entry) = cache.and_then(|cache| cache.get(&path)) {
+        return serve_cached(&entry);
+    }
 
+    match resolve_path_fs(cfg, &path) {
+        // files in bundles aren't cached, they can't change until the next reload anyway
+        ResolvedPath::File(Node::Fs(file), resolved) => match (cache, generation) {
+            (Some(cache), Some(generation)) => {
+                serve_and_cache(cfg, cache, generation, req, file, path, &resolved)
+            }
+            _ => serve_fs_file(req, file, &resolved),
+        },
+        ResolvedPath::File(node, resolved) => serve_file(req, node, &resolved).await,
+        ResolvedPath::ListDir(dir) => {
             // if req_path is not empty but doesn't end with /, redirect
-            if !req_path.is_empty() && !req_path.ends_with('/') {
-                return HttpResponse::Found()
-                    .append_header((header::LOCATION, format!("/{}/", req_path)))
-                    .finish();
+            let bytes = utils::os_str_bytes(req_path.as_os_str());
+            if !bytes.is_empty() && !bytes.ends_with(b"/") {
+                let location = format!("/{}/", listing::encode_path(&req_path));
+                return redirect(req, StatusCode::FOUND, location);
+            }
+
+            if let Some(format) = ArchiveQuery::parse(req.query_string()).download {
+                if cfg.archive_max_size == 0 {
+                    return forbidden();
+                }
+                return serve_archive(cfg, dir, &path, &req_path, format).await;
             }
 
-            let listing = match list_directory(path, &req_path) {
+            let mut listing = match Listing::read(&cfg.resolver, dir, &path, &req_path) {
                 Ok(listing) => listing,
                 Err(err) => {
                     warn!("Failed to list directory({:?}): {:#}", path, err);
                     return forbidden();
                 }
             };
-            HttpResponse::Ok()
-                .append_header((header::CONTENT_TYPE, "text/html; charset=utf-8"))
-                .body(listing)
+            listing.sort(&ListingQuery::parse(req.query_string()));
+
+            let wants_json = req
+                .headers()
+                .get(header::ACCEPT)
+                .and_then(|accept| accept.to_str().ok())
+                .map(|accept| accept.contains("application/json"))
+                .unwrap_or(false);
+
+            if wants_json {
+                HttpResponse::Ok()
+                    .append_header((header::VARY, "Accept"))
+                    .json(listing)
+            } else {
+                HttpResponse::Ok()
+                    .append_header((header::VARY, "Accept"))
+                    .append_header((header::CONTENT_TYPE, "text/html; charset=utf-8"))
+                    .body(listing.to_html(&cfg.listing_template, cfg.archive_max_size > 0))
+            }
         }
         ResolvedPath::Forbidden => forbidden(),
-        ResolvedPath::NotFound => not_found(),
+        ResolvedPath::NotFound => match &cfg.spa_fallback {
+            // paths with a file extension are likely assets and should still 404
+            Some(fallback) if req_path.extension().is_none() => {
+                debug!("Serving spa fallback for {:?}", req_path);
+                match cfg.resolver.open(fallback) {
+                    Ok(file) => serve_file(req, file, fallback).await,
+                    Err(err) => {
+                        warn!("Failed to open spa fallback({:?}): {:#}", fallback, err);
+                        not_found()
+                    }
+                }
+            }
+            _ => not_found(),
+        },
     }
 }
 
+#[get("/{tail:.*}")]
+async fn index(cfg: web::Data<LiveConfig>, req: HttpRequest) -> impl Responder {
+    let cfg = cfg.get();
+    // decode the raw path ourselves, file names on unix don't need to be valid utf8
+    let raw_path = req.uri().path();
+    let req_path = utils::decode_url_path(raw_path.strip_prefix('/').unwrap_or(raw_path));
+    // rules are matched against the same decoded path that is served, so an encoded slash can't
+    // be used to skip them
+    let url_path = format!("/{}", req_path.to_string_lossy());
+
+    let mut response = match cfg.redirects.resolve(&url_path) {
+        Some(redirects::Action::Redirect(status, location)) => redirect(&req, status, location),
+        Some(redirects::Action::Rewrite(path)) => {
+            debug!("Rewriting {:?} to {:?}", url_path, path);
+            let req_path = path.trim_start_matches('/').parse().unwrap();
+            serve(&cfg, &req, req_path).await
+        }
+        None => match clean_url_redirect(&cfg, &req, &req_path) {
+            Some(response) => response,
+            None => serve(&cfg, &req, req_path).await,
+        },
+    };
+    cfg.headers.apply(&url_path, response.headers_mut());
+    response
+}
+
+async fn upload_form() -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .body(upload::FORM)
+}
+
+async fn upload_receive(
+    upload: web::Data<Upload>,
+    req: HttpRequest,
+    payload: web::Payload,
+) -> HttpResponse {
+    let too_large = || {
+        HttpResponse::PayloadTooLarge()
+            .content_type("text/plain; charset=utf-8")
+            .body("413 - payload too large\n")
+    };
+
+    // reject early if the client already told us the upload is too large
+    let content_length = req
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|len| len.to_str().ok())
+        .and_then(|len| len.parse::<u64>().ok());
+    if content_length.is_some_and(|len| len > upload.max_size()) {
+        return too_large();
+    }
+
+    let multipart = Multipart::new(req.headers(), payload);
+    match upload.receive(multipart).await {
+        Ok(files) if files.is_empty() => bad_request(),
+        Ok(files) => HttpResponse::Ok()
+            .content_type("text/html; charset=utf-8")
+            .body(upload::render_received(&files)),
+        Err(UploadError::TooLarge) => too_large(),
+        Err(UploadError::NoSpace) => {
+            warn!("Refusing upload, the upload directory is running out of space");
+            HttpResponse::InsufficientStorage()
+                .content_type("text/plain; charset=utf-8")
+                .body("507 - insufficient storage\n")
+        }
+        Err(UploadError::Invalid(err)) => {
+            debug!("Rejected upload: {:#}", err);
+            bad_request()
+        }
+        Err(UploadError::Failed(err)) => {
+            warn!("Failed to receive upload: {:#}", err);
+            HttpResponse::InternalServerError()
+                .content_type("text/plain; charset=utf-8")
+                .body("500 - internal server error\n")
+        }
+    }
+}
+
+async fn webdav(
+    webdav: web::Data<WebDav>,
+    req: HttpRequest,
+    payload: web::Payload,
+) -> HttpResponse {
+    webdav.handle(req, payload).await
+}
+
 #[actix_web::main]
-pub async fn run(args: Args, bind: Bind, web_root: String) -> Result<()> {
-    let config = web::Data::new(Config {
-        web_root,
-        list_directories: args.list_directories,
-    });
+pub async fn run(
+    args: Args,
+    bind: Bind,
+    redirect: Option<Bind>,
+    web_root: Option<String>,
+    reload: mpsc::Receiver<Args>,
+) -> Result<()> {
+    // authentication, uploads and webdav are only configured on startup
+    let auth = Auth::load(&args)?.map(Arc::new);
+    let access_log = AccessLog::load(&args)?;
+    let upload = Upload::load(&args, web_root.as_deref())?.map(web::Data::new);
+    let webdav = WebDav::load(&args, auth.as_deref())?.map(web::Data::new);
+    let config = web::Data::new(LiveConfig::new(Config::load(&args, web_root)?));
+    let acme = Acme::load(&args)?;
+    let challenges = acme.as_ref().map(|acme| web::Data::from(acme.challenges()));
+    let tls = if let Some(acme) = &acme {
+        Some(CertResolver::new(
+            acme.initial_cert()?,
+            Some(acme.challenges()),
+        ))
+    } else {
+        CertResolver::load(&args)?
+    };
+
+    {
+        let config = config.clone();
+        // certificates from ACME are replaced when they're renewed instead
+        let tls = tls.clone().filter(|_| acme.is_none());
+        thread::spawn(move || {
+            for args in reload {
+                info!("Reloading config");
+                match config.reload(&args) {
+                    Ok(()) => info!("Successfully reloaded config"),
+                    Err(err) => error!(
+                        "Failed to reload config, keeping the previous one: {:#}",
+                        err
+                    ),
+                }
+                if let Some(tls) = &tls {
+                    match tls.reload(&args) {
+                        Ok(()) => info!("Successfully reloaded certificate"),
+                        Err(err) => error!(
+                            "Failed to reload certificate, keeping the previous one: {:#}",
+                            err
+                        ),
+                    }
+                }
+            }
+        });
+    }
+
+    let record_metrics = args.metrics_bind.is_some();
+    let redirect_to_https = tls.is_some();
     let server = HttpServer::new(move || {
+        let upload = upload.clone();
+        let webdav = webdav.clone();
+        let challenges = challenges.clone();
         App::new()
-            .wrap(
-                middleware::DefaultHeaders::new()
-                    .add((header::DATE, "Thu, 01 Jan 1970 00:00:00 GMT"))
-                    .add((header::X_CONTENT_TYPE_OPTIONS, "nosniff"))
-                    .add((header::REFERRER_POLICY, "no-referrer")),
-            )
+            .wrap(middleware::Condition::new(
+                auth.is_some(),
+                RequireAuth::new(auth.clone().unwrap_or_default()),
+            ))
+            .wrap(default_headers())
             .wrap(middleware::Compress::default())
+            // only requests from the redirect listener aren't secure
+            .wrap(middleware::Condition::new(
+                redirect_to_https,
+                RedirectToHttps,
+            ))
+            .wrap(middleware::Condition::new(record_metrics, RecordMetrics))
+            // outermost, so rejected requests are logged too and the compressed size is counted
+            .wrap(middleware::Condition::new(
+                access_log.is_some(),
+                LogAccess::new(access_log.clone().unwrap_or_default()),
+            ))
             .app_data(config.clone())
+            .configure(|app| {
+                if let Some(challenges) = challenges {
+                    app.service(
+                        web::resource(format!("{}{{token}}", acme::CHALLENGE_PATH))
+                            .app_data(challenges)
+                            .route(web::get().to(acme::http_challenge)),
+                    );
+                }
+                if let Some(upload) = upload {
+                    app.service(
+                        web::resource(&upload.path)
+                            .app_data(upload.clone())
+                            .route(web::get().to(upload_form))
+                            .route(web::post().to(upload_receive)),
+                    );
+                }
+                if let Some(webdav) = webdav {
+                    app.service(
+                        web::scope(&webdav.path)
+                            .app_data(webdav.clone())
+                            .default_service(web::to(self::webdav)),
+                    );
+                }
+            })
             .service(index)
     });
 
-    let server = match bind {
-        Bind::Tcp(tcp) => server.listen(tcp),
+    let server = match (bind, &tls) {
+        (Bind::Tcp(tcp), Some(tls)) => server.listen_rustls(tcp, tls.server_config()),
+        (Bind::Tcp(tcp), None) => server.listen(tcp),
+        #[cfg(unix)]
+        (Bind::Unix(_), Some(_)) => {
+            unreachable!("TLS on unix sockets is rejected by Server::setup")
+        }
+        #[cfg(unix)]
+        (Bind::Unix(uds), None) => server.listen_uds(uds),
+    };
+    let server = server.context("Failed to setup server")?;
+
+    let server = match redirect {
+        Some(Bind::Tcp(tcp)) => server.listen(tcp),
         #[cfg(unix)]
-        Bind::Unix(uds) => server.listen_uds(uds),
+        Some(Bind::Unix(uds)) => server.listen_uds(uds),
+        None => Ok(server),
     };
 
-    server
-        .context("Failed to setup server")?
-        .run()
-        .await
-        .context("Failed to run http server")?;
+    let server = server.context("Failed to setup redirect server")?.run();
+    if let (Some(acme), Some(tls)) = (acme, tls) {
+        actix_web::rt::spawn(acme.run(tls));
+    }
+    server.await.context("Failed to run http server")?;
 
     warn!("httpd thread has terminated");
     Ok(())
@@ -222,8 +879,32 @@ pub async fn run(args: Args, bind: Bind, web_root: String) -> Result<()> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use actix_web::dev::ServiceResponse;
+    use actix_web::test::{self, TestRequest};
+    use clap::Parser;
     use test_case::test_case;
 
+    async fn get(root: &Path, flags: &[&str], uri: &str) -> ServiceResponse {
+        let web_root = root.to_str().unwrap().to_string();
+        let mut argv = vec!["narnia", "-w", &web_root];
+        argv.extend(flags);
+        let args = Args::parse_from(argv);
+        let config = Config::load(&args, Some(web_root.clone())).unwrap();
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(LiveConfig::new(config)))
+                .service(index),
+        )
+        .await;
+        test::call_service(&app, TestRequest::get().uri(uri).to_request()).await
+    }
+
+    fn location(res: &ServiceResponse) -> Option<&str> {
+        res.headers()
+            .get(header::LOCATION)
+            .map(|location| location.to_str().unwrap())
+    }
+
     #[test_case("", "/var/www/"; "root")]
     #[test_case("index.html", "/var/www/index.html"; "index.html")]
     #[test_case("a/b/c", "/var/www/a/b/c"; "a b c file")]
@@ -246,4 +927,117 @@ mod tests {
         let result = resolve_path_req("/var/www/", Path::new(x));
         assert!(result.is_err());
     }
+
+    #[test_case("/static/secret.txt"; "plain")]
+    #[test_case("/static%2Fsecret.txt"; "encoded slash")]
+    #[test_case("/static%2fsecret.txt"; "encoded slash lowercase")]
+    #[test_case("/%73tatic/secret.txt"; "encoded letter")]
+    #[actix_web::test]
+    async fn test_rules_match_served_path(uri: &str) {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        fs::create_dir(root.join("static")).unwrap();
+        fs::write(root.join("static/secret.txt"), "secret").unwrap();
+        fs::write(
+            root.join("_headers"),
+            "/static/*\n  X-Frame-Options: DENY\n",
+        )
+        .unwrap();
+
+        let res = get(root, &[], uri).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers().get(header::X_FRAME_OPTIONS).unwrap(), "DENY");
+
+        fs::write(root.join("_redirects"), "/static/* /moved 302\n").unwrap();
+        let res = get(root, &[], uri).await;
+        assert_eq!(res.status(), StatusCode::FOUND);
+        assert_eq!(location(&res), Some("/moved"));
+    }
+
+    fn sorted_headers(res: &ServiceResponse) -> Vec<(String, Vec<u8>)> {
+        let mut headers = res
+            .headers()
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.as_bytes().to_vec()))
+            .collect::<Vec<_>>();
+        headers.sort();
+        headers
+    }
+
+    #[test_case("style.css"; "css")]
+    #[test_case("index.html"; "html")]
+    #[test_case("data.bin"; "unknown extension")]
+    #[actix_web::test]
+    async fn test_cached_response_headers(name: &str) {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        fs::write(root.join(name), "body {}").unwrap();
+        let uri = format!("/{}", name);
+
+        let uncached = get(root, &[], &uri).await;
+        assert_eq!(uncached.status(), StatusCode::OK);
+        let expected = sorted_headers(&uncached);
+
+        let web_root = root.to_str().unwrap().to_string();
+        let args = Args::parse_from(["narnia", "-w", &web_root, "--cache-size", "1M"]);
+        let config = web::Data::new(LiveConfig::new(
+            Config::load(&args, Some(web_root.clone())).unwrap(),
+        ));
+        let app = test::init_service(App::new().app_data(config.clone()).service(index)).await;
+
+        let path = root.join(name);
+        let mut served_from_cache = false;
+        for _ in 0..3 {
+            served_from_cache |= config.get().cache.as_ref().unwrap().get(&path).is_some();
+            let req = TestRequest::get().uri(&uri).to_request();
+            let res = test::call_service(&app, req).await;
+            assert_eq!(res.status(), StatusCode::OK);
+            assert_eq!(sorted_headers(&res), expected);
+            assert_eq!(test::read_body(res).await, "body {}");
+        }
+        assert!(served_from_cache);
+
+        // the same cache is used after a reload, but it's empty
+        let cache = config.get().cache.clone().unwrap();
+        config.reload(&args).unwrap();
+        assert!(Arc::ptr_eq(&cache, config.get().cache.as_ref().unwrap()));
+        assert!(cache.get(&path).is_none());
+    }
+
+    #[test_case("/a%20b.html", Some("/a%20b"); "encoded")]
+    #[test_case("/%3F.html", Some("/%3F"); "question mark")]
+    #[test_case("/d%C3%BCr/index.html", Some("/d%C3%BCr/"); "unicode directory")]
+    #[test_case("/index.html", Some("/"); "root index")]
+    #[test_case("/both/index.html", None; "different index is served")]
+    #[test_case("/both/index.htm", Some("/both/"); "served index")]
+    #[test_case("/shadowed.html", None; "directory with the same name")]
+    #[test_case("/missing.html", None; "missing")]
+    #[actix_web::test]
+    async fn test_clean_url_redirect(uri: &str, expected: Option<&str>) {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        for path in [
+            "a b.html",
+            "?.html",
+            "d\u{fc}r/index.html",
+            "index.html",
+            "both/index.htm",
+            "both/index.html",
+            "shadowed.html",
+            "shadowed/x.txt",
+        ] {
+            let path = root.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, "x").unwrap();
+        }
+
+        let flags = ["--clean-urls", "--index", "index.htm,index.html"];
+        let res = get(root, &flags, uri).await;
+        if expected.is_some() {
+            assert_eq!(res.status(), StatusCode::MOVED_PERMANENTLY);
+        } else {
+            assert_ne!(res.status(), StatusCode::MOVED_PERMANENTLY);
+        }
+        assert_eq!(location(&res), expected);
+    }
 }
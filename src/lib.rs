@@ -1,7 +1,26 @@
+pub mod access_log;
+pub mod acme;
+pub mod admin;
+pub mod archive;
 pub mod args;
+pub mod auth;
+pub mod body;
+pub mod bundle;
+pub mod cache;
+pub mod deploy;
 pub mod errors;
 pub mod httpd;
+pub mod listing;
+pub mod logger;
+pub mod metrics;
+pub mod resolve;
+pub mod rules;
 pub mod security;
 pub mod server;
+pub mod share;
+pub mod signing;
+pub mod tls;
 pub mod tor;
+pub mod upload;
 pub mod utils;
+pub mod webdav;
@@ -0,0 +1,350 @@
+use crate::errors::*;
+use crate::resolve::{Node, Resolver};
+use crate::rules;
+use crate::utils;
+use actix_web::web;
+use percent_encoding::{percent_encode, AsciiSet, CONTROLS};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+
+/// Characters that are escaped in links, non-ascii bytes are always escaped
+const PATH: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'%')
+    .add(b'&')
+    .add(b'\'')
+    .add(b':')
+    .add(b'<')
+    .add(b'>')
+    .add(b'?')
+    .add(b'[')
+    .add(b'\\')
+    .add(b']')
+    .add(b'^')
+    .add(b'`')
+    .add(b'{')
+    .add(b'|')
+    .add(b'}');
+
+/// Like PATH, but for a single file name
+const SEGMENT: &AsciiSet = &PATH.add(b'/');
+
+pub const DEFAULT_TEMPLATE: &str = r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<meta name="viewport" content="width=device-width, initial-scale=1">
+<title>Index of {{path}}</title>
+</head>
+<body>
+<h1>Index of {{path}}</h1>
+<hr>
+<table>
+<tr><th><a href="?sort=name">Name</a></th><th><a href="?sort=size&amp;order=desc">Size</a></th></tr>
+{{entries}}
+</table>
+{{downloads}}
+<hr>
+</body>
+</html>
+"#;
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SortKey {
+    Name,
+    Size,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SortOrder {
+    Asc,
+    Desc,
+}
+
+/// Query parameters to control the directory listing, like `?sort=size&order=desc`
+#[derive(Debug, Default, Deserialize)]
+pub struct ListingQuery {
+    sort: Option<SortKey>,
+    order: Option<SortOrder>,
+}
+
+impl ListingQuery {
+    /// Invalid query strings are ignored and fall back to the default order
+    pub fn parse(query: &str) -> ListingQuery {
+        web::Query::<ListingQuery>::from_query(query)
+            .map(|query| query.into_inner())
+            .unwrap_or_default()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EntryType {
+    Directory,
+    File,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Entry {
+    /// The file name, invalid utf8 is replaced
+    pub name: String,
+    /// The percent-encoded link to the file
+    pub href: String,
+    #[serde(rename = "type")]
+    pub entry_type: EntryType,
+    /// The size of the file, not set for directories
+    pub size: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Listing {
+    pub path: String,
+    pub entries: Vec<Entry>,
+}
+
+impl Listing {
+    pub fn read(
+        resolver: &Resolver,
+        dir: Node,
+        full_path: &Path,
+        req_path: &Path,
+    ) -> Result<Listing> {
+        let is_root = req_path.as_os_str().is_empty();
+        let entries = resolver
+            .read_dir(dir, full_path)
+            .context("Failed to list directory")?;
+
+        let mut listing = Vec::new();
+        for entry in entries {
+            let bytes = utils::os_str_bytes(&entry.name);
+            let name = String::from_utf8_lossy(&bytes).into_owned();
+            if is_root && rules::is_rules_file(Path::new(&name)) {
+                continue;
+            }
+
+            let mut href = percent_encode(&bytes, SEGMENT).to_string();
+            listing.push(if entry.is_dir {
+                href.push('/');
+                Entry {
+                    name,
+                    href,
+                    entry_type: EntryType::Directory,
+                    size: None,
+                }
+            } else {
+                Entry {
+                    name,
+                    href,
+                    entry_type: EntryType::File,
+                    size: Some(entry.len),
+                }
+            });
+        }
+
+        let mut listing = Listing {
+            path: format!("/{}", req_path.to_string_lossy()),
+            entries: listing,
+        };
+        listing.sort(&ListingQuery::default());
+        Ok(listing)
+    }
+
+    /// Sort the entries, directories are always listed first
+    pub fn sort(&mut self, query: &ListingQuery) {
+        let key = query.sort.unwrap_or(SortKey::Name);
+        let order = query.order.unwrap_or(SortOrder::Asc);
+
+        self.entries.sort_by(|a, b| {
+            let dirs_first =
+                (b.entry_type == EntryType::Directory).cmp(&(a.entry_type == EntryType::Directory));
+            let ordering = match key {
+                SortKey::Name => a.name.cmp(&b.name),
+                SortKey::Size => a.size.cmp(&b.size).then_with(|| a.name.cmp(&b.name)),
+            };
+            let ordering = match order {
+                SortOrder::Asc => ordering,
+                SortOrder::Desc => ordering.reverse(),
+            };
+            dirs_first.then(ordering)
+        });
+    }
+
+    pub fn to_html(&self, template: &str, downloads: bool) -> String {
+        let mut entries = String::new();
+        if self.path != "/" {
+            entries.push_str("<tr><td><a href=\"../\">../</a></td><td></td></tr>\n");
+        }
+
+        for entry in &self.entries {
+            let (name, size) = match entry.entry_type {
+                EntryType::Directory => (format!("{}/", entry.name), "-".to_string()),
+                EntryType::File => (entry.name.clone(), human_size(entry.size.unwrap_or(0))),
+            };
+            entries.push_str(&format!(
+                "<tr><td><a href=\"{}\">{}</a></td><td>{}</td></tr>\n",
+                htmlescape::encode_attribute(&entry.href),
+                htmlescape::encode_minimal(&name),
+                size
+            ));
+        }
+
+        let downloads = if downloads {
+            r#"<p>Download: <a href="?download=tar">tar</a> <a href="?download=zip">zip</a></p>"#
+        } else {
+            ""
+        };
+
+        let path = htmlescape::encode_minimal(&self.path);
+        render_template(
+            template,
+            &[
+                ("path", &path),
+                ("entries", entries.trim_end()),
+                ("downloads", downloads),
+            ],
+        )
+    }
+}
+
+/// Percent-encode a request path so it can be used in a redirect
+pub fn encode_path(path: &Path) -> String {
+    let bytes = utils::os_str_bytes(path.as_os_str());
+    percent_encode(&bytes, PATH).to_string()
+}
+
+pub fn load_template(path: &Path) -> Result<String> {
+    let template = fs::read_to_string(path)
+        .with_context(|| anyhow!("Failed to read listing template: {:?}", path))?;
+    if !template.contains("{{entries}}") {
+        bail!("Listing template is missing an {{{{entries}}}} placeholder");
+    }
+    Ok(template)
+}
+
+/// Replace `{{name}}` placeholders in a single pass, so values can't inject placeholders
+fn render_template(template: &str, vars: &[(&str, &str)]) -> String {
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        out.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        let value = rest.find("}}").and_then(|end| {
+            let name = &rest[2..end];
+            let (_, value) = vars.iter().find(|(key, _)| *key == name)?;
+            Some((value, end + 2))
+        });
+
+        if let Some((value, len)) = value {
+            out.push_str(value);
+            rest = &rest[len..];
+        } else {
+            out.push_str("{{");
+            rest = &rest[2..];
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Format a file size similar to `ls -h`
+pub fn human_size(size: u64) -> String {
+    const UNITS: &[&str] = &["K", "M", "G", "T", "P", "E"];
+
+    if size < 1024 {
+        return size.to_string();
+    }
+
+    let mut size = size as f64;
+    let mut unit = UNITS[0];
+    for u in UNITS {
+        size /= 1024.0;
+        unit = u;
+        if size < 1024.0 {
+            break;
+        }
+    }
+
+    if size < 10.0 {
+        format!("{:.1}{}", size, unit)
+    } else {
+        format!("{:.0}{}", size, unit)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_case::test_case;
+
+    #[test_case(0, "0"; "zero")]
+    #[test_case(1023, "1023"; "bytes")]
+    #[test_case(1024, "1.0K"; "one kilobyte")]
+    #[test_case(1536, "1.5K"; "fraction")]
+    #[test_case(20 * 1024 * 1024, "20M"; "megabytes")]
+    #[test_case(3 * 1024 * 1024 * 1024 * 1024, "3.0T"; "terabytes")]
+    fn test_human_size(size: u64, expected: &str) {
+        assert_eq!(human_size(size), expected);
+    }
+
+    fn listing() -> Listing {
+        let file = |name: &str, size| Entry {
+            name: name.to_string(),
+            href: name.to_string(),
+            entry_type: EntryType::File,
+            size: Some(size),
+        };
+        Listing {
+            path: "/".to_string(),
+            entries: vec![
+                file("b", 1),
+                Entry {
+                    name: "z".to_string(),
+                    href: "z/".to_string(),
+                    entry_type: EntryType::Directory,
+                    size: None,
+                },
+                file("a", 2),
+                file("c", 3),
+            ],
+        }
+    }
+
+    #[test_case("", &["z", "a", "b", "c"]; "default")]
+    #[test_case("sort=name&order=desc", &["z", "c", "b", "a"]; "name desc")]
+    #[test_case("sort=size", &["z", "b", "a", "c"]; "size asc")]
+    #[test_case("sort=size&order=desc", &["z", "c", "a", "b"]; "size desc")]
+    #[test_case("sort=invalid", &["z", "a", "b", "c"]; "invalid")]
+    fn test_sort_listing(query: &str, expected: &[&str]) {
+        let mut listing = listing();
+        listing.sort(&ListingQuery::parse(query));
+        let names = listing
+            .entries
+            .iter()
+            .map(|e| e.name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(names, expected);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_encode_path_roundtrip() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+        let path = Path::new(OsStr::from_bytes(b"dir/caf\xe9 #1.txt"));
+        let encoded = encode_path(path);
+        assert_eq!(encoded, "dir/caf%E9%20%231.txt");
+        assert_eq!(utils::decode_url_path(&encoded), path);
+    }
+
+    #[test]
+    fn test_render_template() {
+        let out = render_template("{{a}} {{b}} {{missing}} {{", &[("a", "{{b}}"), ("b", "2")]);
+        assert_eq!(out, "{{b}} 2 {{missing}} {{");
+    }
+}
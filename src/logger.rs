@@ -0,0 +1,271 @@
+use crate::args::Args;
+use crate::errors::*;
+use env_logger::filter::{self, Filter};
+use env_logger::Env;
+use log::{Level, Log, Metadata, Record};
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::io::{self, Write};
+#[cfg(unix)]
+use std::os::unix::net::UnixDatagram;
+use std::process;
+use std::time::SystemTime;
+
+#[derive(Debug, Clone, Copy, PartialEq, clap::ArgEnum, Serialize, Deserialize)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, clap::ArgEnum, Serialize, Deserialize)]
+pub enum LogOutput {
+    Stderr,
+    Syslog,
+    Journald,
+}
+
+#[cfg(unix)]
+const SYSLOG_SOCKET: &str = "/dev/log";
+#[cfg(unix)]
+const JOURNALD_SOCKET: &str = "/run/systemd/journal/socket";
+/// The daemon facility, shifted into place for the syslog priority
+const SYSLOG_FACILITY: u8 = 3 << 3;
+
+pub fn init(args: &Args) -> Result<()> {
+    let log_level = match args.verbose {
+        0 => "warn",
+        1 => "info",
+        2 => "debug",
+        _ => "trace",
+    };
+
+    if args.log_format == LogFormat::Text && args.log_output == LogOutput::Stderr {
+        env_logger::init_from_env(Env::default().default_filter_or(log_level));
+        return Ok(());
+    }
+
+    let spec = env::var("RUST_LOG").unwrap_or_else(|_| log_level.to_string());
+    let filter = filter::Builder::new().parse(&spec).build();
+    let sink = Sink::connect(args.log_output)?;
+
+    log::set_max_level(filter.filter());
+    log::set_boxed_logger(Box::new(Logger {
+        filter,
+        format: args.log_format,
+        sink,
+    }))?;
+    Ok(())
+}
+
+/// The part of narnia a log line is coming from, so logs can be filtered without knowing the
+/// module layout
+fn component(target: &str) -> &'static str {
+    let mut parts = target.split("::");
+    let krate = parts.next().unwrap_or_default();
+    let module = parts.next().unwrap_or_default();
+    match (krate, module) {
+        ("narnia", "tor") => "tor",
+        ("narnia", "security") => "security",
+        ("narnia", "" | "server" | "admin" | "metrics" | "args" | "deploy" | "share") => "server",
+        _ if krate.starts_with("libtor") => "tor",
+        _ => "httpd",
+    }
+}
+
+fn syslog_severity(level: Level) -> u8 {
+    match level {
+        Level::Error => 3,
+        Level::Warn => 4,
+        Level::Info => 6,
+        Level::Debug | Level::Trace => 7,
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct JsonLine<'a> {
+    time: String,
+    level: &'a str,
+    component: &'a str,
+    target: &'a str,
+    pid: u32,
+    message: String,
+}
+
+fn format_json(record: &Record, time: SystemTime, pid: u32) -> Result<String> {
+    let line = JsonLine {
+        time: humantime::format_rfc3339_seconds(time).to_string(),
+        level: &record.level().as_str().to_lowercase(),
+        component: component(record.target()),
+        target: record.target(),
+        pid,
+        message: record.args().to_string(),
+    };
+    Ok(serde_json::to_string(&line)?)
+}
+
+/// The timestamp is omitted, syslog and journald record it themselves
+fn format_text(record: &Record) -> String {
+    format!(
+        "{:<5} {}] {}",
+        record.level(),
+        record.target(),
+        record.args()
+    )
+}
+
+/// Encode a message for the native journald protocol, values that contain newlines are sent with
+/// an explicit length instead
+fn journald_message(fields: &[(&str, &str)]) -> Vec<u8> {
+    let mut buf = Vec::new();
+    for (key, value) in fields {
+        buf.extend_from_slice(key.as_bytes());
+        if value.contains('\n') {
+            buf.push(b'\n');
+            buf.extend_from_slice(&(value.len() as u64).to_le_bytes());
+        } else {
+            buf.push(b'=');
+        }
+        buf.extend_from_slice(value.as_bytes());
+        buf.push(b'\n');
+    }
+    buf
+}
+
+/// Sockets are connected on startup, so logging keeps working after a chroot
+enum Sink {
+    Stderr,
+    #[cfg(unix)]
+    Syslog(UnixDatagram),
+    #[cfg(unix)]
+    Journald(UnixDatagram),
+}
+
+impl Sink {
+    fn connect(output: LogOutput) -> Result<Sink> {
+        match output {
+            LogOutput::Stderr => Ok(Sink::Stderr),
+            #[cfg(unix)]
+            LogOutput::Syslog => {
+                let socket = UnixDatagram::unbound()?;
+                socket
+                    .connect(SYSLOG_SOCKET)
+                    .with_context(|| anyhow!("Failed to connect to syslog: {:?}", SYSLOG_SOCKET))?;
+                Ok(Sink::Syslog(socket))
+            }
+            #[cfg(unix)]
+            LogOutput::Journald => {
+                let socket = UnixDatagram::unbound()?;
+                socket.connect(JOURNALD_SOCKET).with_context(|| {
+                    anyhow!("Failed to connect to journald: {:?}", JOURNALD_SOCKET)
+                })?;
+                Ok(Sink::Journald(socket))
+            }
+            #[cfg(not(unix))]
+            _ => bail!("Logging to {:?} is only supported on unix", output),
+        }
+    }
+}
+
+struct Logger {
+    filter: Filter,
+    format: LogFormat,
+    sink: Sink,
+}
+
+impl Logger {
+    fn format(&self, record: &Record, pid: u32) -> Result<String> {
+        match self.format {
+            LogFormat::Text => Ok(format_text(record)),
+            LogFormat::Json => format_json(record, SystemTime::now(), pid),
+        }
+    }
+
+    fn write(&self, record: &Record) -> Result<()> {
+        let pid = process::id();
+        let message = self.format(record, pid)?;
+        match &self.sink {
+            Sink::Stderr => {
+                let mut stderr = io::stderr().lock();
+                writeln!(stderr, "{}", message)?;
+            }
+            #[cfg(unix)]
+            Sink::Syslog(socket) => {
+                let priority = SYSLOG_FACILITY | syslog_severity(record.level());
+                let line = format!("<{}>narnia[{}]: {}", priority, pid, message);
+                socket.send(line.as_bytes())?;
+            }
+            #[cfg(unix)]
+            Sink::Journald(socket) => {
+                let priority = syslog_severity(record.level()).to_string();
+                let buf = journald_message(&[
+                    ("MESSAGE", &message),
+                    ("PRIORITY", &priority),
+                    ("SYSLOG_IDENTIFIER", "narnia"),
+                    ("NARNIA_COMPONENT", component(record.target())),
+                    ("CODE_MODULE", record.target()),
+                ]);
+                socket.send(&buf)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.filter.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if self.filter.matches(record) {
+            // there's nowhere left to report this to
+            self.write(record).ok();
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, UNIX_EPOCH};
+    use test_case::test_case;
+
+    #[test_case("narnia", "server")]
+    #[test_case("narnia::server", "server")]
+    #[test_case("narnia::admin", "server")]
+    #[test_case("narnia::tor", "tor")]
+    #[test_case("libtor::tor", "tor")]
+    #[test_case("narnia::security::os", "security")]
+    #[test_case("narnia::httpd", "httpd")]
+    #[test_case("narnia::upload", "httpd")]
+    #[test_case("actix_server::worker", "httpd")]
+    fn test_component(target: &str, expected: &str) {
+        assert_eq!(component(target), expected);
+    }
+
+    #[test]
+    fn test_format_json() {
+        let args = format_args!("Binding to tcp: {:?}", "[::1]:1337");
+        let record = Record::builder()
+            .args(args)
+            .level(Level::Info)
+            .target("narnia::server")
+            .build();
+        let line = format_json(&record, UNIX_EPOCH + Duration::from_secs(1337), 42).unwrap();
+        assert_eq!(
+            line,
+            r#"{"time":"1970-01-01T00:22:17Z","level":"info","component":"server","target":"narnia::server","pid":42,"message":"Binding to tcp: \"[::1]:1337\""}"#
+        );
+    }
+
+    #[test]
+    fn test_journald_message() {
+        let buf = journald_message(&[("MESSAGE", "a\nb"), ("PRIORITY", "6")]);
+        assert_eq!(
+            buf,
+            b"MESSAGE\n\x03\x00\x00\x00\x00\x00\x00\x00a\nb\nPRIORITY=6\n".to_vec()
+        );
+    }
+}
@@ -1,10 +1,14 @@
 use clap::Parser;
-use env_logger::Env;
-use narnia::args::Args;
+use narnia::admin::{Admin, STATUS};
+use narnia::args::{Args, SubCommand};
 use narnia::errors::*;
+use narnia::metrics::Exporter;
 use narnia::security;
-use narnia::server::Server;
-use std::io::{self, Read};
+use narnia::server::{self, Reloader, Server};
+use std::env;
+use std::fs;
+use std::io::{self, BufRead};
+use std::path::Path;
 use std::sync::mpsc;
 use std::thread;
 
@@ -13,6 +17,8 @@ fn get_arguments() -> Result<Args> {
     if args.child_process {
         args = read_args_stdin().context("Failed to read arguments from stdin")?;
         args.child_process = true;
+    } else {
+        args = Args::parse_with_config(env::args_os())?;
     }
     Ok(args)
 }
@@ -26,46 +32,182 @@ fn read_args_stdin() -> Result<Args> {
     Ok(args)
 }
 
+/// Reload the config on SIGHUP, the commandline and config file are parsed again
+#[cfg(unix)]
+fn reload_on_sighup(mut reloader: Reloader) -> Result<()> {
+    use signal_hook::consts::SIGHUP;
+    use signal_hook::iterator::Signals;
+
+    let mut signals = Signals::new([SIGHUP]).context("Failed to setup signal handler")?;
+    thread::spawn(move || {
+        for _ in signals.forever() {
+            info!("Received SIGHUP, reloading config");
+            let result =
+                Args::parse_with_config(env::args_os()).and_then(|args| reloader.reload(args));
+            if let Err(err) = result {
+                error!("Failed to reload config: {:#}", err);
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Shut down cleanly on SIGTERM and SIGINT, so the pid file is removed. Without a child process this
+/// is handled by the http server, which shuts down gracefully
+#[cfg(unix)]
+fn shutdown_on_signal(tx: mpsc::Sender<()>) -> Result<()> {
+    use signal_hook::consts::{SIGINT, SIGTERM};
+    use signal_hook::iterator::Signals;
+
+    let mut signals = Signals::new([SIGTERM, SIGINT]).context("Failed to setup signal handler")?;
+    thread::spawn(move || {
+        if let Some(signal) = signals.forever().next() {
+            info!("Received signal {}, shutting down", signal);
+            tx.send(()).ok();
+        }
+    });
+    Ok(())
+}
+
+/// Removes the pid file on shutdown. On unix the directory is opened when the file is written, so
+/// this still works after a chroot
+struct PidFile {
+    path: std::path::PathBuf,
+    #[cfg(unix)]
+    dir: Option<fs::File>,
+}
+
+impl PidFile {
+    fn create(path: &Path) -> Result<PidFile> {
+        fs::write(path, format!("{}\n", std::process::id()))
+            .with_context(|| anyhow!("Failed to write pid file: {:?}", path))?;
+        #[cfg(unix)]
+        let dir = match path.parent() {
+            Some(dir) if dir != Path::new("") => fs::File::open(dir).ok(),
+            _ => fs::File::open(".").ok(),
+        };
+        Ok(PidFile {
+            path: path.to_path_buf(),
+            #[cfg(unix)]
+            dir,
+        })
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        #[cfg(unix)]
+        let result = match (&self.dir, self.path.file_name()) {
+            (Some(dir), Some(name)) => {
+                use std::os::unix::io::AsRawFd;
+                nix::unistd::unlinkat(
+                    Some(dir.as_raw_fd()),
+                    name,
+                    nix::unistd::UnlinkatFlags::NoRemoveDir,
+                )
+                .map_err(io::Error::from)
+            }
+            _ => fs::remove_file(&self.path),
+        };
+        #[cfg(not(unix))]
+        let result = fs::remove_file(&self.path);
+
+        if let Err(err) = result {
+            warn!("Failed to remove pid file({:?}): {:#}", self.path, err);
+        }
+    }
+}
+
 fn main() -> Result<()> {
     let args = get_arguments()?;
 
-    let log_level = match args.verbose {
-        0 => "warn",
-        1 => "info",
-        2 => "debug",
-        _ => "trace",
-    };
-    env_logger::init_from_env(Env::default().default_filter_or(log_level));
+    narnia::logger::init(&args)?;
+
+    match &args.subcommand {
+        Some(SubCommand::Share(share)) => return narnia::share::run(args.clone(), share),
+        Some(SubCommand::Reload) => return server::send_reload(&args),
+        Some(SubCommand::Deploy(deploy)) => return narnia::deploy::run(&args, deploy),
+        None => (),
+    }
+
+    let _pid_file = args.pid_file.as_deref().map(PidFile::create).transpose()?;
+
+    // the child process is reloaded by the parent, but shouldn't be terminated by SIGHUP either
+    #[cfg(unix)]
+    if args.child_process {
+        signal_hook::flag::register(signal_hook::consts::SIGHUP, Default::default())
+            .context("Failed to setup signal handler")?;
+    }
 
     let (tx, rx) = mpsc::channel();
 
     let server = Server::setup(args.clone(), tx.clone())?;
+    // in multi-process mode metrics and the admin endpoints are served by the parent
+    let (exporter, admin) = if args.child_process {
+        (None, None)
+    } else {
+        (Exporter::setup(&args)?, Admin::setup(&args)?)
+    };
     debug!("Locking down process");
-    security::setup(&args)?;
+    let sandbox = security::setup(&args)?;
+    if let (Some(config), Some(chroot)) = (&args.config, &sandbox.chroot) {
+        if !args.child_process {
+            warn!(
+                "The config file is read from {:?} inside of the chroot {:?} on reload",
+                config, chroot
+            );
+        }
+    }
     debug!("Sending server to background");
-    server.background();
+    let mut reloader = server.background();
+    if args.child_process {
+        if server::reports_to_parent(&args) {
+            server::report_to_parent(&args, sandbox);
+        }
+    } else if !args.needs_child() {
+        STATUS.httpd_ready(sandbox);
+    }
+    if let Some(exporter) = exporter {
+        exporter.background();
+    }
+    if let Some(admin) = admin {
+        admin.background();
+    }
 
-    // if we are a child process we monitor if stdin gets closed so we shutdown if the parent dies
+    // if we are a child process we monitor if stdin gets closed so we shutdown if the parent dies,
+    // the parent sends new arguments as json if the config should be reloaded
     if args.child_process {
         let tx = tx.clone();
         thread::spawn(move || {
-            let mut stdin = io::stdin();
-            let mut buf = [0; 128];
-            loop {
-                match stdin.read(&mut buf) {
-                    Ok(n) if n == 0 => {
-                        warn!("Detected stdin was closed, shutting down");
-                        break;
-                    }
-                    Ok(_) => (),
+            let stdin = io::stdin();
+            for line in stdin.lock().lines() {
+                let line = match line {
+                    Ok(line) => line,
                     Err(err) => {
                         warn!("Failed reading from stdin, shutting down: {:#}", err);
                         break;
                     }
+                };
+                let result = serde_json::from_str::<Args>(&line)
+                    .context("Failed to parse json")
+                    .and_then(|mut args| {
+                        args.child_process = true;
+                        reloader.reload(args)
+                    });
+                if let Err(err) = result {
+                    error!("Failed to reload config: {:#}", err);
                 }
             }
+            warn!("Detected stdin was closed, shutting down");
             tx.send(()).ok();
         });
+    } else {
+        #[cfg(unix)]
+        reload_on_sighup(reloader)?;
+        #[cfg(unix)]
+        if args.needs_child() {
+            shutdown_on_signal(tx.clone())?;
+        }
     }
 
     if let Some(data_dir) = args.data_dir.clone() {
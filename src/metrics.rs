@@ -0,0 +1,336 @@
+use crate::args::{self, Args};
+use crate::body::CountingBody;
+use crate::errors::*;
+use crate::server::Bind;
+use crate::tor;
+use actix_web::body::MessageBody;
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::{web, App, HttpResponse, HttpServer};
+use futures_util::future::{ready, LocalBoxFuture, Ready};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// The upper bounds of the latency histogram in seconds, the same as the prometheus defaults
+const BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+/// How often the httpd child process sends its counters to the parent
+pub const REPORT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The http counters of a process, in multi-process mode they're sent from the child to the parent
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Snapshot {
+    requests: BTreeMap<u16, u64>,
+    bytes_sent: u64,
+    /// Not cumulative, requests slower than the last bucket are only counted in `duration_count`
+    buckets: [u64; BUCKETS.len()],
+    duration_sum: f64,
+    duration_count: u64,
+}
+
+impl Snapshot {
+    const fn new() -> Snapshot {
+        Snapshot {
+            requests: BTreeMap::new(),
+            bytes_sent: 0,
+            buckets: [0; BUCKETS.len()],
+            duration_sum: 0.0,
+            duration_count: 0,
+        }
+    }
+
+    fn record(&mut self, status: u16, size: u64, duration: Duration) {
+        *self.requests.entry(status).or_default() += 1;
+        self.bytes_sent += size;
+
+        let secs = duration.as_secs_f64();
+        if let Some(bucket) = BUCKETS.iter().position(|le| secs <= *le) {
+            self.buckets[bucket] += 1;
+        }
+        self.duration_sum += secs;
+        self.duration_count += 1;
+    }
+}
+
+pub struct Metrics {
+    http: Mutex<Snapshot>,
+}
+
+/// Metrics are collected for the whole process, like the default registry of other prometheus
+/// clients
+pub static METRICS: Metrics = Metrics {
+    http: Mutex::new(Snapshot::new()),
+};
+
+impl Metrics {
+    fn record(&self, status: u16, size: u64, duration: Duration) {
+        self.http.lock().unwrap().record(status, size, duration);
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        self.http.lock().unwrap().clone()
+    }
+
+    /// The parent doesn't serve http itself in multi-process mode, it uses the counters of the
+    /// child instead
+    pub fn replace(&self, snapshot: Snapshot) {
+        *self.http.lock().unwrap() = snapshot;
+    }
+}
+
+fn render(http: &Snapshot, tor_bootstrap: Option<u8>, started: SystemTime) -> String {
+    let mut out = String::new();
+
+    out.push_str("# HELP narnia_http_requests_total Number of http requests by status code\n");
+    out.push_str("# TYPE narnia_http_requests_total counter\n");
+    for (status, count) in &http.requests {
+        writeln!(
+            out,
+            "narnia_http_requests_total{{status=\"{}\"}} {}",
+            status, count
+        )
+        .ok();
+    }
+
+    out.push_str("# HELP narnia_http_response_bytes_total Bytes sent in http response bodies\n");
+    out.push_str("# TYPE narnia_http_response_bytes_total counter\n");
+    writeln!(out, "narnia_http_response_bytes_total {}", http.bytes_sent).ok();
+
+    out.push_str(
+        "# HELP narnia_http_request_duration_seconds Time until the response was sent completely\n",
+    );
+    out.push_str("# TYPE narnia_http_request_duration_seconds histogram\n");
+    let mut cumulative = 0;
+    for (le, count) in BUCKETS.iter().zip(&http.buckets) {
+        cumulative += count;
+        writeln!(
+            out,
+            "narnia_http_request_duration_seconds_bucket{{le=\"{}\"}} {}",
+            le, cumulative
+        )
+        .ok();
+    }
+    writeln!(
+        out,
+        "narnia_http_request_duration_seconds_bucket{{le=\"+Inf\"}} {}",
+        http.duration_count
+    )
+    .ok();
+    writeln!(
+        out,
+        "narnia_http_request_duration_seconds_sum {}",
+        http.duration_sum
+    )
+    .ok();
+    writeln!(
+        out,
+        "narnia_http_request_duration_seconds_count {}",
+        http.duration_count
+    )
+    .ok();
+
+    if let Some(percent) = tor_bootstrap {
+        out.push_str("# HELP narnia_tor_bootstrap_percent How far Tor has bootstrapped, the hidden service is reachable at 100\n");
+        out.push_str("# TYPE narnia_tor_bootstrap_percent gauge\n");
+        writeln!(out, "narnia_tor_bootstrap_percent {}", percent).ok();
+    }
+
+    let started = started
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    out.push_str(
+        "# HELP process_start_time_seconds Start time of the process since unix epoch in seconds\n",
+    );
+    out.push_str("# TYPE process_start_time_seconds gauge\n");
+    writeln!(out, "process_start_time_seconds {}", started).ok();
+
+    out
+}
+
+/// Serves `/metrics` on its own address, so metrics are never reachable through the hidden service
+pub struct Exporter {
+    bind: Bind,
+    tor_control: Option<PathBuf>,
+    started: SystemTime,
+}
+
+impl Exporter {
+    /// Needs to be called before privileges are dropped, so the address can be bound
+    pub fn setup(args: &Args) -> Result<Option<Exporter>> {
+        let addr = if let Some(addr) = &args.metrics_bind {
+            addr
+        } else {
+            return Ok(None);
+        };
+        if args.bind.as_ref() == Some(addr) {
+            bail!("Metrics need to be served on a different address than the http server");
+        }
+
+        let bind = Bind::listen(args::parse_bind_addr(addr)).context("Failed to bind metrics")?;
+        let tor_control = if cfg!(unix) {
+            args.data_dir.as_deref().map(tor::control_socket)
+        } else {
+            None
+        };
+        Ok(Some(Exporter {
+            bind,
+            tor_control,
+            started: SystemTime::now(),
+        }))
+    }
+
+    pub fn background(self) {
+        thread::spawn(move || {
+            if let Err(err) = serve(self) {
+                error!("Metrics thread has terminated: {:#}", err);
+            }
+        });
+    }
+}
+
+struct State {
+    tor_control: Option<PathBuf>,
+    started: SystemTime,
+}
+
+async fn metrics(state: web::Data<State>) -> HttpResponse {
+    let tor_bootstrap = if let Some(path) = state.tor_control.clone() {
+        Some(tor::query_bootstrap(path).await)
+    } else {
+        None
+    };
+
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(render(&METRICS.snapshot(), tor_bootstrap, state.started))
+}
+
+#[actix_web::main]
+async fn serve(exporter: Exporter) -> Result<()> {
+    let state = web::Data::new(State {
+        tor_control: exporter.tor_control,
+        started: exporter.started,
+    });
+    let server = HttpServer::new(move || {
+        App::new()
+            .app_data(state.clone())
+            .route("/metrics", web::get().to(metrics))
+    })
+    .workers(1)
+    // shutting down is handled by the httpd server, or by the default signal handlers
+    .disable_signals();
+
+    let server = match exporter.bind {
+        Bind::Tcp(tcp) => server.listen(tcp),
+        #[cfg(unix)]
+        Bind::Unix(uds) => server.listen_uds(uds),
+    };
+    server
+        .context("Failed to setup metrics server")?
+        .run()
+        .await
+        .context("Failed to run metrics server")?;
+    Ok(())
+}
+
+/// Middleware that records requests of the main server, they are exported on `/metrics`
+pub struct RecordMetrics;
+
+impl<S, B> Transform<S, ServiceRequest> for RecordMetrics
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<CountingBody>;
+    type Error = actix_web::Error;
+    type Transform = RecordMetricsMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RecordMetricsMiddleware {
+            service: Rc::new(service),
+        }))
+    }
+}
+
+pub struct RecordMetricsMiddleware<S> {
+    service: Rc<S>,
+}
+
+impl<S, B> Service<ServiceRequest> for RecordMetricsMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<CountingBody>;
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+
+        Box::pin(async move {
+            let start = Instant::now();
+            let res = service.call(req).await?;
+            let status = res.status().as_u16();
+            Ok(res.map_body(move |_, body| {
+                CountingBody::new(body, move |size| {
+                    METRICS.record(status, size, start.elapsed())
+                })
+            }))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record() {
+        let mut snapshot = Snapshot::new();
+        snapshot.record(200, 100, Duration::from_millis(3));
+        snapshot.record(200, 50, Duration::from_millis(300));
+        snapshot.record(404, 10, Duration::from_secs(60));
+        assert_eq!(snapshot.requests.get(&200), Some(&2));
+        assert_eq!(snapshot.requests.get(&404), Some(&1));
+        assert_eq!(snapshot.bytes_sent, 160);
+        assert_eq!(snapshot.buckets, [1, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0]);
+        assert_eq!(snapshot.duration_count, 3);
+    }
+
+    #[test]
+    fn test_render() {
+        let mut snapshot = Snapshot::new();
+        snapshot.record(200, 100, Duration::from_millis(3));
+        snapshot.record(404, 10, Duration::from_millis(20));
+        let out = render(&snapshot, Some(85), UNIX_EPOCH + Duration::from_secs(1337));
+
+        assert!(out.contains("\nnarnia_http_requests_total{status=\"200\"} 1\n"));
+        assert!(out.contains("\nnarnia_http_requests_total{status=\"404\"} 1\n"));
+        assert!(out.contains("\nnarnia_http_response_bytes_total 110\n"));
+        assert!(out.contains("\nnarnia_http_request_duration_seconds_bucket{le=\"0.005\"} 1\n"));
+        assert!(out.contains("\nnarnia_http_request_duration_seconds_bucket{le=\"0.025\"} 2\n"));
+        assert!(out.contains("\nnarnia_http_request_duration_seconds_bucket{le=\"+Inf\"} 2\n"));
+        assert!(out.contains("\nnarnia_tor_bootstrap_percent 85\n"));
+        assert!(out.contains("\nprocess_start_time_seconds 1337\n"));
+    }
+
+    #[test]
+    fn test_snapshot_json() {
+        let mut snapshot = Snapshot::new();
+        snapshot.record(200, 100, Duration::from_millis(3));
+        let json = serde_json::to_string(&snapshot).unwrap();
+        assert_eq!(serde_json::from_str::<Snapshot>(&json).unwrap(), snapshot);
+    }
+}
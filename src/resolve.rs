@@ -0,0 +1,602 @@
+use crate::args::Args;
+use crate::bundle::{self, Bundle};
+use crate::errors::*;
+use serde::{Deserialize, Serialize};
+use std::ffi::{OsStr, OsString};
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+/// How many symlinks may be followed while resolving a single path
+#[cfg(unix)]
+const MAX_SYMLINKS: usize = 40;
+
+/// Hidden names that are always served, `--allow-hidden` adds to these
+const DEFAULT_HIDDEN_ALLOWLIST: &[&str] = &[".well-known"];
+
+#[derive(Debug, Clone, Copy, PartialEq, clap::ArgEnum, Serialize, Deserialize)]
+pub enum SymlinkPolicy {
+    /// Follow all symlinks, even if they point outside of the web root
+    Follow,
+    /// Follow symlinks as long as they stay within the web root
+    WithinRoot,
+    /// Never follow symlinks
+    Never,
+}
+
+#[derive(Debug)]
+pub struct DirEntry {
+    pub name: OsString,
+    pub len: u64,
+    pub is_dir: bool,
+    /// Symlinks are listed like the file or directory they point to
+    pub is_symlink: bool,
+    pub modified: Option<SystemTime>,
+}
+
+/// The parts of `fs::Metadata` we need, for files on disk and in bundles
+#[derive(Debug, Clone)]
+pub struct Metadata {
+    is_dir: bool,
+    len: u64,
+    modified: Option<SystemTime>,
+}
+
+impl Metadata {
+    pub fn new(is_dir: bool, len: u64, modified: Option<SystemTime>) -> Metadata {
+        Metadata {
+            is_dir,
+            len,
+            modified,
+        }
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.is_dir
+    }
+
+    /// Only regular files and directories are ever opened
+    pub fn is_file(&self) -> bool {
+        !self.is_dir
+    }
+
+    #[allow(clippy::len_without_is_empty)]
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn modified(&self) -> Option<SystemTime> {
+        self.modified
+    }
+}
+
+/// A file or directory that was opened by the resolver
+#[derive(Debug)]
+pub enum Node {
+    Fs(File),
+    Bundle(bundle::Handle),
+}
+
+impl Node {
+    pub fn metadata(&self) -> io::Result<Metadata> {
+        match self {
+            Node::Fs(file) => {
+                let md = file.metadata()?;
+                Ok(Metadata::new(md.is_dir(), md.len(), md.modified().ok()))
+            }
+            Node::Bundle(handle) => Ok(handle.metadata()),
+        }
+    }
+
+    pub fn into_reader(self) -> io::Result<Box<dyn Read + Send>> {
+        match self {
+            Node::Fs(file) => Ok(Box::new(file)),
+            Node::Bundle(handle) => handle.reader(0),
+        }
+    }
+}
+
+/// Opens files below the web root, enforcing the hidden file and symlink policies
+#[derive(Debug, Clone)]
+pub struct Resolver {
+    root: PathBuf,
+    symlinks: SymlinkPolicy,
+    serve_hidden: bool,
+    hidden_allowlist: Vec<OsString>,
+    /// If set, files are opened from this bundle instead of the directory
+    bundle: Option<Arc<Bundle>>,
+}
+
+fn denied(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::PermissionDenied, msg)
+}
+
+fn not_found() -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, "File not found")
+}
+
+impl Resolver {
+    pub fn new(args: &Args, root: PathBuf) -> Resolver {
+        Resolver {
+            root,
+            symlinks: args.symlinks,
+            serve_hidden: args.serve_hidden,
+            hidden_allowlist: DEFAULT_HIDDEN_ALLOWLIST
+                .iter()
+                .copied()
+                .chain(args.hidden_allowlist.iter().map(String::as_str))
+                .map(OsString::from)
+                .collect(),
+            bundle: None,
+        }
+    }
+
+    /// Serve files from a bundle, `root` is the path of the bundle
+    pub fn with_bundle(mut self, bundle: Bundle) -> Resolver {
+        self.bundle = Some(Arc::new(bundle));
+        self
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Read a file like `_headers` from the root of the web root, these are never served so the
+    /// hidden file and symlink policies don't apply
+    pub fn read_rules_file(&self, name: &str) -> io::Result<String> {
+        if let Some(bundle) = &self.bundle {
+            let mut buf = String::new();
+            bundle
+                .lookup(&[OsString::from(name)])
+                .ok_or_else(not_found)?
+                .reader(0)?
+                .read_to_string(&mut buf)?;
+            Ok(buf)
+        } else {
+            fs::read_to_string(self.root.join(name))
+        }
+    }
+
+    pub fn is_hidden(&self, name: &OsStr) -> bool {
+        !self.serve_hidden
+            && name.to_string_lossy().starts_with('.')
+            && !self.hidden_allowlist.iter().any(|allowed| allowed == name)
+    }
+
+    /// Open a path inside of the web root, only regular files and directories are returned
+    pub fn open(&self, path: &Path) -> io::Result<Node> {
+        let rel = path
+            .strip_prefix(&self.root)
+            .map_err(|_| denied("Path is outside of web root"))?;
+
+        let mut components = Vec::new();
+        for comp in rel.components() {
+            match comp {
+                Component::Normal(name) => {
+                    // hidden files are reported as missing so their existence isn't leaked
+                    if self.is_hidden(name) {
+                        return Err(not_found());
+                    }
+                    components.push(name.to_os_string());
+                }
+                _ => return Err(denied("Invalid path component")),
+            }
+        }
+
+        if let Some(bundle) = &self.bundle {
+            return bundle
+                .lookup(&components)
+                .map(Node::Bundle)
+                .ok_or_else(not_found);
+        }
+
+        let file = match self.open_beneath(components) {
+            Ok(file) => file,
+            // a path below a regular file doesn't exist, it's not a permission problem
+            Err(err) if err.kind() == io::ErrorKind::NotADirectory => return Err(not_found()),
+            Err(err) => return Err(err),
+        };
+
+        let file_type = file.metadata()?.file_type();
+        if file_type.is_file() || file_type.is_dir() {
+            Ok(Node::Fs(file))
+        } else {
+            Err(denied("Not a regular file or directory"))
+        }
+    }
+
+    pub fn is_file(&self, path: &Path) -> bool {
+        self.open(path)
+            .and_then(|file| file.metadata())
+            .map(|md| md.is_file())
+            .unwrap_or(false)
+    }
+
+    /// Walk the path one component at a time, relative to a file descriptor of the previous
+    /// directory. Symlinks are never followed by the kernel, so a concurrent rename or symlink
+    /// swap can't trick us into opening something outside of the web root. Symlink targets are
+    /// walked the same way, so they can't point into hidden directories either.
+    #[cfg(unix)]
+    fn open_beneath(&self, components: Vec<OsString>) -> io::Result<File> {
+        use nix::fcntl::AtFlags;
+        use nix::fcntl::{openat, readlinkat, OFlag};
+        use nix::sys::stat::{fstatat, Mode, SFlag};
+        use std::collections::VecDeque;
+        use std::os::unix::fs::MetadataExt;
+        use std::os::unix::io::{AsRawFd, FromRawFd};
+
+        let root = File::open(&self.root)?;
+        let root_id = {
+            let md = root.metadata()?;
+            (md.dev(), md.ino())
+        };
+        let mut stack = vec![root];
+        // the position of the web root in the stack, `None` while we're outside of it. Hidden
+        // files are only checked inside of the web root
+        let mut root_idx = Some(0);
+        let mut queue = VecDeque::from(components);
+        let mut symlinks = 0;
+
+        while let Some(name) = queue.pop_front() {
+            if name == ".." {
+                if stack.len() > 1 {
+                    stack.pop();
+                    if root_idx.is_some_and(|idx| idx >= stack.len()) {
+                        root_idx = None;
+                    }
+                    continue;
+                } else if self.symlinks != SymlinkPolicy::Follow {
+                    return Err(denied("Symlink points outside of web root"));
+                }
+                // leaving the web root, replace it with its parent directory
+                let fd = openat(
+                    stack[0].as_raw_fd(),
+                    "..",
+                    OFlag::O_RDONLY | OFlag::O_DIRECTORY | OFlag::O_CLOEXEC,
+                    Mode::empty(),
+                )?;
+                stack[0] = unsafe { File::from_raw_fd(fd) };
+                root_idx = None;
+                continue;
+            } else if name == "." {
+                continue;
+            } else if root_idx.is_some() && self.is_hidden(&name) {
+                return Err(not_found());
+            }
+
+            let dirfd = stack.last().unwrap().as_raw_fd();
+            let st = fstatat(dirfd, name.as_os_str(), AtFlags::AT_SYMLINK_NOFOLLOW)?;
+            if SFlag::from_bits_truncate(st.st_mode) & SFlag::S_IFMT == SFlag::S_IFLNK {
+                if self.symlinks == SymlinkPolicy::Never {
+                    return Err(denied("Following symlinks is disabled"));
+                }
+
+                symlinks += 1;
+                if symlinks > MAX_SYMLINKS {
+                    return Err(io::Error::from(nix::errno::Errno::ELOOP));
+                }
+
+                let target = PathBuf::from(readlinkat(dirfd, name.as_os_str())?);
+                let target = if target.is_absolute() {
+                    let root = fs::canonicalize(&self.root)?;
+                    stack.truncate(1);
+                    match target.strip_prefix(&root) {
+                        Ok(target) => {
+                            // the stack might have left the web root before
+                            stack[0] = File::open(&self.root)?;
+                            root_idx = Some(0);
+                            target.to_path_buf()
+                        }
+                        Err(_) if self.symlinks == SymlinkPolicy::Follow => {
+                            stack[0] = File::open("/")?;
+                            root_idx = None;
+                            target
+                        }
+                        Err(_) => return Err(denied("Symlink points outside of web root")),
+                    }
+                } else {
+                    target
+                };
+
+                for comp in target.components().rev() {
+                    match comp {
+                        Component::Normal(name) => queue.push_front(name.to_os_string()),
+                        Component::ParentDir => queue.push_front(OsString::from("..")),
+                        Component::CurDir | Component::RootDir => (),
+                        _ => return Err(denied("Invalid symlink")),
+                    }
+                }
+                continue;
+            }
+
+            // O_NOFOLLOW makes this fail if the file was replaced with a symlink in the meantime
+            let fd = openat(
+                dirfd,
+                name.as_os_str(),
+                OFlag::O_RDONLY | OFlag::O_NOFOLLOW | OFlag::O_NONBLOCK | OFlag::O_CLOEXEC,
+                Mode::empty(),
+            )?;
+            let file = unsafe { File::from_raw_fd(fd) };
+            if root_idx.is_none() {
+                // walking back into the web root from outside of it
+                let md = file.metadata()?;
+                if (md.dev(), md.ino()) == root_id {
+                    root_idx = Some(stack.len());
+                }
+            }
+            stack.push(file);
+        }
+
+        Ok(stack.pop().unwrap())
+    }
+
+    /// There's no openat on this platform, fall back to checking the canonical path
+    #[cfg(not(unix))]
+    fn open_beneath(&self, components: Vec<OsString>) -> io::Result<File> {
+        let mut path = self.root.clone();
+        for name in components {
+            path.push(name);
+            if self.symlinks == SymlinkPolicy::Never
+                && fs::symlink_metadata(&path)?.file_type().is_symlink()
+            {
+                return Err(denied("Following symlinks is disabled"));
+            }
+        }
+
+        let root = fs::canonicalize(&self.root)?;
+        let path = fs::canonicalize(&path)?;
+        if self.symlinks == SymlinkPolicy::Follow {
+            // hidden files are only checked inside of the web root
+            let hidden = path.strip_prefix(&root).is_ok_and(|path| {
+                path.components()
+                    .any(|comp| matches!(comp, Component::Normal(name) if self.is_hidden(name)))
+            });
+            if hidden {
+                return Err(not_found());
+            }
+        } else if !path.starts_with(&root) {
+            return Err(denied("Symlink points outside of web root"));
+        }
+        File::open(path)
+    }
+
+    /// List a directory that was previously opened with `open`, hidden files are skipped
+    pub fn read_dir(&self, dir: Node, path: &Path) -> io::Result<Vec<DirEntry>> {
+        match dir {
+            Node::Fs(dir) => self.read_dir_fs(dir, path),
+            Node::Bundle(handle) => {
+                let mut entries = handle.read_dir()?;
+                entries.retain(|entry| !self.is_hidden(&entry.name));
+                Ok(entries)
+            }
+        }
+    }
+
+    #[cfg(unix)]
+    fn read_dir_fs(&self, dir: File, path: &Path) -> io::Result<Vec<DirEntry>> {
+        use nix::dir::Dir;
+        use nix::fcntl::AtFlags;
+        use nix::sys::stat::{fstatat, SFlag};
+        use std::convert::TryFrom;
+        use std::os::unix::ffi::OsStrExt;
+        use std::os::unix::io::{AsRawFd, IntoRawFd};
+        use std::time::Duration;
+
+        let mut dir = Dir::from_fd(dir.into_raw_fd())?;
+        let dirfd = dir.as_raw_fd();
+
+        let mut entries = Vec::new();
+        for entry in dir.iter() {
+            let entry = entry?;
+            let name = OsStr::from_bytes(entry.file_name().to_bytes());
+            if name == "." || name == ".." || self.is_hidden(name) {
+                continue;
+            }
+
+            let st = fstatat(dirfd, entry.file_name(), AtFlags::AT_SYMLINK_NOFOLLOW)?;
+            let file_type = SFlag::from_bits_truncate(st.st_mode) & SFlag::S_IFMT;
+            if file_type == SFlag::S_IFLNK {
+                // symlinks that can't be served with the current policy aren't listed
+                match self.open(&path.join(name)).and_then(|node| node.metadata()) {
+                    Ok(md) => entries.push(DirEntry {
+                        name: name.to_os_string(),
+                        len: md.len(),
+                        is_dir: md.is_dir(),
+                        is_symlink: true,
+                        modified: md.modified(),
+                    }),
+                    Err(err) => debug!("Not listing symlink({:?}): {:#}", name, err),
+                }
+                continue;
+            }
+
+            entries.push(DirEntry {
+                name: name.to_os_string(),
+                len: st.st_size as u64,
+                is_dir: file_type == SFlag::S_IFDIR,
+                is_symlink: false,
+                modified: u64::try_from(st.st_mtime)
+                    .ok()
+                    .map(|secs| SystemTime::UNIX_EPOCH + Duration::from_secs(secs)),
+            });
+        }
+        Ok(entries)
+    }
+
+    #[cfg(not(unix))]
+    fn read_dir_fs(&self, _dir: File, path: &Path) -> io::Result<Vec<DirEntry>> {
+        let mut entries = Vec::new();
+        for entry in fs::read_dir(path)? {
+            let entry = entry?;
+            let name = entry.file_name();
+            if self.is_hidden(&name) {
+                continue;
+            }
+
+            let md = entry.metadata()?;
+            entries.push(DirEntry {
+                name,
+                len: md.len(),
+                is_dir: md.is_dir(),
+                is_symlink: entry.file_type()?.is_symlink(),
+                modified: md.modified().ok(),
+            });
+        }
+        Ok(entries)
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use clap::Parser;
+    use std::os::unix::fs::symlink;
+
+    fn setup(symlinks: &str) -> (tempfile::TempDir, Resolver) {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("www");
+        fs::create_dir_all(root.join("static")).unwrap();
+        fs::create_dir_all(root.join(".git")).unwrap();
+        fs::write(root.join("static/a.css"), "a").unwrap();
+        fs::write(root.join(".git/config"), "secret").unwrap();
+        fs::write(dir.path().join("outside.txt"), "outside").unwrap();
+        symlink("static/a.css", root.join("inside")).unwrap();
+        symlink("../outside.txt", root.join("outside")).unwrap();
+        symlink(".git/config", root.join("git")).unwrap();
+        symlink(".git", root.join("public")).unwrap();
+        symlink(
+            fs::canonicalize(&root).unwrap().join(".git"),
+            root.join("abs"),
+        )
+        .unwrap();
+        symlink("static", root.join("linked")).unwrap();
+        // leaves the web root, then follows an absolute symlink back into it
+        fs::create_dir_all(dir.path().join("elsewhere/.local")).unwrap();
+        fs::write(dir.path().join("elsewhere/.local/data.txt"), "data").unwrap();
+        symlink(
+            fs::canonicalize(&root).unwrap().join("static"),
+            dir.path().join("elsewhere/back"),
+        )
+        .unwrap();
+        symlink("../elsewhere/back/a.css", root.join("roundtrip")).unwrap();
+        symlink("../elsewhere/.local/data.txt", root.join("dotted")).unwrap();
+        symlink("../www/.git/config", root.join("reenter")).unwrap();
+
+        let args = Args::parse_from(["narnia", "--symlinks", symlinks]);
+        let resolver = Resolver::new(&args, root);
+        (dir, resolver)
+    }
+
+    fn list(resolver: &Resolver) -> Vec<(String, bool, bool)> {
+        let root = resolver.root.clone();
+        let dir = resolver.open(&root).unwrap();
+        let mut entries = resolver
+            .read_dir(dir, &root)
+            .unwrap()
+            .into_iter()
+            .map(|entry| {
+                let name = entry.name.into_string().unwrap();
+                (name, entry.is_dir, entry.is_symlink)
+            })
+            .collect::<Vec<_>>();
+        entries.sort();
+        entries
+    }
+
+    fn open(resolver: &Resolver, path: &str) -> io::ErrorKind {
+        match resolver.open(&resolver.root.join(path)) {
+            Ok(_) => io::ErrorKind::Other,
+            Err(err) => err.kind(),
+        }
+    }
+
+    #[test]
+    fn test_within_root() {
+        let (_dir, resolver) = setup("within-root");
+        assert!(resolver.is_file(&resolver.root.join("static/a.css")));
+        assert!(resolver.is_file(&resolver.root.join("inside")));
+        assert_eq!(open(&resolver, "outside"), io::ErrorKind::PermissionDenied);
+        assert_eq!(open(&resolver, ".git/config"), io::ErrorKind::NotFound);
+        assert_eq!(open(&resolver, "git"), io::ErrorKind::NotFound);
+        assert_eq!(open(&resolver, "public/config"), io::ErrorKind::NotFound);
+        assert_eq!(open(&resolver, "abs/config"), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn test_never_follow() {
+        let (_dir, resolver) = setup("never");
+        assert!(resolver.is_file(&resolver.root.join("static/a.css")));
+        assert_eq!(open(&resolver, "inside"), io::ErrorKind::PermissionDenied);
+        assert_eq!(open(&resolver, "outside"), io::ErrorKind::PermissionDenied);
+    }
+
+    #[test]
+    fn test_follow() {
+        let (_dir, resolver) = setup("follow");
+        assert!(resolver.is_file(&resolver.root.join("inside")));
+        assert!(resolver.is_file(&resolver.root.join("outside")));
+        assert_eq!(open(&resolver, ".git/config"), io::ErrorKind::NotFound);
+        assert_eq!(open(&resolver, "git"), io::ErrorKind::NotFound);
+        assert_eq!(open(&resolver, "public/config"), io::ErrorKind::NotFound);
+        assert_eq!(open(&resolver, "abs/config"), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn test_follow_outside_and_back() {
+        let (_dir, resolver) = setup("follow");
+        let mut file = match resolver.open(&resolver.root.join("roundtrip")).unwrap() {
+            Node::Fs(file) => file,
+            Node::Bundle(_) => unreachable!(),
+        };
+        let mut content = String::new();
+        file.read_to_string(&mut content).unwrap();
+        assert_eq!(content, "a");
+        // hidden names outside of the web root are fine, but not after walking back into it
+        assert!(resolver.is_file(&resolver.root.join("dotted")));
+        assert_eq!(open(&resolver, "reenter"), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn test_allow_hidden_extends_default() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir_all(dir.path().join(".well-known")).unwrap();
+        fs::create_dir_all(dir.path().join(".git")).unwrap();
+        fs::write(dir.path().join(".well-known/security.txt"), "a").unwrap();
+        fs::write(dir.path().join(".git/config"), "b").unwrap();
+
+        let args = Args::parse_from(["narnia"]);
+        let resolver = Resolver::new(&args, dir.path().to_path_buf());
+        assert!(resolver.is_file(&dir.path().join(".well-known/security.txt")));
+        assert!(!resolver.is_file(&dir.path().join(".git/config")));
+
+        let args = Args::parse_from(["narnia", "--allow-hidden", ".git"]);
+        let resolver = Resolver::new(&args, dir.path().to_path_buf());
+        assert!(resolver.is_file(&dir.path().join(".well-known/security.txt")));
+        assert!(resolver.is_file(&dir.path().join(".git/config")));
+    }
+
+    #[test]
+    fn test_read_dir_symlinks() {
+        let (_dir, resolver) = setup("within-root");
+        assert_eq!(
+            list(&resolver),
+            vec![
+                ("inside".to_string(), false, true),
+                ("linked".to_string(), true, true),
+                ("static".to_string(), true, false),
+            ]
+        );
+
+        let (_dir, resolver) = setup("never");
+        assert_eq!(list(&resolver), vec![("static".to_string(), true, false)]);
+    }
+
+    #[test]
+    fn test_not_a_directory() {
+        let (_dir, resolver) = setup("within-root");
+        assert_eq!(open(&resolver, "static/a.css/x"), io::ErrorKind::NotFound);
+        assert_eq!(open(&resolver, "inside/x"), io::ErrorKind::NotFound);
+    }
+}
@@ -0,0 +1,130 @@
+use crate::errors::*;
+use crate::resolve::Resolver;
+use crate::rules::pattern::Pattern;
+use actix_web::http::header::{HeaderMap, HeaderName, HeaderValue};
+use std::io;
+
+pub const FILENAME: &str = "_headers";
+
+#[derive(Debug)]
+struct HeaderRule {
+    pattern: Pattern,
+    headers: Vec<(HeaderName, HeaderValue)>,
+}
+
+/// Per-path response headers, configured with a `_headers` file in the web root
+///
+/// ```text
+/// # cache static assets for a year
+/// /static/*
+///   Cache-Control: public, max-age=31536000
+/// /files/*
+///   Content-Disposition: attachment
+/// ```
+#[derive(Debug, Default)]
+pub struct HeaderRules {
+    rules: Vec<HeaderRule>,
+}
+
+impl HeaderRules {
+    pub fn load(resolver: &Resolver) -> Result<HeaderRules> {
+        let path = resolver.root().join(FILENAME);
+        match resolver.read_rules_file(FILENAME) {
+            Ok(buf) => {
+                let rules =
+                    Self::parse(&buf).with_context(|| anyhow!("Failed to parse {:?}", path))?;
+                debug!("Loaded {} header rules from {:?}", rules.rules.len(), path);
+                Ok(rules)
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(HeaderRules::default()),
+            Err(err) => Err(Error::from(err)).with_context(|| anyhow!("Failed to read {:?}", path)),
+        }
+    }
+
+    pub fn parse(buf: &str) -> Result<HeaderRules> {
+        let mut rules = Vec::<HeaderRule>::new();
+
+        for (idx, line) in buf.lines().enumerate() {
+            let trimmed = line.trim();
+            if trimmed.is_empty() || trimmed.starts_with('#') {
+                continue;
+            }
+
+            if line.starts_with(char::is_whitespace) {
+                let rule = rules
+                    .last_mut()
+                    .with_context(|| anyhow!("Line {}: Header without a path", idx + 1))?;
+                let (name, value) = trimmed
+                    .split_once(':')
+                    .with_context(|| anyhow!("Line {}: Header is missing a colon", idx + 1))?;
+                let name = HeaderName::from_bytes(name.trim().as_bytes())
+                    .with_context(|| anyhow!("Line {}: Invalid header name", idx + 1))?;
+                let value = HeaderValue::from_str(value.trim())
+                    .with_context(|| anyhow!("Line {}: Invalid header value", idx + 1))?;
+                rule.headers.push((name, value));
+            } else {
+                let pattern = trimmed
+                    .parse()
+                    .with_context(|| anyhow!("Line {}: Invalid path", idx + 1))?;
+                rules.push(HeaderRule {
+                    pattern,
+                    headers: Vec::new(),
+                });
+            }
+        }
+
+        Ok(HeaderRules { rules })
+    }
+
+    /// Add the headers of all rules matching the path, later rules take precedence
+    pub fn apply(&self, path: &str, headers: &mut HeaderMap) {
+        for rule in &self.rules {
+            if rule.pattern.matches(path).is_some() {
+                for (name, value) in &rule.headers {
+                    headers.insert(name.clone(), value.clone());
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::header;
+
+    #[test]
+    fn test_apply_headers() {
+        let rules = HeaderRules::parse(
+            r#"
+# comment
+/static/*
+  Cache-Control: public, max-age=31536000
+  X-Frame-Options: DENY
+
+/static/private/*
+  Cache-Control: no-store
+"#,
+        )
+        .unwrap();
+
+        let mut headers = HeaderMap::new();
+        rules.apply("/static/private/a.txt", &mut headers);
+        assert_eq!(headers.get(header::CACHE_CONTROL).unwrap(), "no-store");
+        assert_eq!(headers.get(header::X_FRAME_OPTIONS).unwrap(), "DENY");
+
+        let mut headers = HeaderMap::new();
+        rules.apply("/index.html", &mut headers);
+        assert!(headers.is_empty());
+    }
+
+    #[test]
+    fn test_header_without_path() {
+        assert!(HeaderRules::parse("  Cache-Control: no-store\n").is_err());
+    }
+
+    #[test]
+    fn test_header_without_colon() {
+        assert!(HeaderRules::parse("/*\n  Cache-Control no-store\n").is_err());
+    }
+}
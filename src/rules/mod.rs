@@ -0,0 +1,12 @@
+pub mod headers;
+pub mod pattern;
+pub mod redirects;
+
+use std::path::Path;
+
+/// Rule files in the web root that configure narnia and are never served
+pub const RULES_FILES: &[&str] = &[headers::FILENAME, redirects::FILENAME];
+
+pub fn is_rules_file(req_path: &Path) -> bool {
+    RULES_FILES.iter().any(|name| req_path == Path::new(name))
+}
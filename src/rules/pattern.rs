@@ -0,0 +1,149 @@
+use crate::errors::*;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Segment {
+    Literal(String),
+    Placeholder(String),
+    Splat,
+}
+
+/// A url path pattern like `/static/*` or `/blog/:year/:slug`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Pattern {
+    segments: Vec<Segment>,
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct Captures(Vec<(String, String)>);
+
+impl Captures {
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// Split a path into non-empty segments and their offsets
+fn split_segments(path: &str) -> impl Iterator<Item = (usize, &str)> {
+    let mut offset = 0;
+    path.split('/')
+        .map(move |part| {
+            let start = offset;
+            offset += part.len() + 1;
+            (start, part)
+        })
+        .filter(|(_, part)| !part.is_empty())
+}
+
+impl FromStr for Pattern {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Pattern> {
+        if !s.starts_with('/') {
+            bail!("Pattern needs to start with a slash: {:?}", s);
+        }
+
+        let mut segments = Vec::new();
+        let mut iter = s.split('/').filter(|x| !x.is_empty()).peekable();
+        while let Some(segment) = iter.next() {
+            if segment == "*" {
+                if iter.peek().is_some() {
+                    bail!("Splat is only allowed at the end of a pattern: {:?}", s);
+                }
+                segments.push(Segment::Splat);
+            } else if let Some(name) = segment.strip_prefix(':') {
+                if name.is_empty() || name == "splat" {
+                    bail!("Invalid placeholder name: {:?}", segment);
+                }
+                segments.push(Segment::Placeholder(name.to_string()));
+            } else if segment.contains('*') {
+                bail!("Splat needs to be a full path segment: {:?}", s);
+            } else {
+                segments.push(Segment::Literal(segment.to_string()));
+            }
+        }
+
+        Ok(Pattern { segments })
+    }
+}
+
+impl Pattern {
+    /// Match a url path, returns the captured placeholders on success
+    pub fn matches(&self, path: &str) -> Option<Captures> {
+        let mut parts = split_segments(path);
+
+        let mut captures = Captures::default();
+        for segment in &self.segments {
+            match segment {
+                Segment::Literal(literal) => {
+                    let (_, part) = parts.next()?;
+                    if part != literal {
+                        return None;
+                    }
+                }
+                Segment::Placeholder(name) => {
+                    let (_, part) = parts.next()?;
+                    captures.0.push((name.clone(), part.to_string()));
+                }
+                Segment::Splat => {
+                    let rest = parts.next().map_or("", |(idx, _)| &path[idx..]);
+                    captures.0.push(("splat".to_string(), rest.to_string()));
+                    return Some(captures);
+                }
+            }
+        }
+
+        if parts.next().is_some() {
+            None
+        } else {
+            Some(captures)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_case::test_case;
+
+    #[test_case("/", "/"; "root")]
+    #[test_case("/about", "/about"; "literal")]
+    #[test_case("/about", "/about/"; "literal trailing slash")]
+    #[test_case("/static/*", "/static/"; "splat empty")]
+    #[test_case("/static/*", "/static/css/main.css"; "splat nested")]
+    #[test_case("/*", "/index.html"; "splat root")]
+    #[test_case("/blog/:year/:slug", "/blog/2021/hello"; "placeholders")]
+    #[test_case("/a//b", "/a/b"; "multiple slash")]
+    fn test_pattern_matches(pattern: &str, path: &str) {
+        let pattern = pattern.parse::<Pattern>().unwrap();
+        assert!(pattern.matches(path).is_some());
+    }
+
+    #[test_case("/", "/index.html"; "root")]
+    #[test_case("/about", "/about/team"; "literal prefix")]
+    #[test_case("/static/*", "/assets/main.css"; "splat different dir")]
+    #[test_case("/blog/:year/:slug", "/blog/2021"; "missing placeholder")]
+    fn test_pattern_no_match(pattern: &str, path: &str) {
+        let pattern = pattern.parse::<Pattern>().unwrap();
+        assert!(pattern.matches(path).is_none());
+    }
+
+    #[test_case("static/*"; "relative")]
+    #[test_case("/*/foo"; "splat in the middle")]
+    #[test_case("/foo*"; "partial splat")]
+    #[test_case("/:"; "empty placeholder")]
+    fn test_invalid_pattern(pattern: &str) {
+        assert!(pattern.parse::<Pattern>().is_err());
+    }
+
+    #[test]
+    fn test_pattern_captures() {
+        let pattern = "/blog/:year/*".parse::<Pattern>().unwrap();
+        let captures = pattern.matches("/blog/2021/hello/world/").unwrap();
+        assert_eq!(captures.get("year"), Some("2021"));
+        assert_eq!(captures.get("splat"), Some("hello/world/"));
+    }
+}
@@ -0,0 +1,207 @@
+use crate::errors::*;
+use crate::resolve::Resolver;
+use crate::rules::pattern::{Captures, Pattern};
+use actix_web::http::header::HeaderValue;
+use actix_web::http::StatusCode;
+use percent_encoding::{utf8_percent_encode, AsciiSet, CONTROLS};
+use std::io;
+
+pub const FILENAME: &str = "_redirects";
+
+/// Characters that need to be escaped when inserting a placeholder into a url path
+const PATH: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'%')
+    .add(b'<')
+    .add(b'>')
+    .add(b'?')
+    .add(b'\\')
+    .add(b'`')
+    .add(b'{')
+    .add(b'}');
+
+#[derive(Debug, PartialEq)]
+pub enum Action {
+    Redirect(StatusCode, String),
+    Rewrite(String),
+}
+
+#[derive(Debug)]
+struct RedirectRule {
+    from: Pattern,
+    to: String,
+    status: StatusCode,
+}
+
+/// Redirects and rewrites, configured with a `_redirects` file in the web root
+///
+/// ```text
+/// # moved permanently, the status code defaults to 301
+/// /old-page /new-page
+/// /blog/:year/:slug /posts/:year/:slug 308
+/// /news/* http://example.onion/news/:splat 302
+/// # serve a different file without redirecting
+/// /app/* /app/index.html 200
+/// ```
+#[derive(Debug, Default)]
+pub struct RedirectRules {
+    rules: Vec<RedirectRule>,
+}
+
+impl RedirectRules {
+    pub fn load(resolver: &Resolver) -> Result<RedirectRules> {
+        let path = resolver.root().join(FILENAME);
+        match resolver.read_rules_file(FILENAME) {
+            Ok(buf) => {
+                let rules =
+                    Self::parse(&buf).with_context(|| anyhow!("Failed to parse {:?}", path))?;
+                debug!(
+                    "Loaded {} redirect rules from {:?}",
+                    rules.rules.len(),
+                    path
+                );
+                Ok(rules)
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(RedirectRules::default()),
+            Err(err) => Err(Error::from(err)).with_context(|| anyhow!("Failed to read {:?}", path)),
+        }
+    }
+
+    pub fn parse(buf: &str) -> Result<RedirectRules> {
+        let mut rules = Vec::new();
+
+        for (idx, line) in buf.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let rule = Self::parse_rule(line)
+                .with_context(|| anyhow!("Line {}: Invalid rule", idx + 1))?;
+            rules.push(rule);
+        }
+
+        Ok(RedirectRules { rules })
+    }
+
+    fn parse_rule(line: &str) -> Result<RedirectRule> {
+        let mut fields = line.split_whitespace();
+        let from = fields.next().context("Missing source path")?.parse()?;
+        let to = fields.next().context("Missing destination")?;
+        let status = if let Some(status) = fields.next() {
+            status
+                .parse::<u16>()
+                .ok()
+                .and_then(|status| StatusCode::from_u16(status).ok())
+                .with_context(|| anyhow!("Invalid status code: {:?}", status))?
+        } else {
+            StatusCode::MOVED_PERMANENTLY
+        };
+        if let Some(field) = fields.next() {
+            bail!("Unexpected field: {:?}", field);
+        }
+
+        match status {
+            StatusCode::OK => {
+                if !to.starts_with('/') {
+                    bail!("Rewrites need to point to a local path: {:?}", to);
+                }
+            }
+            StatusCode::MOVED_PERMANENTLY
+            | StatusCode::FOUND
+            | StatusCode::TEMPORARY_REDIRECT
+            | StatusCode::PERMANENT_REDIRECT => {
+                if !to.starts_with('/') && !to.contains("://") {
+                    bail!("Redirects need to point to a path or url: {:?}", to);
+                }
+                HeaderValue::from_str(to).context("Destination is not a valid header value")?;
+            }
+            _ => bail!("Unsupported status code: {}", status.as_u16()),
+        }
+
+        Ok(RedirectRule {
+            from,
+            to: to.to_string(),
+            status,
+        })
+    }
+
+    /// Find the first rule matching the path
+    pub fn resolve(&self, path: &str) -> Option<Action> {
+        self.rules.iter().find_map(|rule| {
+            let captures = rule.from.matches(path)?;
+            if rule.status == StatusCode::OK {
+                let to = expand(&rule.to, &captures, |value| value.to_string());
+                Some(Action::Rewrite(to))
+            } else {
+                let to = expand(&rule.to, &captures, |value| {
+                    utf8_percent_encode(value, PATH).to_string()
+                });
+                // browsers treat these as a different host
+                if to.starts_with("//") || to.starts_with("/\\") {
+                    debug!("Refusing redirect to other host: {:?}", to);
+                    return None;
+                }
+                Some(Action::Redirect(rule.status, to))
+            }
+        })
+    }
+}
+
+/// Replace `:name` and `:splat` segments in the destination with the captured values
+fn expand<F: Fn(&str) -> String>(to: &str, captures: &Captures, encode: F) -> String {
+    to.split('/')
+        .map(|segment| {
+            segment
+                .strip_prefix(':')
+                .and_then(|name| captures.get(name))
+                .map(&encode)
+                .unwrap_or_else(|| segment.to_string())
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_case::test_case;
+
+    const RULES: &str = r#"
+# comment
+/old /new
+/blog/:year/:slug /posts/:year/:slug 308
+/news/* http://example.onion/news/:splat 302
+/app/* /app/index.html 200
+/files/* /downloads/:splat 200
+/moved/* /:splat
+/user/:name /:name
+/empty/* /:splat/evil.com
+"#;
+
+    #[test_case("/old", Some(Action::Redirect(StatusCode::MOVED_PERMANENTLY, "/new".into())); "default status")]
+    #[test_case("/blog/2021/hello", Some(Action::Redirect(StatusCode::PERMANENT_REDIRECT, "/posts/2021/hello".into())); "placeholders")]
+    #[test_case("/news/a/b c", Some(Action::Redirect(StatusCode::FOUND, "http://example.onion/news/a/b%20c".into())); "splat")]
+    #[test_case("/moved/\\evil.com", Some(Action::Redirect(StatusCode::MOVED_PERMANENTLY, "/%5Cevil.com".into())); "splat with backslash")]
+    #[test_case("/moved//evil.com", Some(Action::Redirect(StatusCode::MOVED_PERMANENTLY, "/evil.com".into())); "splat with double slash")]
+    #[test_case("/empty/", None; "redirect to other host")]
+    #[test_case("/user/\\evil.com", Some(Action::Redirect(StatusCode::MOVED_PERMANENTLY, "/%5Cevil.com".into())); "placeholder with backslash")]
+    #[test_case("/moved/a b", Some(Action::Redirect(StatusCode::MOVED_PERMANENTLY, "/a%20b".into())); "splat at start")]
+    #[test_case("/app/settings", Some(Action::Rewrite("/app/index.html".into())); "rewrite")]
+    #[test_case("/files/a b.txt", Some(Action::Rewrite("/downloads/a b.txt".into())); "rewrite splat")]
+    #[test_case("/index.html", None; "no match")]
+    fn test_resolve_redirects(path: &str, action: Option<Action>) {
+        let rules = RedirectRules::parse(RULES).unwrap();
+        assert_eq!(rules.resolve(path), action);
+    }
+
+    #[test_case("/old"; "missing destination")]
+    #[test_case("/old /new 404"; "unsupported status")]
+    #[test_case("/old /new 301 foo"; "trailing field")]
+    #[test_case("/old http://example.onion/ 200"; "rewrite to url")]
+    #[test_case("/old new"; "relative destination")]
+    fn test_invalid_redirects(rule: &str) {
+        assert!(RedirectRules::parse(rule).is_err());
+    }
+}
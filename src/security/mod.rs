@@ -1,3 +1,6 @@
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+
 #[cfg_attr(unix, path = "unix.rs")]
 #[cfg_attr(windows, path = "windows.rs")]
 mod os;
@@ -6,3 +9,12 @@ pub use self::os::*;
 
 #[cfg(target_os = "openbsd")]
 pub mod openbsd;
+
+/// Which restrictions have been applied to a process, this is reported in `/status`
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Sandbox {
+    pub chroot: Option<PathBuf>,
+    pub user: Option<String>,
+    pub caps_dropped: bool,
+    pub pledge: Option<String>,
+}
@@ -1,25 +1,96 @@
 use crate::args::Args;
+use crate::deploy;
 use crate::errors::*;
+use crate::signing;
 use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
 
 pub fn unveil(args: &Args) -> Result<()> {
     if let Some(web_root) = &args.web_root {
         unveil::unveil(web_root, "r")
             .map_err(|e| anyhow!("Failed to unveil {:?}: {:?}", web_root, e))?;
+        // releases are swapped on reload, so all of them need to be accessible
+        if let Some(releases) = deploy::releases_dir(Path::new(web_root)) {
+            if releases.is_dir() {
+                unveil::unveil(releases.as_os_str().as_bytes(), "r")
+                    .map_err(|e| anyhow!("Failed to unveil {:?}: {:?}", releases, e))?;
+            }
+        }
+        if args.bundle_key.is_some() {
+            let signature = signing::signature_path(Path::new(web_root));
+            unveil::unveil(signature.as_os_str().as_bytes(), "r")
+                .map_err(|e| anyhow!("Failed to unveil {:?}: {:?}", signature, e))?;
+        }
+    }
+    for path in [
+        // read again on reload
+        &args.config,
+        &args.htpasswd,
+        &args.auth_tokens,
+        &args.bundle_key,
+        &args.listing_template,
+        &args.tls_cert,
+        &args.tls_key,
+    ]
+    .iter()
+    .copied()
+    .flatten()
+    {
+        unveil::unveil(path.as_os_str().as_bytes(), "r")
+            .map_err(|e| anyhow!("Failed to unveil {:?}: {:?}", path, e))?;
+    }
+    if let Some(upload_dir) = &args.upload_dir {
+        unveil::unveil(upload_dir.as_os_str().as_bytes(), "rwc")
+            .map_err(|e| anyhow!("Failed to unveil {:?}: {:?}", upload_dir, e))?;
+    }
+    if let Some(webdav_dir) = &args.webdav_dir {
+        unveil::unveil(webdav_dir, "rwc")
+            .map_err(|e| anyhow!("Failed to unveil {:?}: {:?}", webdav_dir, e))?;
+    }
+    // removed on shutdown
+    if let Some(pid_file) = &args.pid_file {
+        unveil::unveil(pid_file.as_os_str().as_bytes(), "c")
+            .map_err(|e| anyhow!("Failed to unveil {:?}: {:?}", pid_file, e))?;
+    }
+    if let Some(access_log) = &args.access_log {
+        if access_log.as_os_str() != "-" {
+            unveil::unveil(access_log.as_os_str().as_bytes(), "wc")
+                .map_err(|e| anyhow!("Failed to unveil {:?}: {:?}", access_log, e))?;
+        }
+    }
+    if args.acme_enabled() {
+        if let Some(acme_dir) = args.acme_dir() {
+            unveil::unveil(acme_dir.as_os_str().as_bytes(), "rwc")
+                .map_err(|e| anyhow!("Failed to unveil {:?}: {:?}", acme_dir, e))?;
+        }
+        // the ACME server is contacted over https
+        unveil::unveil("/etc/ssl", "r")
+            .map_err(|e| anyhow!("Failed to unveil /etc/ssl: {:?}", e))?;
     }
     if let Some(data_dir) = &args.data_dir {
         unveil::unveil(data_dir.as_os_str().as_bytes(), "rwc")
             .map_err(|e| anyhow!("Failed to unveil {:?}: {:?}", data_dir, e))?;
+        // Tor writes its logs into a pipe that is opened through /dev/fd
+        unveil::unveil("/dev/fd", "w").map_err(|e| anyhow!("Failed to unveil /dev/fd: {:?}", e))?;
     }
     unveil::unveil("", "").map_err(|e| anyhow!("Failed to finish unveil: {:?}", e))?;
     Ok(())
 }
 
-pub fn pledge(args: &Args) -> Result<()> {
+/// Returns the promises so they can be reported
+pub fn pledge(args: &Args) -> Result<String> {
     let mut pledge = String::from("stdio dns inet rpath unix");
     if args.data_dir.is_some() {
         pledge.push_str(" wpath cpath id flock");
+    } else if args.upload_dir.is_some()
+        || args.webdav_dir.is_some()
+        || args.access_log.is_some()
+        || args.acme_enabled()
+    {
+        pledge.push_str(" wpath cpath");
+    } else if args.pid_file.is_some() {
+        pledge.push_str(" cpath");
     }
     pledge::pledge(Some(pledge.as_str()), Some(""))?;
-    Ok(())
+    Ok(pledge)
 }
@@ -2,11 +2,14 @@ use crate::args::Args;
 use crate::errors::*;
 #[cfg(target_os = "openbsd")]
 use crate::security::openbsd;
+use crate::security::Sandbox;
 use nix::unistd::{Gid, Uid};
 use std::path::Path;
 use users::User;
 
-pub fn setup(args: &Args) -> Result<()> {
+pub fn setup(args: &Args) -> Result<Sandbox> {
+    let mut sandbox = Sandbox::default();
+
     let user = if let Some(user) = &args.user {
         let user = users::get_user_by_name(&user).context("Could not find user")?;
         Some(user)
@@ -18,23 +21,30 @@ pub fn setup(args: &Args) -> Result<()> {
         if let Some(path) = &args.chroot {
             chroot(path).with_context(|| anyhow!("Failed to chroot into: {:?}", path))?;
             info!("Successfully chrooted into {:?}", path);
+            sandbox.chroot = Some(path.clone());
         }
     }
 
     if let Some(user) = user {
         become_user(&user)?;
+        sandbox.user = Some(user.name().to_string_lossy().into_owned());
     }
 
     #[cfg(target_os = "openbsd")]
     openbsd::unveil(&args).context("Failed to setup unveil")?;
 
     #[cfg(target_os = "openbsd")]
-    openbsd::pledge(&args).context("Failed to pledge")?;
+    {
+        sandbox.pledge = Some(openbsd::pledge(&args).context("Failed to pledge")?);
+    }
 
     #[cfg(target_os = "linux")]
-    drop_caps()?;
+    {
+        drop_caps()?;
+        sandbox.caps_dropped = true;
+    }
 
-    Ok(())
+    Ok(sandbox)
 }
 
 fn chroot(path: &Path) -> Result<()> {
@@ -1,6 +1,7 @@
 use crate::args::Args;
 use crate::errors::*;
+use crate::security::Sandbox;
 
-pub fn setup(_args: &Args) -> Result<()> {
-    Ok(())
+pub fn setup(_args: &Args) -> Result<Sandbox> {
+    Ok(Sandbox::default())
 }
@@ -1,12 +1,16 @@
-use crate::args::Args;
+use crate::admin::STATUS;
+use crate::args::{self, Args};
 use crate::errors::*;
 use crate::httpd;
+use crate::metrics::{self, Snapshot, METRICS};
+use crate::security::Sandbox;
 #[cfg(unix)]
 use crate::utils;
 use libtor::TorAddress;
+use serde::{Deserialize, Serialize};
 use std::env;
 use std::fs;
-use std::io::Write;
+use std::io::{self, BufRead, BufReader, Read, Write};
 use std::net::TcpListener;
 #[cfg(unix)]
 use std::os::unix::net::UnixListener;
@@ -15,68 +19,220 @@ use std::sync::mpsc;
 use std::thread;
 
 pub enum ServerType {
-    Thread((Bind, String)),
-    Child((Child, ChildStdin)),
+    Thread((Bind, Option<Bind>, Option<String>, mpsc::Receiver<Args>)),
+    Child(Child),
+}
+
+/// Sends new arguments to the http server so it can reload its config
+pub enum Reloader {
+    Thread(mpsc::Sender<Args>),
+    Child(ChildStdin),
+}
+
+impl Reloader {
+    pub fn reload(&mut self, args: Args) -> Result<()> {
+        match self {
+            Reloader::Thread(tx) => tx
+                .send(args)
+                .map_err(|_| anyhow!("httpd thread has terminated")),
+            Reloader::Child(stdin) => {
+                let json = serde_json::to_string(&child_args(args))?;
+                stdin.write_all(json.as_bytes())?;
+                stdin.write_all(b"\n")?;
+                Ok(())
+            }
+        }
+    }
+}
+
+/// The arguments that are sent to the child process
+fn child_args(mut args: Args) -> Args {
+    args.child_process = false;
+    args.always_multi_process = false;
+    args.data_dir = None;
+    args.pid_file = None;
+    args
+}
+
+/// Send SIGHUP to the process in the pid file
+#[cfg(unix)]
+pub fn send_reload(args: &Args) -> Result<()> {
+    use nix::sys::signal::{kill, Signal};
+    use nix::unistd::Pid;
+
+    let path = args
+        .pid_file
+        .as_ref()
+        .context("Reloading requires --pid-file")?;
+    let pid =
+        fs::read_to_string(path).with_context(|| anyhow!("Failed to read pid file: {:?}", path))?;
+    let pid = pid
+        .trim()
+        .parse()
+        .with_context(|| anyhow!("Invalid pid file: {:?}", path))?;
+    kill(Pid::from_raw(pid), Signal::SIGHUP)
+        .with_context(|| anyhow!("Failed to send SIGHUP to process {}", pid))?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn send_reload(_args: &Args) -> Result<()> {
+    bail!("Reloading is only supported on unix")
+}
+
+/// Messages from the httpd child to the parent, sent as json lines on stdout
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Report {
+    /// The socket is bound and the child has been locked down
+    Ready(Sandbox),
+    Metrics(Snapshot),
+}
+
+/// The child only writes to stdout if the parent serves metrics or the admin endpoints
+pub fn reports_to_parent(args: &Args) -> bool {
+    args.metrics_bind.is_some() || args.admin_enabled()
+}
+
+fn send_report(report: &Report) -> Result<()> {
+    let json = serde_json::to_string(report)?;
+    let mut stdout = io::stdout().lock();
+    writeln!(stdout, "{}", json)?;
+    stdout.flush()?;
+    Ok(())
+}
+
+/// Tell the parent that the child is ready, the metrics are sent afterwards once per interval
+pub fn report_to_parent(args: &Args, sandbox: Sandbox) {
+    let metrics = args.metrics_bind.is_some();
+    thread::spawn(move || {
+        let mut report = Report::Ready(sandbox);
+        loop {
+            if let Err(err) = send_report(&report) {
+                warn!("Failed to send report to parent, stopping: {:#}", err);
+                break;
+            }
+            if !metrics {
+                break;
+            }
+            thread::sleep(metrics::REPORT_INTERVAL);
+            report = Report::Metrics(METRICS.snapshot());
+        }
+    });
+}
+
+/// Read the reports that are sent by `report_to_parent` in the child process
+fn receive_from_child<R: Read + Send + 'static>(stdout: R) {
+    thread::spawn(move || {
+        for line in BufReader::new(stdout).lines() {
+            let line = match line {
+                Ok(line) => line,
+                Err(err) => {
+                    warn!("Failed to read report from child: {:#}", err);
+                    break;
+                }
+            };
+            match serde_json::from_str(&line) {
+                Ok(Report::Ready(sandbox)) => STATUS.httpd_ready(sandbox),
+                Ok(Report::Metrics(snapshot)) => METRICS.replace(snapshot),
+                Err(err) => warn!("Received invalid report from child: {:#}", err),
+            }
+        }
+    });
 }
 
 pub struct Server {
     inner: ServerType,
+    reloader: Reloader,
     args: Args,
     tx: mpsc::Sender<()>,
 }
 
 impl Server {
-    pub fn setup(mut args: Args, tx: mpsc::Sender<()>) -> Result<Server> {
-        let inner = if args.needs_child() {
+    pub fn setup(args: Args, tx: mpsc::Sender<()>) -> Result<Server> {
+        let (inner, reloader) = if args.needs_child() {
             debug!("Setting up httpd child process");
-            args.child_process = false;
-            args.always_multi_process = false;
-            args.data_dir = None;
-            let json = serde_json::to_string(&args)?;
+            if args.acme_enabled() {
+                bail!("ACME isn't supported in multi-process mode, the httpd child can't write to the data directory");
+            }
+            let json = serde_json::to_string(&child_args(args.clone()))?;
 
             debug!("Spawning multi-process child");
             let exe = env::current_exe().context("Failed to get own path")?;
-            let mut cmd = Command::new(exe)
-                .args(&["-M"])
-                .stdin(Stdio::piped())
-                .spawn()
-                .context("Failed to spawn child")?;
+            let mut cmd = Command::new(exe);
+            cmd.args(["-M"]).stdin(Stdio::piped());
+            if reports_to_parent(&args) {
+                cmd.stdout(Stdio::piped());
+            }
+            let mut cmd = cmd.spawn().context("Failed to spawn child")?;
 
+            if let Some(stdout) = cmd.stdout.take() {
+                receive_from_child(stdout);
+            }
             let mut stdin = cmd.stdin.take().unwrap();
             stdin.write_all(json.as_bytes())?;
             stdin.write_all(b"\n")?;
             debug!("Sent instructions to child");
 
-            ServerType::Child((cmd, stdin))
+            (ServerType::Child(cmd), Reloader::Child(stdin))
         } else {
             debug!("Setting up httpd");
 
             let bind = Bind::setup(&args).context("Failed to bind socket")?;
-            let web_root = args
-                .web_root
-                .clone()
-                .context("Missing --web-root argument")?;
+            #[cfg(unix)]
+            if matches!(bind, Bind::Unix(_)) && args.tls_enabled() {
+                bail!("TLS is only supported on tcp sockets");
+            }
+            let redirect = if let Some(addr) = &args.http_redirect_bind {
+                if !args.tls_enabled() {
+                    bail!("Redirecting to https needs --tls-cert and --tls-key");
+                }
+                let bind = Bind::listen(args::parse_bind_addr(addr))
+                    .context("Failed to bind redirect socket")?;
+                Some(bind)
+            } else {
+                None
+            };
+            let web_root = httpd::web_root(&args)?;
 
-            ServerType::Thread((bind, web_root))
+            let (reload_tx, reload_rx) = mpsc::channel();
+            (
+                ServerType::Thread((bind, redirect, web_root, reload_rx)),
+                Reloader::Thread(reload_tx),
+            )
         };
-        Ok(Server { inner, args, tx })
+        Ok(Server {
+            inner,
+            reloader,
+            args,
+            tx,
+        })
     }
 
-    pub fn background(self) {
+    /// Run the server in the background, the returned reloader needs to be kept around, the child
+    /// process shuts down if it's dropped
+    pub fn background(self) -> Reloader {
+        let Server {
+            inner,
+            reloader,
+            args,
+            tx,
+        } = self;
         thread::spawn(move || {
-            match self.inner {
-                ServerType::Child((mut cmd, _)) => {
+            match inner {
+                ServerType::Child(mut cmd) => {
                     let status = cmd.wait();
                     error!("child process has exited: {:?}", status);
                 }
-                ServerType::Thread((bind, web_root)) => {
-                    if let Err(err) = httpd::run(self.args, bind, web_root) {
+                ServerType::Thread((bind, redirect, web_root, reload)) => {
+                    if let Err(err) = httpd::run(args, bind, redirect, web_root, reload) {
                         error!("httpd thread has terminated: {:#}", err);
                     }
                 }
             }
-            self.tx.send(()).ok();
+            tx.send(()).ok();
         });
+        reloader
     }
 }
 
@@ -94,7 +250,11 @@ impl Bind {
                 .with_context(|| anyhow!("Failed to create data directory: {:?}", &data_dir))?;
         }
 
-        let bind = match args.bind_addr()? {
+        Bind::listen(args.bind_addr()?)
+    }
+
+    pub fn listen(addr: TorAddress) -> Result<Bind> {
+        let bind = match addr {
             TorAddress::Address(addr) => {
                 info!("Binding to tcp: {:?}", addr);
                 let listener = TcpListener::bind(addr)?;
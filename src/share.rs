@@ -0,0 +1,401 @@
+use crate::args::{Args, ShareArgs};
+use crate::errors::*;
+use crate::httpd::{default_headers, not_found};
+use crate::listing;
+use crate::security;
+use crate::server::Bind;
+use crate::tor;
+use actix_web::{
+    http::header, web, web::Bytes, App, HttpRequest, HttpResponse, HttpServer, Responder,
+};
+use futures_util::stream::{self, Stream};
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use subtle::ConstantTimeEq;
+use tokio::sync::mpsc;
+
+const CHUNK_SIZE: usize = 64 * 1024;
+/// How long we wait for Tor to create the hidden service
+const HOSTNAME_TIMEOUT: Duration = Duration::from_secs(120);
+
+struct Shared {
+    slug: String,
+    /// Opened before the sandbox is setup, every download reads from it without changing its offset
+    file: Arc<File>,
+    file_name: String,
+    once: bool,
+    /// Set after the first complete download if `once` is enabled
+    done: AtomicBool,
+    shutdown: mpsc::Sender<()>,
+}
+
+impl Shared {
+    fn downloaded(&self) {
+        info!("Shared file was downloaded completely");
+        if self.once && !self.done.swap(true, Ordering::SeqCst) {
+            self.shutdown.try_send(()).ok();
+        }
+    }
+}
+
+fn random_hex(len: usize) -> Result<String> {
+    let mut buf = vec![0; len];
+    getrandom::getrandom(&mut buf).map_err(|err| anyhow!("Failed to get randomness: {}", err))?;
+    Ok(buf.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+/// Create a private directory for the throwaway Tor data, it's owned by `--user` so Tor can still
+/// write to it after privileges were dropped
+fn create_data_dir(args: &Args) -> Result<PathBuf> {
+    let path = env::temp_dir().join(format!("narnia-share-{}", random_hex(8)?));
+    let mut builder = fs::DirBuilder::new();
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::DirBuilderExt;
+        builder.mode(0o700);
+    }
+    builder
+        .create(&path)
+        .with_context(|| anyhow!("Failed to create data directory: {:?}", path))?;
+    #[cfg(unix)]
+    if let Some(user) = &args.user {
+        let user = users::get_user_by_name(user).context("Could not find user")?;
+        nix::unistd::chown(
+            &path,
+            Some(nix::unistd::Uid::from_raw(user.uid())),
+            Some(nix::unistd::Gid::from_raw(user.primary_group_id())),
+        )
+        .with_context(|| anyhow!("Failed to change owner of data directory: {:?}", path))?;
+    }
+    #[cfg(not(unix))]
+    let _ = args;
+    Ok(path)
+}
+
+/// Overwrite the hidden service key before deleting the data directory
+fn wipe_data_dir(path: &Path) -> Result<()> {
+    let hs_dir = path.join("hs");
+    if let Ok(entries) = fs::read_dir(&hs_dir) {
+        for entry in entries.flatten() {
+            let name = entry.file_name();
+            if !name.to_string_lossy().contains("secret_key") {
+                continue;
+            }
+            let path = entry.path();
+            let len = entry.metadata().map(|md| md.len()).unwrap_or(0);
+            let mut file = fs::OpenOptions::new()
+                .write(true)
+                .open(&path)
+                .with_context(|| anyhow!("Failed to open key for wiping: {:?}", path))?;
+            file.write_all(&vec![0; len as usize])?;
+            file.sync_all()?;
+            debug!("Wiped hidden service key: {:?}", path);
+        }
+    }
+    fs::remove_dir_all(path).with_context(|| anyhow!("Failed to remove {:?}", path))?;
+    Ok(())
+}
+
+fn wait_for_hostname(data_dir: &Path) -> Result<String> {
+    let path = data_dir.join("hs").join("hostname");
+    let mut waited = Duration::from_secs(0);
+    loop {
+        if let Ok(hostname) = fs::read_to_string(&path) {
+            return Ok(hostname.trim().to_string());
+        }
+        if waited > HOSTNAME_TIMEOUT {
+            bail!("Timed out waiting for hidden service hostname");
+        }
+        thread::sleep(Duration::from_millis(100));
+        waited += Duration::from_millis(100);
+    }
+}
+
+#[cfg(unix)]
+fn read_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    use std::os::unix::fs::FileExt;
+    file.read_at(buf, offset)
+}
+
+#[cfg(windows)]
+fn read_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    use std::os::windows::fs::FileExt;
+    file.seek_read(buf, offset)
+}
+
+/// Stream the file, calling `on_complete` once the last chunk was handed to the client
+fn file_stream<F: FnOnce() + 'static>(
+    file: Arc<File>,
+    len: u64,
+    on_complete: F,
+) -> impl Stream<Item = io::Result<Bytes>> {
+    stream::unfold(
+        Some((file, 0u64, Some(on_complete))),
+        move |state| async move {
+            let (file, sent, mut on_complete) = state?;
+            let result = web::block(move || {
+                let mut buf = vec![0; CHUNK_SIZE];
+                let n = read_at(&file, &mut buf, sent)?;
+                buf.truncate(n);
+                Ok::<_, io::Error>((file, buf))
+            })
+            .await;
+
+            match result {
+                Ok(Ok((_, buf))) if buf.is_empty() => None,
+                Ok(Ok((file, buf))) => {
+                    let sent = sent + buf.len() as u64;
+                    if sent >= len {
+                        if let Some(on_complete) = on_complete.take() {
+                            on_complete();
+                        }
+                    }
+                    Some((Ok(Bytes::from(buf)), Some((file, sent, on_complete))))
+                }
+                Ok(Err(err)) => Some((Err(err), None)),
+                Err(_) => Some((Err(io::Error::other("Blocking task failed")), None)),
+            }
+        },
+    )
+}
+
+async fn download(shared: web::Data<Shared>, req: HttpRequest) -> impl Responder {
+    let slug = req.match_info().query("slug");
+    if !bool::from(slug.as_bytes().ct_eq(shared.slug.as_bytes())) {
+        return not_found();
+    }
+    if shared.done.load(Ordering::SeqCst) {
+        return not_found();
+    }
+
+    let len = match shared.file.metadata() {
+        Ok(md) => md.len(),
+        Err(err) => {
+            warn!("Failed to read metadata of shared file: {:#}", err);
+            return not_found();
+        }
+    };
+
+    info!("Shared file is being downloaded");
+    let on_complete = {
+        let shared = shared.clone();
+        move || shared.downloaded()
+    };
+
+    let disposition = header::ContentDisposition {
+        disposition: header::DispositionType::Attachment,
+        parameters: vec![header::DispositionParam::Filename(shared.file_name.clone())],
+    };
+    HttpResponse::Ok()
+        .content_type("application/octet-stream")
+        .append_header((header::CONTENT_DISPOSITION, disposition))
+        .no_chunking(len)
+        .streaming(file_stream(shared.file.clone(), len, on_complete))
+}
+
+#[actix_web::main]
+async fn serve(bind: Bind, shared: Shared, mut shutdown: mpsc::Receiver<()>) -> Result<()> {
+    let shared = web::Data::new(shared);
+    let server = HttpServer::new(move || {
+        App::new()
+            .wrap(default_headers())
+            .app_data(shared.clone())
+            .route("/{slug}/{name}", web::get().to(download))
+            .default_service(web::to(|| async { not_found() }))
+    });
+
+    let server = match bind {
+        Bind::Tcp(tcp) => server.listen(tcp),
+        #[cfg(unix)]
+        Bind::Unix(uds) => server.listen_uds(uds),
+    }
+    .context("Failed to setup server")?
+    .run();
+
+    let handle = server.handle();
+    actix_web::rt::spawn(async move {
+        if shutdown.recv().await.is_some() {
+            info!("Shutting down after the first download");
+            handle.stop(true).await;
+        }
+    });
+
+    server.await.context("Failed to run http server")?;
+    Ok(())
+}
+
+pub fn run(mut args: Args, share: &ShareArgs) -> Result<()> {
+    let path = fs::canonicalize(&share.file)
+        .with_context(|| anyhow!("Failed to open file: {:?}", share.file))?;
+    let file =
+        File::open(&path).with_context(|| anyhow!("Failed to open file: {:?}", share.file))?;
+    if !file.metadata()?.is_file() {
+        bail!("Only regular files can be shared: {:?}", share.file);
+    }
+    let file_name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "download".to_string());
+
+    let data_dir = create_data_dir(&args)?;
+    args.data_dir = Some(data_dir.clone());
+
+    let result = (|| {
+        let bind = Bind::setup(&args).context("Failed to bind socket")?;
+        debug!("Locking down process");
+        security::setup(&args)?;
+
+        {
+            let args = args.clone();
+            let data_dir = data_dir.clone();
+            thread::spawn(move || {
+                if let Err(err) = tor::run(args, data_dir) {
+                    error!("Tor thread has terminated: {:#}", err);
+                }
+            });
+        }
+
+        let slug = random_hex(16)?;
+        let hostname = wait_for_hostname(&data_dir)?;
+        println!(
+            "http://{}/{}/{}",
+            hostname,
+            slug,
+            listing::encode_path(Path::new(&file_name))
+        );
+        info!("It might take a moment until the hidden service is reachable");
+
+        let (tx, rx) = mpsc::channel(1);
+        let shared = Shared {
+            slug,
+            file: Arc::new(file),
+            file_name,
+            once: share.once,
+            done: AtomicBool::new(false),
+            shutdown: tx,
+        };
+        serve(bind, shared, rx)
+    })();
+
+    debug!("Removing hidden service data: {:?}", data_dir);
+    if let Err(err) = wipe_data_dir(&data_dir) {
+        error!("Failed to wipe hidden service data: {:#}", err);
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::StatusCode;
+    use actix_web::test::{self, TestRequest};
+    use futures_util::StreamExt;
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    fn shared(dir: &Path, content: &[u8], once: bool) -> (Shared, mpsc::Receiver<()>) {
+        let path = dir.join("report.pdf");
+        fs::write(&path, content).unwrap();
+        let (tx, rx) = mpsc::channel(1);
+        let shared = Shared {
+            slug: "abcd".to_string(),
+            file: Arc::new(File::open(path).unwrap()),
+            file_name: "report.pdf".to_string(),
+            once,
+            done: AtomicBool::new(false),
+            shutdown: tx,
+        };
+        (shared, rx)
+    }
+
+    #[test]
+    fn test_wipe_data_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let data_dir = dir.path().join("data");
+        fs::create_dir_all(data_dir.join("hs")).unwrap();
+        let key = data_dir.join("hs/hs_ed25519_secret_key");
+        fs::write(&key, "secret").unwrap();
+        fs::write(data_dir.join("hs/hostname"), "abc.onion\n").unwrap();
+        // a second link to the key shows what happened to its content
+        let copy = dir.path().join("key");
+        fs::hard_link(&key, &copy).unwrap();
+
+        wipe_data_dir(&data_dir).unwrap();
+        assert!(!data_dir.exists());
+        assert_eq!(fs::read(&copy).unwrap(), vec![0; 6]);
+    }
+
+    #[actix_web::test]
+    async fn test_download_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let content = vec![0x42; CHUNK_SIZE * 2 + 1];
+        let (shared, mut shutdown) = shared(dir.path(), &content, true);
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(shared))
+                .route("/{slug}/{name}", web::get().to(download)),
+        )
+        .await;
+
+        let req = TestRequest::get().uri("/abce/report.pdf").to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+        let req = TestRequest::get().uri("/abcd/report.pdf").to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(shutdown.try_recv().is_err());
+        assert_eq!(test::read_body(res).await, content);
+        assert!(shutdown.try_recv().is_ok());
+
+        let req = TestRequest::get().uri("/abcd/report.pdf").to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[actix_web::test]
+    async fn test_download_without_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let (shared, mut shutdown) = shared(dir.path(), b"hello", false);
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(shared))
+                .route("/{slug}/{name}", web::get().to(download)),
+        )
+        .await;
+
+        for _ in 0..2 {
+            let req = TestRequest::get().uri("/abcd/report.pdf").to_request();
+            let res = test::call_service(&app, req).await;
+            assert_eq!(test::read_body(res).await, &b"hello"[..]);
+        }
+        assert!(shutdown.try_recv().is_err());
+    }
+
+    #[actix_web::test]
+    async fn test_partial_download_is_not_complete() {
+        let dir = tempfile::tempdir().unwrap();
+        let content = vec![0x42; CHUNK_SIZE * 2];
+        let (shared, _shutdown) = shared(dir.path(), &content, true);
+        let completed = Rc::new(Cell::new(false));
+
+        let stream = file_stream(shared.file.clone(), content.len() as u64, {
+            let completed = completed.clone();
+            move || completed.set(true)
+        });
+        futures_util::pin_mut!(stream);
+        let chunk = stream.next().await.unwrap().unwrap();
+        assert_eq!(chunk.len(), CHUNK_SIZE);
+        assert!(!completed.get());
+
+        stream.next().await.unwrap().unwrap();
+        assert!(completed.get());
+        assert!(stream.next().await.is_none());
+    }
+}
@@ -0,0 +1,132 @@
+use crate::errors::*;
+use minisign_verify::{PublicKey, Signature};
+use std::fs;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// A minisign public key, bundles need to be signed with it to be served
+#[derive(Debug)]
+pub struct SigningKey {
+    key: PublicKey,
+    /// The key id, formatted the same way minisign displays it
+    id: String,
+}
+
+impl SigningKey {
+    pub fn load(path: &Path) -> Result<SigningKey> {
+        let content = fs::read_to_string(path)
+            .with_context(|| anyhow!("Failed to read public key: {:?}", path))?;
+        SigningKey::decode(&content)
+            .with_context(|| anyhow!("Invalid minisign public key: {:?}", path))
+    }
+
+    /// Parse a `minisign.pub` file, the untrusted comment is optional
+    fn decode(content: &str) -> Result<SigningKey> {
+        let line = content
+            .lines()
+            .map(str::trim)
+            .find(|line| !line.is_empty() && !line.starts_with("untrusted comment:"))
+            .context("Public key is empty")?;
+        let key = PublicKey::from_base64(line)?;
+
+        let bytes = base64::decode(line)?;
+        let mut key_id = [0; 8];
+        key_id.copy_from_slice(&bytes[2..10]);
+        let id = format!("{:016X}", u64::from_le_bytes(key_id));
+
+        Ok(SigningKey { key, id })
+    }
+
+    /// Check the signature in `<bundle>.minisig`, the trusted comment of the signature is
+    /// returned so it can be logged
+    pub fn verify<R: Read>(&self, bundle: &Path, mut reader: R) -> Result<String> {
+        let path = signature_path(bundle);
+        let content = fs::read_to_string(&path)
+            .with_context(|| anyhow!("Failed to read signature: {:?}", path))?;
+        let signature = Signature::decode(&content)
+            .with_context(|| anyhow!("Invalid minisign signature: {:?}", path))?;
+
+        let mut verifier = self
+            .key
+            .verify_stream(&signature)
+            .with_context(|| anyhow!("Failed to verify signature: {:?}", path))?;
+        let mut buf = vec![0; CHUNK_SIZE];
+        loop {
+            match reader.read(&mut buf)? {
+                0 => break,
+                n => verifier.update(&buf[..n]),
+            }
+        }
+        verifier
+            .finalize()
+            .with_context(|| anyhow!("Bad signature for bundle: {:?}", bundle))?;
+
+        Ok(signature.trusted_comment().to_string())
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+}
+
+/// The signature is expected next to the bundle, like `minisign -S` creates it
+pub fn signature_path(bundle: &Path) -> PathBuf {
+    let mut path = bundle.as_os_str().to_os_string();
+    path.push(".minisig");
+    PathBuf::from(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PUBLIC_KEY: &str = "untrusted comment: minisign public key 31480EC441853D8C
+RWSMPYVBxA5IMUk6FXTAotQMRV66IX04Z9QDreB0F4D08YKi5vNwTejQ
+";
+    const OTHER_KEY: &str = "RWRhmkgqAwIyv3QxHoNKKcJ5oadSDNtPWkfbkPFcQly5fdVua5IwzorG";
+    const SIGNATURE: &str = "untrusted comment: signature from minisign secret key
+RUSMPYVBxA5IMbDW7S0JFJJhO24lt2CNYOO3kTOT98eRAqvx6+tS0Zrwz4I2ToCXsyhd1rfUBk69BCCGive0hPxlxFNJHDLZagk=
+trusted comment: timestamp:1700000000\tfile:site.tar
+DpgNxqx6HBxvOm58Lmq/NQ08vBhAfqk9bC63NyKXv0Opc8QmhdmrPgFkySEqYTH2YtSSgrQOevgGlnxzQZpoAA==
+";
+
+    fn verify(key: &str, data: &[u8]) -> Result<String> {
+        let dir = tempfile::tempdir().unwrap();
+        let bundle = dir.path().join("site.tar");
+        fs::write(signature_path(&bundle), SIGNATURE).unwrap();
+        SigningKey::decode(key).unwrap().verify(&bundle, data)
+    }
+
+    #[test]
+    fn test_key_id() {
+        let key = SigningKey::decode(PUBLIC_KEY).unwrap();
+        assert_eq!(key.id(), "31480EC441853D8C");
+    }
+
+    #[test]
+    fn test_verify() {
+        let comment = verify(PUBLIC_KEY, b"hello world\n").unwrap();
+        assert_eq!(comment, "timestamp:1700000000\tfile:site.tar");
+    }
+
+    #[test]
+    fn test_reject_modified() {
+        assert!(verify(PUBLIC_KEY, b"hello world!\n").is_err());
+    }
+
+    #[test]
+    fn test_reject_other_key() {
+        assert!(verify(OTHER_KEY, b"hello world\n").is_err());
+    }
+
+    #[test]
+    fn test_reject_missing_signature() {
+        let dir = tempfile::tempdir().unwrap();
+        let key = SigningKey::decode(PUBLIC_KEY).unwrap();
+        assert!(key
+            .verify(&dir.path().join("site.tar"), &b"hello world\n"[..])
+            .is_err());
+    }
+}
@@ -1,28 +1,259 @@
-use crate::args::Args;
+use crate::args::{self, Args};
 use crate::errors::*;
 use crate::utils;
-use libtor::{HiddenServiceVersion, Tor, TorAddress, TorFlag};
-use std::path::PathBuf;
+use actix_web::web;
+use libtor::{HiddenServiceVersion, LogDestination, LogLevel, Tor, TorAddress, TorFlag};
+use log::{log, Level, LevelFilter};
+use std::fs;
+#[cfg(unix)]
+use std::fs::File;
+#[cfg(unix)]
+use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
+#[cfg(unix)]
+use std::thread;
+#[cfg(unix)]
+use std::time::Duration;
+
+/// Log lines of Tor are forwarded with this target, so they can be filtered with `RUST_LOG`
+const LOG_TARGET: &str = "libtor";
+const CONTROL_SOCKET: &str = "control.sock";
+const CONTROL_COOKIE: &str = "control_auth_cookie";
 
 pub fn run(args: Args, data_dir: PathBuf) -> Result<()> {
     let bind_addr = args.bind_addr()?;
 
     let hs_path = utils::path_to_string(data_dir.join("hs"))?;
+    #[cfg(unix)]
+    let control_socket = utils::path_to_string(control_socket(&data_dir))?;
+    #[cfg(unix)]
+    let control_cookie = utils::path_to_string(control_cookie(&data_dir))?;
     let data_dir = utils::path_to_string(data_dir)?;
 
-    debug!("Starting tor");
-    Tor::new()
-        .flag(TorFlag::DataDirectory(data_dir))
+    let mut tor = Tor::new();
+    tor.flag(TorFlag::DataDirectory(data_dir))
         .flag(TorFlag::SocksPort(0))
         .flag(TorFlag::HiddenServiceDir(hs_path))
-        .flag(TorFlag::HiddenServiceVersion(HiddenServiceVersion::V3))
-        .flag(TorFlag::HiddenServicePort(
+        .flag(TorFlag::HiddenServiceVersion(HiddenServiceVersion::V3));
+    if args.tls_enabled() {
+        tor.flag(TorFlag::HiddenServicePort(
+            TorAddress::Port(443),
+            Some(bind_addr).into(),
+        ));
+        if let Some(addr) = &args.http_redirect_bind {
+            tor.flag(TorFlag::HiddenServicePort(
+                TorAddress::Port(80),
+                Some(args::parse_bind_addr(addr)).into(),
+            ));
+        }
+    } else {
+        tor.flag(TorFlag::HiddenServicePort(
             TorAddress::Port(80),
             Some(bind_addr).into(),
-        ))
-        .start()
-        .context("Failed to start tor")?;
+        ));
+    }
+    // anybody who can connect to the control socket could reconfigure Tor, so it's only
+    // writable by us and requires the cookie that is readable by us
+    #[cfg(unix)]
+    if args.metrics_bind.is_some() || args.admin_enabled() {
+        tor.flag(TorFlag::ControlSocket(control_socket))
+            .flag(TorFlag::ControlSocketsGroupWritable(false.into()))
+            .flag(TorFlag::CookieAuthentication(true.into()))
+            .flag(TorFlag::CookieAuthFile(control_cookie))
+            .flag(TorFlag::CookieAuthFileGroupReadable(false.into()));
+    }
+
+    #[cfg(unix)]
+    {
+        let log_path = forward_logs().context("Failed to setup Tor logging")?;
+        tor.flag(TorFlag::LogTo(
+            tor_log_level(log::max_level()),
+            LogDestination::File(log_path),
+        ));
+    }
+
+    debug!("Starting tor");
+    tor.start().context("Failed to start tor")?;
 
     warn!("Tor thread has terminated");
     Ok(())
 }
+
+/// Only ask Tor for log lines that would be shown anyway, the levels are shifted by one since
+/// Tor is quite chatty at notice
+fn tor_log_level(max: LevelFilter) -> LogLevel {
+    match max {
+        LevelFilter::Off | LevelFilter::Error => LogLevel::Err,
+        LevelFilter::Warn => LogLevel::Warn,
+        LevelFilter::Info => LogLevel::Notice,
+        LevelFilter::Debug => LogLevel::Info,
+        LevelFilter::Trace => LogLevel::Debug,
+    }
+}
+
+/// Parse a line like `Oct 19 06:00:00.000 [notice] Bootstrapped 100% (done): Done`
+fn parse_log_line(line: &str) -> Option<(Level, &str)> {
+    let (_, rest) = line.split_once(" [")?;
+    let (level, message) = rest.split_once("] ")?;
+    let level = match level {
+        "err" => Level::Error,
+        "warn" => Level::Warn,
+        "notice" => Level::Info,
+        "info" => Level::Debug,
+        "debug" => Level::Trace,
+        _ => return None,
+    };
+    Some((level, message))
+}
+
+fn log_line(line: &str) {
+    if let Some((level, message)) = parse_log_line(line) {
+        log!(target: LOG_TARGET, level, "{}", message);
+    } else {
+        log!(target: LOG_TARGET, Level::Info, "{}", line);
+    }
+}
+
+/// Tor writes its logs into a pipe, a thread reads them and passes them on to our own logger.
+/// Returns the path Tor should log to
+#[cfg(unix)]
+fn forward_logs() -> Result<String> {
+    use nix::fcntl::{fcntl, FcntlArg, FdFlag};
+    use std::os::unix::io::FromRawFd;
+
+    let (rx, tx) = nix::unistd::pipe()?;
+    for fd in [rx, tx] {
+        fcntl(fd, FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC))?;
+    }
+    let reader = unsafe { File::from_raw_fd(rx) };
+    thread::spawn(move || {
+        for line in BufReader::new(reader).lines() {
+            match line {
+                Ok(line) => log_line(&line),
+                Err(err) => {
+                    warn!("Failed to read Tor logs: {:#}", err);
+                    break;
+                }
+            }
+        }
+    });
+
+    // the write end stays open, Tor opens the pipe again through this path
+    Ok(format!("/dev/fd/{}", tx))
+}
+
+/// Tor is configured with this control socket if metrics or the admin endpoints are enabled, it's
+/// only used to query the bootstrap status
+pub fn control_socket(data_dir: &Path) -> PathBuf {
+    data_dir.join(CONTROL_SOCKET)
+}
+
+/// The cookie that authenticates connections to the control socket, it's next to the socket
+fn control_cookie(data_dir: &Path) -> PathBuf {
+    data_dir.join(CONTROL_COOKIE)
+}
+
+/// The onion address of the hidden service, Tor writes it once the service was set up
+pub fn hostname(data_dir: &Path) -> Result<String> {
+    let path = data_dir.join("hs").join("hostname");
+    let hostname =
+        fs::read_to_string(&path).with_context(|| anyhow!("Failed to read {:?}", path))?;
+    Ok(hostname.trim().to_string())
+}
+
+fn bootstrap_request(cookie: &[u8]) -> String {
+    let cookie = cookie
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<String>();
+    format!(
+        "AUTHENTICATE {}\r\nGETINFO status/bootstrap-phase\r\nQUIT\r\n",
+        cookie
+    )
+}
+
+fn parse_bootstrap(response: &str) -> Result<u8> {
+    let progress = response
+        .split_whitespace()
+        .find_map(|word| word.strip_prefix("PROGRESS="))
+        .with_context(|| anyhow!("Unexpected response from Tor: {:?}", response))?;
+    let progress = progress
+        .parse()
+        .with_context(|| anyhow!("Invalid bootstrap progress: {:?}", progress))?;
+    Ok(progress)
+}
+
+/// Ask Tor how far it has bootstrapped, the hidden service is reachable at 100
+#[cfg(unix)]
+pub fn bootstrap_progress(control_socket: &Path) -> Result<u8> {
+    use std::io::{Read, Write};
+    use std::os::unix::net::UnixStream;
+
+    let cookie_path = control_socket.with_file_name(CONTROL_COOKIE);
+    let cookie = fs::read(&cookie_path)
+        .with_context(|| anyhow!("Failed to read Tor control cookie: {:?}", cookie_path))?;
+
+    let mut stream = UnixStream::connect(control_socket).with_context(|| {
+        anyhow!(
+            "Failed to connect to Tor control socket: {:?}",
+            control_socket
+        )
+    })?;
+    stream.set_read_timeout(Some(Duration::from_secs(1)))?;
+    stream.set_write_timeout(Some(Duration::from_secs(1)))?;
+    stream.write_all(bootstrap_request(&cookie).as_bytes())?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    parse_bootstrap(&response)
+}
+
+#[cfg(not(unix))]
+pub fn bootstrap_progress(control_socket: &Path) -> Result<u8> {
+    bail!(
+        "Tor control sockets are only supported on unix: {:?}",
+        control_socket
+    )
+}
+
+/// Like `bootstrap_progress`, but errors count as not bootstrapped
+pub async fn query_bootstrap(control_socket: PathBuf) -> u8 {
+    match web::block(move || bootstrap_progress(&control_socket)).await {
+        Ok(Ok(percent)) => percent,
+        Ok(Err(err)) => {
+            // Tor creates the socket a moment after it was started
+            debug!("Failed to query Tor bootstrap status: {:#}", err);
+            0
+        }
+        Err(_) => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_case::test_case;
+
+    #[test_case("Oct 19 06:00:00.000 [notice] Bootstrapped 100% (done): Done", Some((Level::Info, "Bootstrapped 100% (done): Done")))]
+    #[test_case("Oct 19 06:00:00.000 [warn] Clock skew detected", Some((Level::Warn, "Clock skew detected")))]
+    #[test_case("Oct 19 06:00:00.000 [err] Reading config failed", Some((Level::Error, "Reading config failed")))]
+    #[test_case("Oct 19 06:00:00.000 [debug] conn_read_callback", Some((Level::Trace, "conn_read_callback")))]
+    #[test_case("Tor can't help you if you use it wrong!", None)]
+    fn test_parse_log_line(line: &str, expected: Option<(Level, &str)>) {
+        assert_eq!(parse_log_line(line), expected);
+    }
+
+    #[test]
+    fn test_bootstrap_request() {
+        assert_eq!(
+            bootstrap_request(&[0x00, 0x13, 0x37, 0xff]),
+            "AUTHENTICATE 001337ff\r\nGETINFO status/bootstrap-phase\r\nQUIT\r\n"
+        );
+    }
+
+    #[test]
+    fn test_parse_bootstrap() {
+        let response = "250 OK\r\n250-status/bootstrap-phase=NOTICE BOOTSTRAP PROGRESS=100 TAG=done SUMMARY=\"Done\"\r\n250 OK\r\n250 closing connection\r\n";
+        assert_eq!(parse_bootstrap(response).unwrap(), 100);
+        assert!(parse_bootstrap("515 Authentication failed\r\n").is_err());
+    }
+}
@@ -0,0 +1,365 @@
+use crate::args::Args;
+use crate::errors::*;
+use crate::httpd;
+use actix_multipart::{Field, Multipart};
+use actix_web::web;
+use futures_util::TryStreamExt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/// How often we try to find a free name before giving up
+const MAX_RENAMES: usize = 100;
+
+pub const FORM: &str = r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<meta name="viewport" content="width=device-width, initial-scale=1">
+<title>Upload files</title>
+</head>
+<body>
+<h1>Upload files</h1>
+<form method="post" enctype="multipart/form-data">
+<input type="file" name="file" multiple required>
+<button type="submit">Upload</button>
+</form>
+</body>
+</html>
+"#;
+
+/// The page shown after a successful upload
+pub fn render_received(files: &[String]) -> String {
+    let files = files
+        .iter()
+        .map(|name| format!("<li>{}</li>\n", htmlescape::encode_minimal(name)))
+        .collect::<String>();
+    format!(
+        r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<meta name="viewport" content="width=device-width, initial-scale=1">
+<title>Upload complete</title>
+</head>
+<body>
+<h1>Upload complete</h1>
+<ul>
+{}</ul>
+<a href="">Upload more files</a>
+</body>
+</html>
+"#,
+        files
+    )
+}
+
+#[derive(Debug)]
+pub enum UploadError {
+    /// The request exceeded the configured size limit
+    TooLarge,
+    /// The disk is running out of space
+    NoSpace,
+    /// The request was malformed or contained an invalid file name
+    Invalid(Error),
+    /// Writing the file failed
+    Failed(Error),
+}
+
+/// Receive mode, files are written into a directory outside of the web root
+#[derive(Debug)]
+pub struct Upload {
+    /// The url path of the upload form
+    pub path: String,
+    dir: PathBuf,
+    max_size: u64,
+    min_free: u64,
+}
+
+/// Removes a file that is still being received, unless it was completed. This also runs if the
+/// client disconnects and the request is dropped
+struct PartialUpload {
+    path: Option<PathBuf>,
+}
+
+impl PartialUpload {
+    fn new(path: PathBuf) -> PartialUpload {
+        PartialUpload { path: Some(path) }
+    }
+
+    fn keep(mut self) {
+        self.path = None;
+    }
+}
+
+impl Drop for PartialUpload {
+    fn drop(&mut self) {
+        if let Some(path) = self.path.take() {
+            if let Err(err) = fs::remove_file(&path) {
+                warn!("Failed to remove partial upload({:?}): {:#}", path, err);
+            }
+        }
+    }
+}
+
+/// The space that is available to unprivileged users
+#[cfg(unix)]
+// the field types depend on the platform
+#[allow(clippy::useless_conversion)]
+fn available_space(dir: &Path) -> io::Result<u64> {
+    let stat = nix::sys::statvfs::statvfs(dir)?;
+    Ok(u64::from(stat.blocks_available()) * u64::from(stat.fragment_size()))
+}
+
+#[cfg(not(unix))]
+fn available_space(_dir: &Path) -> io::Result<u64> {
+    Ok(u64::MAX)
+}
+
+impl Upload {
+    pub fn load(args: &Args, web_root: Option<&str>) -> Result<Option<Upload>> {
+        let dir = if let Some(dir) = &args.upload_dir {
+            dir
+        } else {
+            return Ok(None);
+        };
+
+        if !args.upload_path.starts_with('/') {
+            bail!("Upload path needs to start with /: {:?}", args.upload_path);
+        }
+
+        let canonical_dir = fs::canonicalize(dir)
+            .with_context(|| anyhow!("Failed to open upload directory: {:?}", dir))?;
+        if !canonical_dir.is_dir() {
+            bail!("Upload directory is not a directory: {:?}", dir);
+        }
+        // embedded files can't overlap with anything
+        if let Some(web_root) = web_root {
+            let web_root = fs::canonicalize(web_root)
+                .with_context(|| anyhow!("Failed to open web root: {:?}", web_root))?;
+            if canonical_dir.starts_with(&web_root) || web_root.starts_with(&canonical_dir) {
+                bail!("Upload directory can't overlap with the web root");
+            }
+        }
+
+        if args.htpasswd.is_none() && args.auth_tokens.is_none() {
+            warn!("Uploads are enabled without authentication, anybody who can reach the server can fill the upload directory");
+        }
+        info!("Accepting uploads at {:?} into {:?}", args.upload_path, dir);
+        Ok(Some(Upload {
+            path: args.upload_path.clone(),
+            dir: dir.clone(),
+            max_size: args.upload_max_size,
+            min_free: args.upload_min_free,
+        }))
+    }
+
+    pub fn max_size(&self) -> u64 {
+        self.max_size
+    }
+
+    /// Write all files of a multipart form into the upload directory, returns the new file names
+    pub async fn receive(&self, mut multipart: Multipart) -> Result<Vec<String>, UploadError> {
+        let mut received = Vec::new();
+        let mut total = 0;
+
+        while let Some(field) = multipart
+            .try_next()
+            .await
+            .map_err(|err| UploadError::Invalid(anyhow!("Invalid multipart form: {}", err)))?
+        {
+            // skip regular form fields and file inputs without a selected file
+            let name = match field.content_disposition().get_filename() {
+                Some(name) if !name.is_empty() => name.to_string(),
+                _ => continue,
+            };
+            let name = sanitize_file_name(&name).map_err(UploadError::Invalid)?;
+
+            let dir = self.dir.clone();
+            let (file, name) = web::block(move || create_unique(&dir, &name))
+                .await
+                .map_err(|err| UploadError::Failed(anyhow!("{}", err)))?
+                .context("Failed to create file")
+                .map_err(UploadError::Failed)?;
+
+            // never leave partial uploads behind
+            let partial = PartialUpload::new(self.dir.join(&name));
+            let size = self.save_field(field, file, &mut total).await?;
+            partial.keep();
+            info!("Received upload {:?} ({} bytes)", name, size);
+            received.push(name);
+        }
+
+        Ok(received)
+    }
+
+    async fn save_field(
+        &self,
+        mut field: Field,
+        mut file: File,
+        total: &mut u64,
+    ) -> Result<u64, UploadError> {
+        let mut size = 0;
+        while let Some(chunk) = field
+            .try_next()
+            .await
+            .map_err(|err| UploadError::Invalid(anyhow!("Failed to read upload: {}", err)))?
+        {
+            size += chunk.len() as u64;
+            *total += chunk.len() as u64;
+            if *total > self.max_size {
+                return Err(UploadError::TooLarge);
+            }
+
+            let dir = self.dir.clone();
+            let min_free = self.min_free;
+            file = web::block(move || {
+                if !has_free_space(&dir, min_free, chunk.len() as u64)? {
+                    return Ok(None);
+                }
+                file.write_all(&chunk).map(|_| Some(file))
+            })
+            .await
+            .map_err(|err| UploadError::Failed(anyhow!("{}", err)))?
+            .context("Failed to write upload")
+            .map_err(UploadError::Failed)?
+            .ok_or(UploadError::NoSpace)?;
+        }
+        Ok(size)
+    }
+}
+
+/// Check if `len` more bytes can be written while keeping `min_free` bytes available
+fn has_free_space(dir: &Path, min_free: u64, len: u64) -> io::Result<bool> {
+    if min_free == 0 {
+        return Ok(true);
+    }
+    Ok(available_space(dir)? >= min_free.saturating_add(len))
+}
+
+/// Only accept plain file names, following the same rules as request paths
+pub fn sanitize_file_name(name: &str) -> Result<String> {
+    let path = httpd::resolve_path_req("", Path::new(name))?;
+    let mut components = path.components();
+    let name = match (components.next(), components.next()) {
+        (Some(name), None) => name.as_os_str(),
+        _ => bail!("File name can't contain directories: {:?}", name),
+    };
+    let name = name.to_str().context("File name is not valid utf8")?;
+
+    if name.starts_with('.') {
+        bail!("Hidden files are not accepted: {:?}", name);
+    }
+    if name.chars().any(|c| c.is_control()) {
+        bail!("File name contains control characters: {:?}", name);
+    }
+    Ok(name.to_string())
+}
+
+fn create_new(path: &Path) -> io::Result<File> {
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(path)
+}
+
+/// Create a file that didn't exist before, `a.txt` becomes `a-1.txt` if it's already taken
+fn create_unique(dir: &Path, name: &str) -> io::Result<(File, String)> {
+    let path = Path::new(name);
+    let stem = path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy())
+        .unwrap_or_default();
+    let extension = path.extension().map(|ext| ext.to_string_lossy());
+
+    for i in 0..MAX_RENAMES {
+        let candidate = match (i, &extension) {
+            (0, _) => name.to_string(),
+            (_, Some(ext)) => format!("{}-{}.{}", stem, i, ext),
+            (_, None) => format!("{}-{}", stem, i),
+        };
+        match create_new(&dir.join(&candidate)) {
+            Ok(file) => return Ok((file, candidate)),
+            Err(err) if err.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(err) => return Err(err),
+        }
+    }
+
+    Err(io::Error::new(
+        io::ErrorKind::AlreadyExists,
+        "Failed to find a free file name",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_case::test_case;
+
+    #[test_case("a.txt", "a.txt"; "plain")]
+    #[test_case("hello world.pdf", "hello world.pdf"; "spaces")]
+    #[test_case("café.txt", "café.txt"; "unicode")]
+    fn test_valid_file_name(name: &str, expected: &str) {
+        assert_eq!(sanitize_file_name(name).unwrap(), expected);
+    }
+
+    #[test_case(""; "empty")]
+    #[test_case("."; "current directory")]
+    #[test_case(".."; "parent")]
+    #[test_case("../a.txt"; "parent traversal")]
+    #[test_case("/etc/passwd"; "absolute")]
+    #[test_case("a/b.txt"; "subdirectory")]
+    #[test_case(".htaccess"; "hidden")]
+    #[test_case("a\nb.txt"; "newline")]
+    fn test_invalid_file_name(name: &str) {
+        assert!(sanitize_file_name(name).is_err());
+    }
+
+    #[test]
+    fn test_create_unique() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("a.txt"), "original").unwrap();
+
+        let (_, name) = create_unique(dir.path(), "a.txt").unwrap();
+        assert_eq!(name, "a-1.txt");
+        let (_, name) = create_unique(dir.path(), "a.txt").unwrap();
+        assert_eq!(name, "a-2.txt");
+        let (_, name) = create_unique(dir.path(), "b").unwrap();
+        assert_eq!(name, "b");
+        let (_, name) = create_unique(dir.path(), "b").unwrap();
+        assert_eq!(name, "b-1");
+
+        assert_eq!(
+            fs::read_to_string(dir.path().join("a.txt")).unwrap(),
+            "original"
+        );
+    }
+
+    #[test]
+    fn test_partial_upload() {
+        let dir = tempfile::tempdir().unwrap();
+        let dropped = dir.path().join("dropped.txt");
+        let kept = dir.path().join("kept.txt");
+        fs::write(&dropped, "partial").unwrap();
+        fs::write(&kept, "complete").unwrap();
+
+        drop(PartialUpload::new(dropped.clone()));
+        PartialUpload::new(kept.clone()).keep();
+        assert!(!dropped.exists());
+        assert!(kept.exists());
+    }
+
+    #[test]
+    fn test_has_free_space() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(has_free_space(dir.path(), 0, u64::MAX).unwrap());
+        assert!(has_free_space(dir.path(), 1, 1).unwrap());
+        if cfg!(unix) {
+            assert!(!has_free_space(dir.path(), u64::MAX, 1).unwrap());
+        }
+    }
+}
@@ -1,4 +1,7 @@
 use crate::errors::*;
+use percent_encoding::percent_decode_str;
+use std::borrow::Cow;
+use std::ffi::OsStr;
 #[cfg(unix)]
 use std::path::Path;
 use std::path::PathBuf;
@@ -9,6 +12,50 @@ pub fn path_to_string(path: PathBuf) -> Result<String> {
         .map_err(|e| anyhow!("Path contains invalid utf8: {:?}", e))
 }
 
+/// Parse a size in bytes, with an optional K, M or G suffix
+pub fn parse_size(s: &str) -> Result<u64> {
+    let (num, factor) = match s.chars().last() {
+        Some('K') | Some('k') => (&s[..s.len() - 1], 1024),
+        Some('M') | Some('m') => (&s[..s.len() - 1], 1024 * 1024),
+        Some('G') | Some('g') => (&s[..s.len() - 1], 1024 * 1024 * 1024),
+        _ => (s, 1),
+    };
+    let num = num
+        .parse::<u64>()
+        .with_context(|| anyhow!("Invalid size: {:?}", s))?;
+    num.checked_mul(factor)
+        .with_context(|| anyhow!("Size is too large: {:?}", s))
+}
+
+/// Get the raw bytes of a file name, on windows they are always valid unicode
+#[cfg(unix)]
+pub fn os_str_bytes(s: &OsStr) -> Cow<'_, [u8]> {
+    use std::os::unix::ffi::OsStrExt;
+    Cow::Borrowed(s.as_bytes())
+}
+
+#[cfg(not(unix))]
+pub fn os_str_bytes(s: &OsStr) -> Cow<'_, [u8]> {
+    match s.to_string_lossy() {
+        Cow::Borrowed(s) => Cow::Borrowed(s.as_bytes()),
+        Cow::Owned(s) => Cow::Owned(s.into_bytes()),
+    }
+}
+
+/// Percent-decode a url path, on unix the result doesn't need to be valid utf8
+#[cfg(unix)]
+pub fn decode_url_path(path: &str) -> PathBuf {
+    use std::ffi::OsString;
+    use std::os::unix::ffi::OsStringExt;
+    let bytes = percent_decode_str(path).collect::<Vec<_>>();
+    PathBuf::from(OsString::from_vec(bytes))
+}
+
+#[cfg(not(unix))]
+pub fn decode_url_path(path: &str) -> PathBuf {
+    PathBuf::from(percent_decode_str(path).decode_utf8_lossy().into_owned())
+}
+
 #[cfg(unix)]
 pub fn mkprivdir(path: &Path) -> Result<()> {
     use nix::sys::stat::Mode;
@@ -18,3 +65,26 @@ pub fn mkprivdir(path: &Path) -> Result<()> {
         Err(err) => Err(Error::from(err)),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_case::test_case;
+
+    #[test_case("0", 0; "zero")]
+    #[test_case("1337", 1337; "bytes")]
+    #[test_case("4K", 4096; "kilobytes")]
+    #[test_case("512M", 512 * 1024 * 1024; "megabytes")]
+    #[test_case("2g", 2 * 1024 * 1024 * 1024; "gigabytes lowercase")]
+    fn test_parse_size(s: &str, expected: u64) {
+        assert_eq!(parse_size(s).unwrap(), expected);
+    }
+
+    #[test_case(""; "empty")]
+    #[test_case("M"; "only suffix")]
+    #[test_case("1.5M"; "fraction")]
+    #[test_case("-1"; "negative")]
+    fn test_invalid_parse_size(s: &str) {
+        assert!(parse_size(s).is_err());
+    }
+}
@@ -0,0 +1,917 @@
+use crate::args::Args;
+use crate::auth::Auth;
+use crate::errors::*;
+use crate::httpd;
+use crate::listing;
+use crate::resolve::{DirEntry, Node, Resolver};
+use crate::utils;
+use actix_web::http::header::{self, HeaderValue};
+use actix_web::http::StatusCode;
+use actix_web::{web, HttpRequest, HttpResponse};
+use futures_util::TryStreamExt;
+use std::ffi::{OsStr, OsString};
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+const ALLOW: &str = "OPTIONS, GET, HEAD, PUT, DELETE, MKCOL, MOVE, PROPFIND";
+
+/// Used to generate unique names for temporary files
+static TMP_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+fn status(code: StatusCode) -> HttpResponse {
+    HttpResponse::build(code)
+        .content_type("text/plain; charset=utf-8")
+        .body(format!(
+            "{} - {}\n",
+            code.as_u16(),
+            code.canonical_reason().unwrap_or("").to_lowercase()
+        ))
+}
+
+fn io_error(err: &io::Error) -> StatusCode {
+    match err.kind() {
+        io::ErrorKind::NotFound => StatusCode::NOT_FOUND,
+        io::ErrorKind::PermissionDenied => StatusCode::FORBIDDEN,
+        io::ErrorKind::AlreadyExists => StatusCode::METHOD_NOT_ALLOWED,
+        _ => {
+            warn!("WebDAV request failed: {:#}", err);
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    }
+}
+
+/// Read/write access to a directory with WebDAV
+#[derive(Debug)]
+pub struct WebDav {
+    /// The url path the directory is mounted at
+    pub path: String,
+    dir: String,
+    resolver: Resolver,
+    max_size: u64,
+}
+
+impl WebDav {
+    pub fn load(args: &Args, auth: Option<&Auth>) -> Result<Option<WebDav>> {
+        let dir = if let Some(dir) = &args.webdav_dir {
+            dir
+        } else {
+            return Ok(None);
+        };
+
+        let path = args.webdav_path.trim_end_matches('/');
+        if !path.starts_with('/') {
+            bail!("WebDAV path needs to start with /: {:?}", args.webdav_path);
+        }
+        if !auth.is_some_and(|auth| auth.applies(path)) {
+            if !args.webdav_allow_anonymous {
+                bail!("WebDAV allows anybody to modify files without authentication, configure --htpasswd or --auth-tokens for {:?}, or use --webdav-allow-anonymous", path);
+            }
+            warn!(
+                "WebDAV is enabled without authentication, anybody can modify {:?}",
+                dir
+            );
+        }
+        if !std::fs::metadata(dir)
+            .with_context(|| anyhow!("Failed to open WebDAV directory: {:?}", dir))?
+            .is_dir()
+        {
+            bail!("WebDAV directory is not a directory: {:?}", dir);
+        }
+
+        info!("Serving WebDAV at {:?} from {:?}", path, dir);
+        Ok(Some(WebDav {
+            path: path.to_string(),
+            dir: dir.clone(),
+            resolver: Resolver::new(args, PathBuf::from(dir)),
+            max_size: args.webdav_max_size,
+        }))
+    }
+
+    /// Map a url path to a path inside of the WebDAV directory
+    fn resolve(&self, url_path: &str) -> Option<(PathBuf, PathBuf)> {
+        let tail = url_path.strip_prefix(&self.path)?;
+        if !tail.is_empty() && !tail.starts_with('/') {
+            return None;
+        }
+        let req_path = utils::decode_url_path(tail.trim_start_matches('/'));
+        let path = httpd::resolve_path_req(&self.dir, &req_path).ok()?;
+        Some((req_path, path))
+    }
+
+    /// Open the parent directory of a path we are about to modify, following the symlink and
+    /// hidden file policies. The root directory itself can't be modified.
+    fn open_parent(
+        &self,
+        req_path: &Path,
+        path: &Path,
+    ) -> Result<(ParentDir, OsString), StatusCode> {
+        let name = match req_path.file_name() {
+            Some(name) => name,
+            None => return Err(StatusCode::FORBIDDEN),
+        };
+        if self.resolver.is_hidden(name) {
+            return Err(StatusCode::FORBIDDEN);
+        }
+
+        let parent = path.parent().ok_or(StatusCode::FORBIDDEN)?;
+        let dir = match self.resolver.open(parent) {
+            Ok(Node::Fs(dir)) => dir,
+            Ok(Node::Bundle(_)) => return Err(StatusCode::FORBIDDEN),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Err(StatusCode::CONFLICT),
+            Err(err) => return Err(io_error(&err)),
+        };
+        match dir.metadata() {
+            Ok(md) if md.is_dir() => Ok((ParentDir::new(dir, parent), name.to_os_string())),
+            Ok(_) => Err(StatusCode::CONFLICT),
+            Err(err) => Err(io_error(&err)),
+        }
+    }
+
+    pub async fn handle(&self, req: HttpRequest, payload: web::Payload) -> HttpResponse {
+        let (req_path, path) = match self.resolve(req.uri().path()) {
+            Some(path) => path,
+            None => return status(StatusCode::BAD_REQUEST),
+        };
+
+        let result = match req.method().as_str() {
+            "OPTIONS" => Ok(HttpResponse::Ok()
+                .append_header(("DAV", "1"))
+                .append_header((header::ALLOW, ALLOW))
+                .finish()),
+            "GET" | "HEAD" => self.get(&req, &path).await,
+            "PROPFIND" => self.propfind(&req, &req_path, &path),
+            "PUT" => self.put(&req, &req_path, &path, payload).await,
+            "MKCOL" => self.mkcol(&req_path, &path),
+            "DELETE" => self.delete(&req_path, &path),
+            "MOVE" => self.move_to(&req, &req_path, &path),
+            _ => Ok(HttpResponse::MethodNotAllowed()
+                .append_header((header::ALLOW, ALLOW))
+                .finish()),
+        };
+
+        result.unwrap_or_else(status)
+    }
+
+    async fn get(&self, req: &HttpRequest, path: &Path) -> Result<HttpResponse, StatusCode> {
+        let file = self.resolver.open(path).map_err(|err| io_error(&err))?;
+        if file.metadata().map(|md| md.is_dir()).unwrap_or(true) {
+            return Err(StatusCode::METHOD_NOT_ALLOWED);
+        }
+        let mut response = httpd::serve_file(req, file, path).await;
+        // files are served on the origin of the site, scripts in uploaded html must not run there
+        response.headers_mut().insert(
+            header::CONTENT_SECURITY_POLICY,
+            HeaderValue::from_static("sandbox"),
+        );
+        Ok(response)
+    }
+
+    fn propfind(
+        &self,
+        req: &HttpRequest,
+        req_path: &Path,
+        path: &Path,
+    ) -> Result<HttpResponse, StatusCode> {
+        // infinite depth is expensive and rarely used, treat it like depth 1
+        let depth = req
+            .headers()
+            .get("Depth")
+            .map(|depth| depth.as_bytes() != b"0")
+            .unwrap_or(true);
+
+        let file = self.resolver.open(path).map_err(|err| io_error(&err))?;
+        let md = file.metadata().map_err(|err| io_error(&err))?;
+
+        let href = if req_path.as_os_str().is_empty() {
+            format!("{}/", self.path)
+        } else {
+            format!("{}/{}", self.path, listing::encode_path(req_path))
+        };
+        let name = req_path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+
+        let mut xml = String::from(
+            "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<D:multistatus xmlns:D=\"DAV:\">\n",
+        );
+        if md.is_dir() {
+            let href = if href.ends_with('/') {
+                href
+            } else {
+                format!("{}/", href)
+            };
+            xml.push_str(&propfind_response(
+                &href,
+                &name,
+                &DirEntry {
+                    name: Default::default(),
+                    len: 0,
+                    is_dir: true,
+                    is_symlink: false,
+                    modified: md.modified(),
+                },
+            ));
+
+            if depth {
+                let entries = self
+                    .resolver
+                    .read_dir(file, path)
+                    .map_err(|err| io_error(&err))?;
+                for entry in entries {
+                    let name = utils::os_str_bytes(&entry.name);
+                    let mut entry_href =
+                        format!("{}{}", href, listing::encode_path(Path::new(&entry.name)));
+                    if entry.is_dir {
+                        entry_href.push('/');
+                    }
+                    xml.push_str(&propfind_response(
+                        &entry_href,
+                        &String::from_utf8_lossy(&name),
+                        &entry,
+                    ));
+                }
+            }
+        } else {
+            xml.push_str(&propfind_response(
+                &href,
+                &name,
+                &DirEntry {
+                    name: Default::default(),
+                    len: md.len(),
+                    is_dir: false,
+                    is_symlink: false,
+                    modified: md.modified(),
+                },
+            ));
+        }
+        xml.push_str("</D:multistatus>\n");
+
+        Ok(HttpResponse::build(StatusCode::MULTI_STATUS)
+            .content_type("application/xml; charset=utf-8")
+            .body(xml))
+    }
+
+    async fn put(
+        &self,
+        req: &HttpRequest,
+        req_path: &Path,
+        path: &Path,
+        mut payload: web::Payload,
+    ) -> Result<HttpResponse, StatusCode> {
+        let (dir, name) = self.open_parent(req_path, path)?;
+
+        let content_length = req
+            .headers()
+            .get(header::CONTENT_LENGTH)
+            .and_then(|len| len.to_str().ok())
+            .and_then(|len| len.parse::<u64>().ok());
+        if content_length.is_some_and(|len| len > self.max_size) {
+            return Err(StatusCode::PAYLOAD_TOO_LARGE);
+        }
+
+        let existed = match dir.stat(&name).map_err(|err| io_error(&err))? {
+            Some(true) => return Err(StatusCode::METHOD_NOT_ALLOWED),
+            Some(false) => true,
+            None => false,
+        };
+
+        // write into a temporary file first, so nobody can read a partial upload
+        let tmp_name = OsString::from(format!(
+            ".narnia-{}-{}.tmp",
+            std::process::id(),
+            TMP_COUNTER.fetch_add(1, Ordering::SeqCst)
+        ));
+        let mut file = dir.create_new(&tmp_name).map_err(|err| io_error(&err))?;
+        let tmp = TmpFile::new(&dir, tmp_name);
+
+        let mut size = 0;
+        loop {
+            let chunk = match payload.try_next().await {
+                Ok(Some(chunk)) => chunk,
+                Ok(None) => break file.sync_all().map_err(|err| io_error(&err))?,
+                Err(err) => {
+                    debug!("Failed to receive upload: {}", err);
+                    return Err(StatusCode::BAD_REQUEST);
+                }
+            };
+            size += chunk.len() as u64;
+            if size > self.max_size {
+                return Err(StatusCode::PAYLOAD_TOO_LARGE);
+            }
+
+            file = match web::block(move || file.write_all(&chunk).map(|_| file)).await {
+                Ok(Ok(file)) => file,
+                Ok(Err(err)) => return Err(io_error(&err)),
+                Err(_) => return Err(StatusCode::INTERNAL_SERVER_ERROR),
+            };
+        }
+
+        tmp.persist(&name).map_err(|err| io_error(&err))?;
+        info!("Received WebDAV upload {:?} ({} bytes)", req_path, size);
+        if existed {
+            Ok(HttpResponse::NoContent().finish())
+        } else {
+            Ok(HttpResponse::Created().finish())
+        }
+    }
+
+    fn mkcol(&self, req_path: &Path, path: &Path) -> Result<HttpResponse, StatusCode> {
+        let (dir, name) = self.open_parent(req_path, path)?;
+        dir.mkdir(&name).map_err(|err| io_error(&err))?;
+        info!("Created WebDAV directory {:?}", req_path);
+        Ok(HttpResponse::Created().finish())
+    }
+
+    fn delete(&self, req_path: &Path, path: &Path) -> Result<HttpResponse, StatusCode> {
+        let (dir, name) = self.open_parent(req_path, path)?;
+        dir.remove(&name).map_err(|err| io_error(&err))?;
+        info!("Deleted WebDAV path {:?}", req_path);
+        Ok(HttpResponse::NoContent().finish())
+    }
+
+    fn move_to(
+        &self,
+        req: &HttpRequest,
+        req_path: &Path,
+        path: &Path,
+    ) -> Result<HttpResponse, StatusCode> {
+        let (dir, name) = self.open_parent(req_path, path)?;
+
+        let destination = req
+            .headers()
+            .get("Destination")
+            .and_then(|dest| dest.to_str().ok())
+            .ok_or(StatusCode::BAD_REQUEST)?;
+        // the destination is usually a full url, only the path is relevant
+        let destination = match destination.split_once("://") {
+            Some((_, rest)) => rest.find('/').map(|idx| &rest[idx..]).unwrap_or("/"),
+            None => destination,
+        };
+        let (dest_req_path, dest_path) =
+            self.resolve(destination).ok_or(StatusCode::BAD_GATEWAY)?;
+        let (dest_dir, dest_name) = self.open_parent(&dest_req_path, &dest_path)?;
+
+        if dir.stat(&name).map_err(|err| io_error(&err))?.is_none() {
+            return Err(StatusCode::NOT_FOUND);
+        }
+        // moving something onto itself or into itself
+        if dest_req_path.starts_with(req_path) {
+            return Err(StatusCode::FORBIDDEN);
+        }
+
+        let overwrite = req
+            .headers()
+            .get("Overwrite")
+            .map(|overwrite| overwrite.as_bytes() != b"F")
+            .unwrap_or(true);
+        let existed = dest_dir
+            .stat(&dest_name)
+            .map_err(|err| io_error(&err))?
+            .is_some();
+        if existed {
+            if !overwrite {
+                return Err(StatusCode::PRECONDITION_FAILED);
+            }
+            dest_dir.remove(&dest_name).map_err(|err| io_error(&err))?;
+        }
+
+        dir.rename(&name, &dest_dir, &dest_name)
+            .map_err(|err| match err.raw_os_error() {
+                // moving a directory into itself
+                #[cfg(unix)]
+                Some(nix::libc::EINVAL) => StatusCode::FORBIDDEN,
+                _ => io_error(&err),
+            })?;
+
+        info!("Moved WebDAV path {:?} to {:?}", req_path, dest_req_path);
+        if existed {
+            Ok(HttpResponse::NoContent().finish())
+        } else {
+            Ok(HttpResponse::Created().finish())
+        }
+    }
+}
+
+fn propfind_response(href: &str, name: &str, entry: &DirEntry) -> String {
+    let mut props = format!(
+        "<D:displayname>{}</D:displayname>\n",
+        htmlescape::encode_minimal(name)
+    );
+    if entry.is_dir {
+        props.push_str("<D:resourcetype><D:collection/></D:resourcetype>\n");
+    } else {
+        props.push_str("<D:resourcetype/>\n");
+        props.push_str(&format!(
+            "<D:getcontentlength>{}</D:getcontentlength>\n",
+            entry.len
+        ));
+    }
+    if let Some(modified) = entry.modified {
+        props.push_str(&format!(
+            "<D:getlastmodified>{}</D:getlastmodified>\n",
+            header::HttpDate::from(modified)
+        ));
+    }
+
+    format!(
+        "<D:response>\n<D:href>{}</D:href>\n<D:propstat>\n<D:prop>\n{}</D:prop>\n<D:status>HTTP/1.1 200 OK</D:status>\n</D:propstat>\n</D:response>\n",
+        htmlescape::encode_minimal(href),
+        props
+    )
+}
+
+/// The directory that contains a path we are about to modify. On unix every change is made
+/// relative to its file descriptor, so replacing a directory with a symlink after it was checked
+/// can't redirect a write outside of the WebDAV directory
+struct ParentDir {
+    dir: File,
+    #[cfg(not(unix))]
+    path: PathBuf,
+}
+
+#[cfg(unix)]
+impl ParentDir {
+    fn new(dir: File, _path: &Path) -> ParentDir {
+        ParentDir { dir }
+    }
+
+    fn fd(&self) -> std::os::unix::io::RawFd {
+        use std::os::unix::io::AsRawFd;
+        self.dir.as_raw_fd()
+    }
+
+    /// Returns if the entry is a directory, or `None` if it doesn't exist
+    fn stat(&self, name: &OsStr) -> io::Result<Option<bool>> {
+        use nix::fcntl::AtFlags;
+        use nix::sys::stat::{fstatat, SFlag};
+        match fstatat(self.fd(), name, AtFlags::AT_SYMLINK_NOFOLLOW) {
+            Ok(st) => Ok(Some(
+                SFlag::from_bits_truncate(st.st_mode) & SFlag::S_IFMT == SFlag::S_IFDIR,
+            )),
+            Err(nix::errno::Errno::ENOENT) => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    fn create_new(&self, name: &OsStr) -> io::Result<File> {
+        use nix::fcntl::{openat, OFlag};
+        use nix::sys::stat::Mode;
+        use std::os::unix::io::FromRawFd;
+        let fd = openat(
+            self.fd(),
+            name,
+            OFlag::O_WRONLY | OFlag::O_CREAT | OFlag::O_EXCL | OFlag::O_NOFOLLOW | OFlag::O_CLOEXEC,
+            Mode::from_bits_truncate(0o644),
+        )?;
+        Ok(unsafe { File::from_raw_fd(fd) })
+    }
+
+    fn mkdir(&self, name: &OsStr) -> io::Result<()> {
+        use nix::sys::stat::{mkdirat, Mode};
+        mkdirat(self.fd(), name, Mode::from_bits_truncate(0o755))?;
+        Ok(())
+    }
+
+    /// Renaming never follows symlinks, a symlink at the destination is replaced
+    fn rename(&self, name: &OsStr, to: &ParentDir, to_name: &OsStr) -> io::Result<()> {
+        nix::fcntl::renameat(Some(self.fd()), name, Some(to.fd()), to_name)?;
+        Ok(())
+    }
+
+    /// Remove a file or a directory with all of its content, symlinks are never followed
+    fn remove(&self, name: &OsStr) -> io::Result<()> {
+        remove_at(self.fd(), name)
+    }
+}
+
+#[cfg(unix)]
+fn remove_at(dirfd: std::os::unix::io::RawFd, name: &OsStr) -> io::Result<()> {
+    use nix::dir::Dir;
+    use nix::fcntl::{AtFlags, OFlag};
+    use nix::sys::stat::{fstatat, Mode, SFlag};
+    use nix::unistd::{unlinkat, UnlinkatFlags};
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::io::AsRawFd;
+
+    let st = fstatat(dirfd, name, AtFlags::AT_SYMLINK_NOFOLLOW)?;
+    if SFlag::from_bits_truncate(st.st_mode) & SFlag::S_IFMT != SFlag::S_IFDIR {
+        unlinkat(Some(dirfd), name, UnlinkatFlags::NoRemoveDir)?;
+        return Ok(());
+    }
+
+    // O_NOFOLLOW makes this fail if the directory was replaced with a symlink in the meantime
+    let mut dir = Dir::openat(
+        dirfd,
+        name,
+        OFlag::O_RDONLY | OFlag::O_DIRECTORY | OFlag::O_NOFOLLOW | OFlag::O_CLOEXEC,
+        Mode::empty(),
+    )?;
+    let mut children = Vec::new();
+    for entry in dir.iter() {
+        let entry = entry?;
+        let child = OsStr::from_bytes(entry.file_name().to_bytes());
+        if child != "." && child != ".." {
+            children.push(child.to_os_string());
+        }
+    }
+    for child in children {
+        remove_at(dir.as_raw_fd(), &child)?;
+    }
+    drop(dir);
+    unlinkat(Some(dirfd), name, UnlinkatFlags::RemoveDir)?;
+    Ok(())
+}
+
+/// There's no openat on this platform, fall back to regular paths
+#[cfg(not(unix))]
+impl ParentDir {
+    fn new(dir: File, path: &Path) -> ParentDir {
+        ParentDir {
+            dir,
+            path: path.to_path_buf(),
+        }
+    }
+
+    fn stat(&self, name: &OsStr) -> io::Result<Option<bool>> {
+        match std::fs::symlink_metadata(self.path.join(name)) {
+            Ok(md) => Ok(Some(md.is_dir())),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn create_new(&self, name: &OsStr) -> io::Result<File> {
+        std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(self.path.join(name))
+    }
+
+    fn mkdir(&self, name: &OsStr) -> io::Result<()> {
+        std::fs::create_dir(self.path.join(name))
+    }
+
+    fn rename(&self, name: &OsStr, to: &ParentDir, to_name: &OsStr) -> io::Result<()> {
+        std::fs::rename(self.path.join(name), to.path.join(to_name))
+    }
+
+    fn remove(&self, name: &OsStr) -> io::Result<()> {
+        let path = self.path.join(name);
+        if std::fs::symlink_metadata(&path)?.is_dir() {
+            std::fs::remove_dir_all(path)
+        } else {
+            std::fs::remove_file(path)
+        }
+    }
+}
+
+/// A temporary file that is removed unless it was moved into place, this also runs if the client
+/// disconnects and the request is dropped
+struct TmpFile<'a> {
+    dir: &'a ParentDir,
+    name: Option<OsString>,
+}
+
+impl<'a> TmpFile<'a> {
+    fn new(dir: &'a ParentDir, name: OsString) -> TmpFile<'a> {
+        TmpFile {
+            dir,
+            name: Some(name),
+        }
+    }
+
+    fn persist(mut self, to: &OsStr) -> io::Result<()> {
+        if let Some(name) = &self.name {
+            self.dir.rename(name, self.dir, to)?;
+            self.name = None;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for TmpFile<'_> {
+    fn drop(&mut self) {
+        if let Some(name) = self.name.take() {
+            if let Err(err) = self.dir.remove(&name) {
+                warn!("Failed to remove partial upload({:?}): {:#}", name, err);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+    use test_case::test_case;
+
+    fn webdav() -> WebDav {
+        let args = Args::parse_from(["narnia"]);
+        WebDav {
+            path: "/dav".to_string(),
+            dir: "/srv/dav".to_string(),
+            resolver: Resolver::new(&args, PathBuf::from("/srv/dav")),
+            max_size: 0,
+        }
+    }
+
+    #[test_case("/dav", "", "/srv/dav/"; "root")]
+    #[test_case("/dav/", "", "/srv/dav/"; "root with slash")]
+    #[test_case("/dav/a/b.txt", "a/b.txt", "/srv/dav/a/b.txt"; "file")]
+    #[test_case("/dav/a%20b.txt", "a b.txt", "/srv/dav/a b.txt"; "encoded")]
+    fn test_valid_resolve(url: &str, req_path: &str, path: &str) {
+        let (r, p) = webdav().resolve(url).unwrap();
+        assert_eq!(r, Path::new(req_path));
+        assert_eq!(p, Path::new(path));
+    }
+
+    #[test_case("/"; "outside")]
+    #[test_case("/davfoo"; "prefix of other path")]
+    #[test_case("/dav/../etc/passwd"; "parent")]
+    #[test_case("/dav/a/%2e%2e/%2e%2e/etc"; "encoded parent")]
+    fn test_invalid_resolve(url: &str) {
+        assert!(webdav().resolve(url).is_none());
+    }
+
+    #[test]
+    fn test_requires_auth() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path().to_str().unwrap();
+        let args = Args::parse_from(["narnia", "--webdav-dir", dir]);
+        assert!(WebDav::load(&args, None).is_err());
+        let args = Args::parse_from(["narnia", "--webdav-dir", dir, "--webdav-allow-anonymous"]);
+        assert!(WebDav::load(&args, None).unwrap().is_some());
+    }
+
+    #[cfg(unix)]
+    mod requests {
+        use super::*;
+        use actix_web::dev::ServiceResponse;
+        use actix_web::http::Method;
+        use actix_web::test::{call_service, init_service, TestRequest};
+        use actix_web::App;
+        use std::fs;
+        use std::os::unix::fs::symlink;
+
+        async fn handle(
+            webdav: web::Data<WebDav>,
+            req: HttpRequest,
+            payload: web::Payload,
+        ) -> HttpResponse {
+            webdav.handle(req, payload).await
+        }
+
+        /// Creates a WebDAV directory next to a directory it must not be able to modify, with
+        /// symlinks pointing into it
+        fn setup() -> (tempfile::TempDir, PathBuf, PathBuf) {
+            let tmp = tempfile::tempdir().unwrap();
+            let dav = tmp.path().join("dav");
+            let outside = tmp.path().join("outside");
+            fs::create_dir_all(dav.join("dir/sub")).unwrap();
+            fs::create_dir(&outside).unwrap();
+            fs::write(dav.join("a.txt"), "a").unwrap();
+            fs::write(dav.join("dir/sub/b.txt"), "b").unwrap();
+            fs::write(outside.join("secret.txt"), "secret").unwrap();
+            symlink(&outside, dav.join("escape")).unwrap();
+            symlink(outside.join("secret.txt"), dav.join("link.txt")).unwrap();
+            (tmp, dav, outside)
+        }
+
+        async fn request(
+            dav: &Path,
+            method: &str,
+            uri: &str,
+            headers: &[(&str, &str)],
+            body: &'static str,
+        ) -> ServiceResponse {
+            let args = Args::parse_from([
+                "narnia",
+                "--webdav-dir",
+                dav.to_str().unwrap(),
+                "--webdav-allow-anonymous",
+            ]);
+            let webdav = WebDav::load(&args, None).unwrap().unwrap();
+            let app = init_service(
+                App::new().service(
+                    web::scope("/dav")
+                        .app_data(web::Data::new(webdav))
+                        .default_service(web::to(handle)),
+                ),
+            )
+            .await;
+            let mut req = TestRequest::default()
+                .method(Method::from_bytes(method.as_bytes()).unwrap())
+                .uri(uri)
+                .set_payload(body);
+            for header in headers {
+                req = req.insert_header(*header);
+            }
+            call_service(&app, req.to_request()).await
+        }
+
+        fn names(dir: &Path) -> Vec<String> {
+            let mut names = fs::read_dir(dir)
+                .unwrap()
+                .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+                .collect::<Vec<_>>();
+            names.sort();
+            names
+        }
+
+        #[actix_web::test]
+        async fn test_put() {
+            let (_tmp, dav, outside) = setup();
+            let res = request(&dav, "PUT", "/dav/new.txt", &[], "hello").await;
+            assert_eq!(res.status(), StatusCode::CREATED);
+            assert_eq!(fs::read_to_string(dav.join("new.txt")).unwrap(), "hello");
+
+            let res = request(&dav, "PUT", "/dav/new.txt", &[], "world").await;
+            assert_eq!(res.status(), StatusCode::NO_CONTENT);
+            assert_eq!(fs::read_to_string(dav.join("new.txt")).unwrap(), "world");
+
+            // the symlink is replaced, the file it points to is untouched
+            let res = request(&dav, "PUT", "/dav/link.txt", &[], "pwned").await;
+            assert_eq!(res.status(), StatusCode::NO_CONTENT);
+            assert!(!fs::symlink_metadata(dav.join("link.txt"))
+                .unwrap()
+                .file_type()
+                .is_symlink());
+            assert_eq!(
+                fs::read_to_string(outside.join("secret.txt")).unwrap(),
+                "secret"
+            );
+
+            let res = request(&dav, "PUT", "/dav/escape/new.txt", &[], "pwned").await;
+            assert_eq!(res.status(), StatusCode::FORBIDDEN);
+            let res = request(&dav, "PUT", "/dav/.htaccess", &[], "pwned").await;
+            assert_eq!(res.status(), StatusCode::FORBIDDEN);
+            let res = request(&dav, "PUT", "/dav/missing/new.txt", &[], "x").await;
+            assert_eq!(res.status(), StatusCode::CONFLICT);
+            let res = request(&dav, "PUT", "/dav/dir", &[], "x").await;
+            assert_eq!(res.status(), StatusCode::METHOD_NOT_ALLOWED);
+
+            assert_eq!(names(&outside), &["secret.txt"]);
+            assert_eq!(
+                names(&dav),
+                &["a.txt", "dir", "escape", "link.txt", "new.txt"]
+            );
+        }
+
+        #[actix_web::test]
+        async fn test_tmp_file_is_removed() {
+            let (_tmp, dav, _outside) = setup();
+            let parent = ParentDir::new(File::open(&dav).unwrap(), &dav);
+            let name = OsString::from(".narnia-test.tmp");
+            parent.create_new(&name).unwrap();
+            drop(TmpFile::new(&parent, name.clone()));
+            assert!(parent.stat(&name).unwrap().is_none());
+
+            parent.create_new(&name).unwrap();
+            TmpFile::new(&parent, name)
+                .persist(OsStr::new("kept.txt"))
+                .unwrap();
+            assert_eq!(parent.stat(OsStr::new("kept.txt")).unwrap(), Some(false));
+        }
+
+        #[actix_web::test]
+        async fn test_get_is_sandboxed() {
+            let (_tmp, dav, _outside) = setup();
+            let res = request(&dav, "GET", "/dav/a.txt", &[], "").await;
+            assert_eq!(res.status(), StatusCode::OK);
+            assert_eq!(
+                res.headers().get(header::CONTENT_SECURITY_POLICY).unwrap(),
+                "sandbox"
+            );
+        }
+
+        #[actix_web::test]
+        async fn test_mkcol() {
+            let (_tmp, dav, outside) = setup();
+            let res = request(&dav, "MKCOL", "/dav/new", &[], "").await;
+            assert_eq!(res.status(), StatusCode::CREATED);
+            assert!(dav.join("new").is_dir());
+
+            let res = request(&dav, "MKCOL", "/dav/new", &[], "").await;
+            assert_eq!(res.status(), StatusCode::METHOD_NOT_ALLOWED);
+            let res = request(&dav, "MKCOL", "/dav/missing/new", &[], "").await;
+            assert_eq!(res.status(), StatusCode::CONFLICT);
+            let res = request(&dav, "MKCOL", "/dav/escape/new", &[], "").await;
+            assert_eq!(res.status(), StatusCode::FORBIDDEN);
+            let res = request(&dav, "MKCOL", "/dav/", &[], "").await;
+            assert_eq!(res.status(), StatusCode::FORBIDDEN);
+            assert_eq!(names(&outside), &["secret.txt"]);
+        }
+
+        #[actix_web::test]
+        async fn test_delete() {
+            let (_tmp, dav, outside) = setup();
+            let res = request(&dav, "DELETE", "/dav/escape/secret.txt", &[], "").await;
+            assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+            // only the symlinks are removed, not what they point to
+            let res = request(&dav, "DELETE", "/dav/escape", &[], "").await;
+            assert_eq!(res.status(), StatusCode::NO_CONTENT);
+            let res = request(&dav, "DELETE", "/dav/link.txt", &[], "").await;
+            assert_eq!(res.status(), StatusCode::NO_CONTENT);
+            assert_eq!(names(&outside), &["secret.txt"]);
+
+            // a symlink inside of a directory that is removed
+            symlink(&outside, dav.join("dir/sub/escape")).unwrap();
+            let res = request(&dav, "DELETE", "/dav/dir", &[], "").await;
+            assert_eq!(res.status(), StatusCode::NO_CONTENT);
+            assert_eq!(names(&outside), &["secret.txt"]);
+
+            let res = request(&dav, "DELETE", "/dav/a.txt", &[], "").await;
+            assert_eq!(res.status(), StatusCode::NO_CONTENT);
+            let res = request(&dav, "DELETE", "/dav/a.txt", &[], "").await;
+            assert_eq!(res.status(), StatusCode::NOT_FOUND);
+            let res = request(&dav, "DELETE", "/dav/", &[], "").await;
+            assert_eq!(res.status(), StatusCode::FORBIDDEN);
+            assert!(names(&dav).is_empty());
+        }
+
+        #[actix_web::test]
+        async fn test_move() {
+            let (_tmp, dav, outside) = setup();
+            let res = request(
+                &dav,
+                "MOVE",
+                "/dav/a.txt",
+                &[("Destination", "http://localhost/dav/escape/a.txt")],
+                "",
+            )
+            .await;
+            assert_eq!(res.status(), StatusCode::FORBIDDEN);
+            let res = request(
+                &dav,
+                "MOVE",
+                "/dav/escape/secret.txt",
+                &[("Destination", "/dav/secret.txt")],
+                "",
+            )
+            .await;
+            assert_eq!(res.status(), StatusCode::FORBIDDEN);
+            let res = request(&dav, "MOVE", "/dav/a.txt", &[("Destination", "/a.txt")], "").await;
+            assert_eq!(res.status(), StatusCode::BAD_GATEWAY);
+            let res = request(
+                &dav,
+                "MOVE",
+                "/dav/dir",
+                &[("Destination", "/dav/dir/sub/dir")],
+                "",
+            )
+            .await;
+            assert_eq!(res.status(), StatusCode::FORBIDDEN);
+            let res = request(
+                &dav,
+                "MOVE",
+                "/dav/a.txt",
+                &[("Destination", "/dav/link.txt"), ("Overwrite", "F")],
+                "",
+            )
+            .await;
+            assert_eq!(res.status(), StatusCode::PRECONDITION_FAILED);
+            assert_eq!(names(&outside), &["secret.txt"]);
+
+            let res = request(
+                &dav,
+                "MOVE",
+                "/dav/a.txt",
+                &[("Destination", "http://localhost/dav/dir/c.txt")],
+                "",
+            )
+            .await;
+            assert_eq!(res.status(), StatusCode::CREATED);
+            assert_eq!(fs::read_to_string(dav.join("dir/c.txt")).unwrap(), "a");
+
+            // overwriting a symlink replaces the link
+            let res = request(
+                &dav,
+                "MOVE",
+                "/dav/dir/c.txt",
+                &[("Destination", "/dav/link.txt")],
+                "",
+            )
+            .await;
+            assert_eq!(res.status(), StatusCode::NO_CONTENT);
+            assert_eq!(fs::read_to_string(dav.join("link.txt")).unwrap(), "a");
+            assert_eq!(
+                fs::read_to_string(outside.join("secret.txt")).unwrap(),
+                "secret"
+            );
+
+            let res = request(
+                &dav,
+                "MOVE",
+                "/dav/missing",
+                &[("Destination", "/dav/other")],
+                "",
+            )
+            .await;
+            assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        }
+    }
+}